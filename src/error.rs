@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors raised while loading a checkpoint, a tokenizer or the command line.
+#[derive(Debug)]
+pub enum Error {
+    /// an I/O error while accessing `path`
+    Io { path: PathBuf, source: io::Error },
+    /// `path` ended before `what` could be read
+    Truncated {
+        path: PathBuf,
+        what: String,
+        /// number of bytes needed to read `what`
+        expected: usize,
+        /// number of bytes actually in the file
+        actual: usize,
+    },
+    /// the checkpoint header is inconsistent with itself or with the file
+    Header { path: PathBuf, reason: String },
+    /// the model at `path` is well formed but uses a feature that is not supported
+    Unsupported { path: PathBuf, reason: String },
+    /// token `index` of the tokenizer at `path` is not valid UTF-8
+    InvalidToken { path: PathBuf, index: usize },
+    /// a command line argument is missing or malformed
+    Argument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn header(path: impl Into<PathBuf>, reason: impl Into<String>) -> Self {
+        Self::Header {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn unsupported(path: impl Into<PathBuf>, reason: impl Into<String>) -> Self {
+        Self::Unsupported {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Self::Truncated {
+                path,
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: file is truncated, {what} needs {expected} bytes but the file has {actual}",
+                path.display()
+            ),
+            Self::Header { path, reason } => {
+                write!(f, "{}: invalid header, {reason}", path.display())
+            }
+            Self::Unsupported { path, reason } => {
+                write!(f, "{}: unsupported model, {reason}", path.display())
+            }
+            Self::InvalidToken { path, index } => {
+                write!(f, "{}: token {index} is not valid UTF-8", path.display())
+            }
+            Self::Argument(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Text generation on top of the transformer, the tokenizer and the sampler.
+
+use crate::error::{Error, Result};
+use crate::sampler::Sampler;
+use crate::tokenizer::{utok, StreamDecoder, Tokenize};
+use crate::transformer::Transformer;
+
+/// A token produced by a [`Generator`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedToken {
+    /// token id
+    pub token: utok,
+    /// the text completed by the token, empty while the bytes of a character
+    /// are split across tokens, see [`Generator::flush`]
+    pub piece: String,
+    /// log-probability of the token under the model, before temperature
+    pub logprob: f32,
+    /// position of the token in the sequence, the leading BOS is at 0
+    pub pos: u32,
+}
+
+/// Streams the tokens following a prompt, one forward pass per item.
+///
+/// The prompt tokens after the first one, usually BOS, are yielded first,
+/// then sampled tokens until the model emits BOS or EOS or `steps` positions
+/// have been processed.
+/// Dropping the generator stops generation early.
+///
+/// The bytes of a character split across the last tokens are held back until
+/// [`Generator::flush`] is called.
+pub struct Generator<'a> {
+    transformer: &'a mut Transformer,
+    tokenizer: &'a dyn Tokenize,
+    sampler: &'a mut Sampler,
+    /// turns the yielded tokens into text
+    decoder: StreamDecoder<'a>,
+    /// the encoded prompt, starting with BOS if the tokenizer has one
+    prompt_tokens: Vec<utok>,
+    /// copy of the logits handed to the sampler, which modifies them in place,
+    /// the originals stay in the transformer state for the log-probability
+    logits: Vec<f32>,
+    /// the token to feed into the transformer next
+    token: utok,
+    /// position in the sequence
+    pos: u32,
+    /// maximum number of positions to process
+    steps: u32,
+    /// set once the model emitted BOS or EOS
+    done: bool,
+}
+
+impl<'a> Generator<'a> {
+    /// `steps` is clamped to the context length of the model, 0 uses all of it.
+    ///
+    /// Fails if the prompt encodes to no token at all, which can only happen
+    /// with an empty prompt and a tokenizer without BOS.
+    pub fn new(
+        transformer: &'a mut Transformer,
+        tokenizer: &'a dyn Tokenize,
+        sampler: &'a mut Sampler,
+        prompt: &str,
+        steps: u32,
+    ) -> Result<Self> {
+        // encode the (string) prompt into tokens sequence, it starts with BOS
+        let prompt_tokens = tokenizer.encode(prompt, true, false);
+        if prompt_tokens.is_empty() {
+            return Err(Error::Argument(
+                "the prompt is empty and the tokenizer has no BOS token to start from".into(),
+            ));
+        }
+        let logits = vec![0.0; transformer.config.vocab_size as usize];
+        let seq_len = transformer.config.seq_len;
+        let steps = if steps == 0 {
+            seq_len
+        } else {
+            steps.min(seq_len)
+        };
+        Ok(Self {
+            transformer,
+            tokenizer,
+            sampler,
+            decoder: StreamDecoder::after(tokenizer, prompt_tokens[0]),
+            token: prompt_tokens[0],
+            prompt_tokens,
+            logits,
+            pos: 0,
+            steps,
+            done: false,
+        })
+    }
+
+    /// Number of tokens in the encoded prompt, including BOS.
+    #[inline]
+    pub fn num_prompt_tokens(&self) -> usize {
+        self.prompt_tokens.len()
+    }
+
+    /// Number of positions that went through the transformer so far.
+    #[inline]
+    pub fn pos(&self) -> u32 {
+        self.pos
+    }
+
+    /// The bytes held back from the pieces so far because they do not form a
+    /// complete character yet, as U+FFFD like [`Tokenize::decode_all`].
+    /// Call it once the iterator is exhausted.
+    pub fn flush(&mut self) -> String {
+        self.decoder.finish()
+    }
+}
+
+impl Iterator for Generator<'_> {
+    type Item = GeneratedToken;
+
+    fn next(&mut self) -> Option<GeneratedToken> {
+        if self.done || self.pos >= self.steps {
+            return None;
+        }
+
+        // forward the transformer to get logits for the next token
+        let pos = self.pos as usize;
+        self.logits
+            .copy_from_slice(self.transformer.forward(self.token, self.pos));
+
+        // advance the state machine
+        let next = if pos < self.prompt_tokens.len() - 1 {
+            // if we are still processing the input prompt, force the next prompt token
+            self.prompt_tokens[pos + 1]
+        } else {
+            // otherwise sample the next token from the logits
+            self.sampler.sample(&mut self.logits)
+        };
+        self.pos += 1;
+
+        // data-dependent terminating condition: the BOS token delimits sequences
+        if [self.tokenizer.bos(), self.tokenizer.eos()].contains(&Some(next)) {
+            self.done = true;
+            return None;
+        }
+
+        let piece = self.decoder.push(next);
+        self.token = next;
+        Some(GeneratedToken {
+            token: next,
+            piece,
+            logprob: log_softmax(self.transformer.state.logits(), next as usize),
+            pos: self.pos,
+        })
+    }
+}
+
+/// Generates text following `prompt` for at most `steps` positions, calling
+/// `on_piece` with every decoded piece as soon as it is produced.
+///
+/// Generation stops early when the model emits BOS or EOS. Returns the number
+/// of positions that went through the transformer, fails like
+/// [`Generator::new`].
+pub fn generate(
+    transformer: &mut Transformer,
+    tokenizer: &dyn Tokenize,
+    sampler: &mut Sampler,
+    prompt: &str,
+    steps: u32,
+    mut on_piece: impl FnMut(&str),
+) -> Result<u32> {
+    let mut generator = Generator::new(transformer, tokenizer, sampler, prompt, steps)?;
+    for token in &mut generator {
+        on_piece(&token.piece);
+    }
+    let rest = generator.flush();
+    if !rest.is_empty() {
+        on_piece(&rest);
+    }
+    Ok(generator.pos())
+}
+
+/// Log-probability of `index` under the softmax of `logits`.
+fn log_softmax(logits: &[f32], index: usize) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&logit| (logit - max).exp()).sum();
+    logits[index] - max - sum.ln()
+}
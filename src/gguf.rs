@@ -0,0 +1,455 @@
+//! Reader for GGUF, the model file format of ggml and llama.cpp.
+//!
+//! ```plain_text
+//! (
+//!     magic: "GGUF",
+//!     version: u32,
+//!     tensor_count: u64,
+//!     metadata_kv_count: u64,
+//!     [(key: string, value_type: u32, value); metadata_kv_count],
+//!     [(name: string, n_dims: u32, dims: [u64; n_dims], type: u32, offset: u64); tensor_count],
+//!     padding to `general.alignment`,
+//!     tensor data
+//! )
+//! ```
+//!
+//! Strings are a `u64` length followed by UTF-8 bytes, everything is little endian.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use half::{bf16, f16};
+use memmap2::Mmap;
+
+use crate::error::{Error, Result};
+
+/// The first four bytes of every GGUF file.
+pub const MAGIC: [u8; 4] = *b"GGUF";
+
+/// Alignment of the tensor data when `general.alignment` is not set.
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// Deepest nesting of arrays in metadata values, llama.cpp only writes
+/// arrays of scalars and strings.
+const MAX_ARRAY_DEPTH: u32 = 4;
+
+/// Whether `bytes` start like a GGUF file.
+pub fn has_magic(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Whether the file at `path` is a GGUF file, `false` if it cannot be read.
+pub fn is_gguf(path: impl AsRef<Path>) -> bool {
+    use std::io::Read;
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && has_magic(&magic)
+}
+
+/// A metadata value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl Value {
+    /// The value of any integer type, if it is not negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v.into()),
+            Self::U16(v) => Some(v.into()),
+            Self::U32(v) => Some(v.into()),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => v.try_into().ok(),
+            Self::I16(v) => v.try_into().ok(),
+            Self::I32(v) => v.try_into().ok(),
+            Self::I64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value of a floating point type.
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Self::F32(v) => Some(v),
+            Self::F64(v) => Some(v as f32),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Element type of a tensor, as numbered by ggml.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    /// blocks of 32 `i8` sharing an f16 scale
+    Q8_0,
+    BF16,
+    /// any other type, not supported
+    Other(u32),
+}
+
+impl GgmlType {
+    /// number of values in a Q8_0 block
+    pub const Q8_0_BLOCK: usize = 32;
+
+    fn from_u32(ty: u32) -> Self {
+        match ty {
+            0 => Self::F32,
+            1 => Self::F16,
+            8 => Self::Q8_0,
+            30 => Self::BF16,
+            ty => Self::Other(ty),
+        }
+    }
+
+    /// Number of bytes taken by `len` values, `None` for unsupported types,
+    /// on overflow or if `len` does not fill whole blocks.
+    pub fn size_of(self, len: u64) -> Option<u64> {
+        match self {
+            Self::F32 => len.checked_mul(4),
+            Self::F16 | Self::BF16 => len.checked_mul(2),
+            Self::Q8_0 if len.is_multiple_of(Self::Q8_0_BLOCK as u64) => {
+                Some(len / Self::Q8_0_BLOCK as u64 * (Self::Q8_0_BLOCK + 2) as u64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Converts `data` of this type to floats, appending them to `out`.
+    ///
+    /// `data` must hold whole values or blocks of a supported type.
+    pub fn dequantize(self, data: &[u8], out: &mut Vec<f32>) {
+        match self {
+            Self::F32 => out.extend(
+                data.chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+            ),
+            Self::F16 => out.extend(
+                data.chunks_exact(2)
+                    .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32()),
+            ),
+            Self::BF16 => out.extend(
+                data.chunks_exact(2)
+                    .map(|b| bf16::from_le_bytes(b.try_into().unwrap()).to_f32()),
+            ),
+            Self::Q8_0 => {
+                for block in data.chunks_exact(Self::Q8_0_BLOCK + 2) {
+                    let (d, qs) = block.split_at(2);
+                    let d = f16::from_le_bytes(d.try_into().unwrap()).to_f32();
+                    out.extend(qs.iter().map(|&q| q as i8 as f32 * d));
+                }
+            }
+            Self::Other(ty) => unreachable!("unsupported ggml type {ty}"),
+        }
+    }
+}
+
+/// The description of a tensor in the tensor-info table.
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub name: String,
+    /// dimensions, innermost first: a matrix of `d` rows of `n` values is `[n, d]`
+    pub dims: Vec<u64>,
+    pub ggml_type: GgmlType,
+    /// offset of the data from the start of the tensor data section
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    /// Number of values in the tensor, saturating at `u64::MAX`.
+    ///
+    /// Tensors read by [`Gguf::open`] are checked not to overflow.
+    pub fn len(&self) -> u64 {
+        self.checked_len().unwrap_or(u64::MAX)
+    }
+
+    fn checked_len(&self) -> Option<u64> {
+        self.dims
+            .iter()
+            .try_fold(1_u64, |len, &dim| len.checked_mul(dim))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A memory mapped GGUF file with its metadata and tensor-info table parsed.
+pub struct Gguf {
+    mmap: Mmap,
+    path: PathBuf,
+    pub version: u32,
+    pub metadata: HashMap<String, Value>,
+    /// tensors in file order
+    pub tensors: Vec<TensorInfo>,
+    /// offset of the tensor data section in the file
+    data_offset: usize,
+}
+
+impl Gguf {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+        Self::from_mmap(mmap, path)
+    }
+
+    /// Parses the GGUF file mapped by `mmap`, read from `path`.
+    pub fn from_mmap(mmap: Mmap, path: &Path) -> Result<Self> {
+        let mut reader = Reader {
+            bytes: &mmap,
+            pos: 0,
+            path,
+        };
+        if !has_magic(reader.take(MAGIC.len(), "magic")?) {
+            return Err(Error::header(path, "not a GGUF file"));
+        }
+        let version = reader.u32("version")?;
+        // version 1 used 32-bit counts and lengths
+        if !(2..=3).contains(&version) {
+            return Err(Error::unsupported(
+                path,
+                format!("GGUF version {version}, expected 2 or 3"),
+            ));
+        }
+        let tensor_count = reader.u64("tensor count")?;
+        let metadata_count = reader.u64("metadata count")?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_count {
+            let key = reader.string("metadata key")?;
+            let ty = reader.u32(&key)?;
+            let value = reader.value(ty, &key, 0)?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..tensor_count {
+            let name = reader.string("tensor name")?;
+            let n_dims = reader.u32(&name)?;
+            let dims = (0..n_dims)
+                .map(|_| reader.u64(&name))
+                .collect::<Result<Vec<_>>>()?;
+            let ggml_type = GgmlType::from_u32(reader.u32(&name)?);
+            let offset = reader.u64(&name)?;
+            let tensor = TensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            };
+            if tensor.checked_len().is_none() {
+                return Err(Error::header(
+                    path,
+                    format!(
+                        "tensor {} has too many values, dims {:?}",
+                        tensor.name, tensor.dims
+                    ),
+                ));
+            }
+            tensors.push(tensor);
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            Some(value) => value
+                .as_u64()
+                .filter(|&alignment| alignment > 0)
+                .ok_or_else(|| Error::header(path, format!("invalid alignment {value:?}")))?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_offset = (reader.pos as u64).next_multiple_of(alignment);
+
+        // check that the data of every supported tensor is in the file
+        for tensor in &tensors {
+            let Some(size) = tensor.ggml_type.size_of(tensor.len()) else {
+                continue;
+            };
+            let end = tensor
+                .offset
+                .checked_add(size)
+                .and_then(|len| len.checked_add(data_offset))
+                .ok_or_else(|| {
+                    Error::header(path, format!("tensor {} is too large", tensor.name))
+                })?;
+            if end > mmap.len() as u64 {
+                return Err(Error::Truncated {
+                    path: path.into(),
+                    what: format!("tensor {}", tensor.name),
+                    expected: end as usize,
+                    actual: mmap.len(),
+                });
+            }
+        }
+
+        Ok(Self {
+            path: path.into(),
+            version,
+            metadata,
+            tensors,
+            data_offset: data_offset as usize,
+            mmap,
+        })
+    }
+
+    /// The path the file was read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.metadata.get(key)
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.iter().find(|tensor| tensor.name == name)
+    }
+
+    /// The raw data of `tensor`, which must be of a supported type.
+    pub fn tensor_data(&self, tensor: &TensorInfo) -> &[u8] {
+        let size = tensor.ggml_type.size_of(tensor.len()).unwrap();
+        &self.mmap[self.data_offset(tensor)..][..size as usize]
+    }
+
+    /// Offset of the data of `tensor` in the file.
+    pub(crate) fn data_offset(&self, tensor: &TensorInfo) -> usize {
+        self.data_offset + tensor.offset as usize
+    }
+
+    /// The mapping of the whole file, for the tensors to be read in place.
+    pub(crate) fn into_mmap(self) -> Mmap {
+        self.mmap
+    }
+}
+
+/// Sequential little endian reads over the header of a GGUF file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    path: &'a Path,
+}
+
+impl<'a> Reader<'a> {
+    /// Takes the next `len` bytes, which belong to `what`.
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| Error::Truncated {
+                path: self.path.into(),
+                what: what.into(),
+                expected: self.pos.saturating_add(len),
+                actual: self.bytes.len(),
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self, what: &str) -> Result<[u8; N]> {
+        Ok(self.take(N, what)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32> {
+        self.array(what).map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64> {
+        self.array(what).map(u64::from_le_bytes)
+    }
+
+    /// A length followed by as many items of at least `item_size` bytes.
+    fn len(&mut self, item_size: usize, what: &str) -> Result<usize> {
+        let len = self.u64(what)?;
+        // refuse lengths the rest of the file cannot hold before allocating
+        let size = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_mul(item_size))
+            .unwrap_or(usize::MAX);
+        if size > self.bytes.len() - self.pos {
+            return Err(Error::Truncated {
+                path: self.path.into(),
+                what: what.into(),
+                expected: self.pos.saturating_add(size),
+                actual: self.bytes.len(),
+            });
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self, what: &str) -> Result<String> {
+        let len = self.len(1, what)?;
+        let bytes = self.take(len, what)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::header(self.path, format!("{what} is not valid UTF-8")))
+    }
+
+    /// A metadata value of type `ty`, belonging to the metadata key `key`,
+    /// nested `depth` arrays deep.
+    fn value(&mut self, ty: u32, key: &str, depth: u32) -> Result<Value> {
+        Ok(match ty {
+            0 => Value::U8(u8::from_le_bytes(self.array(key)?)),
+            1 => Value::I8(i8::from_le_bytes(self.array(key)?)),
+            2 => Value::U16(u16::from_le_bytes(self.array(key)?)),
+            3 => Value::I16(i16::from_le_bytes(self.array(key)?)),
+            4 => Value::U32(u32::from_le_bytes(self.array(key)?)),
+            5 => Value::I32(i32::from_le_bytes(self.array(key)?)),
+            6 => Value::F32(f32::from_le_bytes(self.array(key)?)),
+            7 => Value::Bool(self.array::<1>(key)?[0] != 0),
+            8 => Value::String(self.string(key)?),
+            9 if depth == MAX_ARRAY_DEPTH => {
+                return Err(Error::header(
+                    self.path,
+                    format!("metadata {key} nests arrays more than {MAX_ARRAY_DEPTH} deep"),
+                ))
+            }
+            9 => {
+                let ty = self.u32(key)?;
+                let len = self.len(1, key)?;
+                let values = (0..len)
+                    .map(|_| self.value(ty, key, depth + 1))
+                    .collect::<Result<_>>()?;
+                Value::Array(values)
+            }
+            10 => Value::U64(u64::from_le_bytes(self.array(key)?)),
+            11 => Value::I64(i64::from_le_bytes(self.array(key)?)),
+            12 => Value::F64(f64::from_le_bytes(self.array(key)?)),
+            ty => {
+                return Err(Error::header(
+                    self.path,
+                    format!("unknown type {ty} of metadata {key}"),
+                ))
+            }
+        })
+    }
+}
@@ -1,53 +1,427 @@
-pub fn rms_norm(x: &[f32], weitht: &[f32], output: &mut [f32], size: usize) {
-    let mut sum = 0.0;
-    for &i in x.iter().take(size) {
-        sum += i * i;
+//! Numeric kernels of the forward pass.
+//!
+//! Every kernel has a scalar reference implementation and SIMD variants for
+//! AVX2/FMA and AVX-512 on x86_64 and NEON on aarch64. The best instruction
+//! set supported by the CPU is detected once at runtime, see [`Isa`].
+
+use std::sync::OnceLock;
+
+use half::{bf16, f16};
+use log::{debug, warn};
+use rayon::prelude::*;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+mod scalar;
+mod simd;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+/// Matrices with fewer elements are multiplied on the calling thread, waking
+/// the workers up would cost more than it saves.
+const PARALLEL_THRESHOLD: usize = 1 << 15;
+
+/// Element types of weight matrices, converted to fp32 as they are read.
+trait Weight: Copy {
+    fn to_f32(self) -> f32;
+
+    /// Loads `S::LANES` weights as floats.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support `S` and `p` must point to `S::LANES` weights.
+    unsafe fn load<S: simd::Simd>(p: *const Self) -> S::V;
+}
+
+impl Weight for f32 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline(always)]
+    unsafe fn load<S: simd::Simd>(p: *const Self) -> S::V {
+        S::load(p)
     }
+}
 
-    sum /= size as f32;
-    sum += 1e-5_f32;
-    sum = 1.0_f32 / sum.sqrt();
+impl Weight for f16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
 
-    for i in 0..size {
-        output[i] = x[i] * sum * weitht[i];
+    #[inline(always)]
+    unsafe fn load<S: simd::Simd>(p: *const Self) -> S::V {
+        S::load_f16(p)
     }
 }
 
-pub fn softmax(x: &mut [f32], size: usize) {
-    let mut max = x[0];
-    for &i in x.iter().take(size) {
-        if i > max {
-            max = i;
+impl Weight for bf16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    #[inline(always)]
+    unsafe fn load<S: simd::Simd>(p: *const Self) -> S::V {
+        S::load_bf16(p)
+    }
+}
+
+/// Instruction sets the kernels are implemented for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isa {
+    /// plain scalar code, the reference implementation
+    Scalar,
+    /// x86_64 AVX2 with FMA and F16C
+    Avx2,
+    /// x86_64 AVX-512F
+    Avx512,
+    /// aarch64 NEON
+    Neon,
+}
+
+impl Isa {
+    /// The best instruction set supported by the running CPU.
+    pub fn detect() -> Self {
+        [Self::Avx512, Self::Avx2, Self::Neon]
+            .into_iter()
+            .find(|isa| isa.is_supported())
+            .unwrap_or(Self::Scalar)
+    }
+
+    /// The instruction set used by the free functions of this module,
+    /// detected on first use.
+    pub fn current() -> Self {
+        static CURRENT: OnceLock<Isa> = OnceLock::new();
+        *CURRENT.get_or_init(|| {
+            let isa = Self::detect();
+            debug!("kernel instruction set: {isa:?}");
+            isa
+        })
+    }
+
+    /// Every instruction set supported by the running CPU.
+    pub fn supported() -> Vec<Self> {
+        [Self::Scalar, Self::Avx2, Self::Avx512, Self::Neon]
+            .into_iter()
+            .filter(|isa| isa.is_supported())
+            .collect()
+    }
+
+    /// Whether the running CPU supports this instruction set.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => {
+                is_x86_feature_detected!("avx2")
+                    && is_x86_feature_detected!("fma")
+                    && is_x86_feature_detected!("f16c")
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Falls back to scalar code if the CPU does not support `self`, which
+    /// makes calling the `#[target_feature]` kernels sound.
+    #[inline]
+    fn checked(self) -> Self {
+        if self.is_supported() {
+            self
+        } else {
+            Self::Scalar
+        }
+    }
+
+    pub fn rms_norm(self, x: &[f32], weight: &[f32], output: &mut [f32], size: usize) {
+        match self.checked() {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::rms_norm(x, weight, output, size) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::rms_norm(x, weight, output, size) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::rms_norm(x, weight, output, size) },
+            _ => scalar::rms_norm(x, weight, output, size),
+        }
+    }
+
+    pub fn softmax(self, x: &mut [f32], size: usize) {
+        match self.checked() {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::softmax(x, size) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::softmax(x, size) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::softmax(x, size) },
+            _ => scalar::softmax(x, size),
+        }
+    }
+
+    pub fn matmul(self, x: &[f32], w: &[f32], o: &mut [f32], n: usize, d: usize) {
+        self.checked().matmul_with(x, w, o, n, d, Self::matmul_rows)
+    }
+
+    /// Variant of [`Isa::matmul`] for f16 weights, converted to fp32 inside
+    /// the inner loop.
+    pub fn matmul_f16(self, x: &[f32], w: &[f16], o: &mut [f32], n: usize, d: usize) {
+        self.checked()
+            .matmul_with(x, w, o, n, d, Self::matmul_f16_rows)
+    }
+
+    /// Variant of [`Isa::matmul`] for bf16 weights, converted to fp32 inside
+    /// the inner loop.
+    pub fn matmul_bf16(self, x: &[f32], w: &[bf16], o: &mut [f32], n: usize, d: usize) {
+        self.checked()
+            .matmul_with(x, w, o, n, d, Self::matmul_bf16_rows)
+    }
+
+    /// `W (d, n) @ x (n,) -> o (d,)`, computing blocks of rows with `rows`.
+    /// `self` must be supported.
+    #[inline]
+    fn matmul_with<W: Sync>(
+        self,
+        x: &[f32],
+        w: &[W],
+        o: &mut [f32],
+        n: usize,
+        d: usize,
+        rows: impl Fn(Self, &[f32], &[W], &mut [f32], usize) + Sync,
+    ) {
+        // by far the most amount of time is spent inside this little function,
+        // so the rows are spread over the worker pool. each row is still summed
+        // by a single thread, the result does not depend on the number of threads.
+        let x = &x[..n];
+        let o = &mut o[..d];
+        if n * d < PARALLEL_THRESHOLD {
+            rows(self, x, &w[..d * n], o, n);
+        } else {
+            // hand out a few rows per task to keep the scheduling overhead low
+            let chunk_rows = (PARALLEL_THRESHOLD / n).max(1);
+            o.par_chunks_mut(chunk_rows)
+                .enumerate()
+                .for_each(|(chunk, o)| {
+                    let w = &w[chunk * chunk_rows * n..][..o.len() * n];
+                    rows(self, x, w, o, n)
+                });
+        }
+    }
+
+    /// Computes `o.len()` rows of `W @ x`, where `w` holds exactly these rows.
+    /// `self` must be supported.
+    #[inline]
+    fn matmul_rows(self, x: &[f32], w: &[f32], o: &mut [f32], n: usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::matmul_rows(x, w, o, n) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::matmul_rows(x, w, o, n) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::matmul_rows(x, w, o, n) },
+            _ => scalar::matmul_rows(x, w, o, n),
+        }
+    }
+
+    #[inline]
+    fn matmul_f16_rows(self, x: &[f32], w: &[f16], o: &mut [f32], n: usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::matmul_f16_rows(x, w, o, n) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::matmul_f16_rows(x, w, o, n) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::matmul_f16_rows(x, w, o, n) },
+            _ => scalar::matmul_rows(x, w, o, n),
+        }
+    }
+
+    #[inline]
+    fn matmul_bf16_rows(self, x: &[f32], w: &[bf16], o: &mut [f32], n: usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::matmul_bf16_rows(x, w, o, n) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::matmul_bf16_rows(x, w, o, n) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::matmul_bf16_rows(x, w, o, n) },
+            _ => scalar::matmul_rows(x, w, o, n),
         }
     }
 
-    let mut sum = 0.0;
-    for item in x.iter_mut().take(size) {
-        *item = (*item - max).exp();
-        sum += *item;
+    /// Quantized-activation variant of [`Isa::matmul`], `x` and `w` must share
+    /// a group size that divides `n`.
+    pub fn matmul_q8(
+        self,
+        x: QuantizedTensor,
+        w: QuantizedTensor,
+        o: &mut [f32],
+        n: usize,
+        d: usize,
+    ) {
+        assert_eq!(x.group_size, w.group_size, "mismatched group sizes");
+        assert!(
+            n.is_multiple_of(x.group_size),
+            "{n} is not a multiple of the group size"
+        );
+        let isa = self.checked();
+        let gs = x.group_size;
+        let o = &mut o[..d];
+        if n * d < PARALLEL_THRESHOLD {
+            isa.matmul_q8_rows(x, w, o, n);
+        } else {
+            let rows = (PARALLEL_THRESHOLD / n).max(1);
+            o.par_chunks_mut(rows).enumerate().for_each(|(chunk, o)| {
+                let w = QuantizedTensor {
+                    q: &w.q[chunk * rows * n..],
+                    s: &w.s[chunk * rows * n / gs..],
+                    group_size: gs,
+                };
+                isa.matmul_q8_rows(x, w, o, n)
+            });
+        }
+    }
+
+    /// Computes `o.len()` rows of the quantized `W @ x`, where `w` starts at
+    /// the first row. `self` must be supported.
+    #[inline]
+    fn matmul_q8_rows(self, x: QuantizedTensor, w: QuantizedTensor, o: &mut [f32], n: usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::matmul_q8_rows(x, w, o, n) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::matmul_q8_rows(x, w, o, n) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::matmul_q8_rows(x, w, o, n) },
+            _ => scalar::matmul_q8_rows(x, w, o, n),
+        }
     }
 
-    for item in x.iter_mut().take(size) {
-        *item /= sum;
+    pub fn swiglu(self, x: &mut [f32], y: &[f32], size: usize) {
+        match self.checked() {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86_64::avx2::swiglu(x, y, size) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86_64::avx512::swiglu(x, y, size) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::neon::swiglu(x, y, size) },
+            _ => scalar::swiglu(x, y, size),
+        }
     }
 }
 
+pub fn rms_norm(x: &[f32], weitht: &[f32], output: &mut [f32], size: usize) {
+    Isa::current().rms_norm(x, weitht, output, size)
+}
+
+pub fn softmax(x: &mut [f32], size: usize) {
+    Isa::current().softmax(x, size)
+}
+
 pub fn matmul(x: &[f32], w: &[f32], o: &mut [f32], n: usize, d: usize) {
-    // W (d, n) @ x (n,) -> xout (d,)
-    // bu far the most amount of time is spent inside this little function
-    for i in 0..d {
-        let mut val = 0.0_f32;
-        for j in 0..n {
-            val += w[i * n + j] * x[j];
+    Isa::current().matmul(x, w, o, n, d)
+}
+
+/// `W (d, n) @ x (n,)` with f16 weights.
+pub fn matmul_f16(x: &[f32], w: &[f16], o: &mut [f32], n: usize, d: usize) {
+    Isa::current().matmul_f16(x, w, o, n, d)
+}
+
+/// `W (d, n) @ x (n,)` with bf16 weights.
+pub fn matmul_bf16(x: &[f32], w: &[bf16], o: &mut [f32], n: usize, d: usize) {
+    Isa::current().matmul_bf16(x, w, o, n, d)
+}
+
+/// Quantized-activation matmul, `W (d, n) @ x (n,)` with both operands
+/// quantized to Q8_0.
+pub fn matmul_q8(x: QuantizedTensor, w: QuantizedTensor, o: &mut [f32], n: usize, d: usize) {
+    Isa::current().matmul_q8(x, w, o, n, d)
+}
+
+/// A tensor quantized to `i8` in groups of `group_size` consecutive values,
+/// each group scaled by one `f32` (llama2.c's Q8_0).
+#[derive(Debug, Clone, Copy)]
+pub struct QuantizedTensor<'a> {
+    /// quantized values
+    pub q: &'a [i8],
+    /// scaling factor of each group
+    pub s: &'a [f32],
+    /// number of values sharing a scaling factor
+    pub group_size: usize,
+}
+
+impl QuantizedTensor<'_> {
+    /// Dequantizes the first `o.len()` values into `o`.
+    pub fn dequantize(&self, o: &mut [f32]) {
+        for (i, o) in o.iter_mut().enumerate() {
+            *o = self.q[i] as f32 * self.s[i / self.group_size];
         }
-        o[i] = val;
     }
 }
 
-pub fn SwiGLU(x: &mut [f32], y: &[f32], size: usize) {
-    // silu(x)=x*σ(x), where σ(x) is the logistic sigmoid
-    for i in 0..size {
-        x[i] *= 1.0_f32 / (1.0_f32 + (-x[i]).exp());
-        x[i] *= y[i];
+/// Owned buffers for activations quantized on the fly.
+#[derive(Debug, Default)]
+pub struct QuantizedVec {
+    q: Vec<i8>,
+    s: Vec<f32>,
+}
+
+impl QuantizedVec {
+    /// Quantizes `x` with symmetric scaling in groups of `group_size`, the
+    /// length of `x` must be a multiple of the group size.
+    pub fn quantize(&mut self, x: &[f32], group_size: usize) -> QuantizedTensor<'_> {
+        assert!(
+            x.len().is_multiple_of(group_size),
+            "{} is not a multiple of the group size",
+            x.len()
+        );
+        let num_groups = x.len() / group_size;
+        self.q.resize(x.len(), 0);
+        self.s.resize(num_groups, 0.0);
+        const Q_MAX: f32 = 127.0;
+        for ((x, q), s) in x
+            .chunks_exact(group_size)
+            .zip(self.q.chunks_exact_mut(group_size))
+            .zip(&mut self.s)
+        {
+            // find the max absolute value in the current group
+            let wmax = x.iter().fold(0.0_f32, |max, v| max.max(v.abs()));
+            // calculate and write the scaling factor
+            *s = wmax / Q_MAX;
+            // calculate and write the quantized values, an all-zero group
+            // divides by zero and the NaN saturates to 0
+            for (q, &x) in q.iter_mut().zip(x) {
+                *q = (x / *s).round() as i8;
+            }
+        }
+        QuantizedTensor {
+            q: &self.q[..x.len()],
+            s: &self.s[..num_groups],
+            group_size,
+        }
+    }
+}
+
+/// Starts the persistent worker pool used by the kernels with `num_threads`
+/// threads, `0` picks one per core (or `RAYON_NUM_THREADS` when set).
+///
+/// The pool can only be configured once, before the first kernel runs.
+pub fn init_thread_pool(num_threads: usize) {
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+    {
+        warn!("failed to configure the thread pool: {e}");
     }
+    debug!("kernel threads: {}", rayon::current_num_threads());
+}
+
+#[allow(non_snake_case)]
+pub fn SwiGLU(x: &mut [f32], y: &[f32], size: usize) {
+    Isa::current().swiglu(x, y, size)
 }
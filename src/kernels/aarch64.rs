@@ -0,0 +1,84 @@
+//! NEON kernels.
+
+use std::arch::aarch64::*;
+
+use half::{bf16, f16};
+
+use super::simd::{simd_kernels, Simd};
+
+pub struct Neon;
+
+impl Simd for Neon {
+    type V = float32x4_t;
+    const LANES: usize = 4;
+
+    #[inline(always)]
+    unsafe fn splat(x: f32) -> float32x4_t {
+        vdupq_n_f32(x)
+    }
+    #[inline(always)]
+    unsafe fn load(p: *const f32) -> float32x4_t {
+        vld1q_f32(p)
+    }
+    #[inline(always)]
+    unsafe fn load_f16(p: *const f16) -> float32x4_t {
+        // the f16 conversion intrinsics are not stable yet
+        let v = [0, 1, 2, 3].map(|i| (*p.add(i)).to_f32());
+        vld1q_f32(v.as_ptr())
+    }
+    #[inline(always)]
+    unsafe fn load_bf16(p: *const bf16) -> float32x4_t {
+        // bf16 is the upper half of an f32
+        let v = vmovl_u16(vld1_u16(p.cast()));
+        vreinterpretq_f32_u32(vshlq_n_u32::<16>(v))
+    }
+    #[inline(always)]
+    unsafe fn store(p: *mut f32, v: float32x4_t) {
+        vst1q_f32(p, v)
+    }
+    #[inline(always)]
+    unsafe fn add(a: float32x4_t, b: float32x4_t) -> float32x4_t {
+        vaddq_f32(a, b)
+    }
+    #[inline(always)]
+    unsafe fn mul(a: float32x4_t, b: float32x4_t) -> float32x4_t {
+        vmulq_f32(a, b)
+    }
+    #[inline(always)]
+    unsafe fn div(a: float32x4_t, b: float32x4_t) -> float32x4_t {
+        vdivq_f32(a, b)
+    }
+    #[inline(always)]
+    unsafe fn fmadd(a: float32x4_t, b: float32x4_t, c: float32x4_t) -> float32x4_t {
+        vfmaq_f32(c, a, b)
+    }
+    #[inline(always)]
+    unsafe fn max(a: float32x4_t, b: float32x4_t) -> float32x4_t {
+        vmaxq_f32(a, b)
+    }
+    #[inline(always)]
+    unsafe fn min(a: float32x4_t, b: float32x4_t) -> float32x4_t {
+        vminq_f32(a, b)
+    }
+    #[inline(always)]
+    unsafe fn floor(a: float32x4_t) -> float32x4_t {
+        vrndmq_f32(a)
+    }
+    #[inline(always)]
+    unsafe fn pow2i(n: float32x4_t) -> float32x4_t {
+        let n = vaddq_s32(vcvtq_s32_f32(n), vdupq_n_s32(127));
+        vreinterpretq_f32_s32(vshlq_n_s32::<23>(n))
+    }
+    #[inline(always)]
+    unsafe fn reduce_sum(a: float32x4_t) -> f32 {
+        vaddvq_f32(a)
+    }
+    #[inline(always)]
+    unsafe fn reduce_max(a: float32x4_t) -> f32 {
+        vmaxvq_f32(a)
+    }
+}
+
+pub mod neon {
+    super::simd_kernels!(super::Neon, "neon");
+}
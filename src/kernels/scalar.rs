@@ -0,0 +1,80 @@
+//! Plain scalar kernels, the reference every SIMD implementation is checked
+//! against and the fallback on CPUs without a supported instruction set.
+
+use super::{QuantizedTensor, Weight};
+
+pub fn rms_norm(x: &[f32], weitht: &[f32], output: &mut [f32], size: usize) {
+    let mut sum = 0.0;
+    for &i in x.iter().take(size) {
+        sum += i * i;
+    }
+
+    sum /= size as f32;
+    sum += 1e-5_f32;
+    sum = 1.0_f32 / sum.sqrt();
+
+    for i in 0..size {
+        output[i] = x[i] * sum * weitht[i];
+    }
+}
+
+pub fn softmax(x: &mut [f32], size: usize) {
+    let mut max = x[0];
+    for &i in x.iter().take(size) {
+        if i > max {
+            max = i;
+        }
+    }
+
+    let mut sum = 0.0;
+    for item in x.iter_mut().take(size) {
+        *item = (*item - max).exp();
+        sum += *item;
+    }
+
+    for item in x.iter_mut().take(size) {
+        *item /= sum;
+    }
+}
+
+/// Computes `o.len()` rows of `W @ x`, where `w` starts at the first row and
+/// is converted to floats on the fly.
+pub fn matmul_rows<W: Weight>(x: &[f32], w: &[W], o: &mut [f32], n: usize) {
+    for (i, o) in o.iter_mut().enumerate() {
+        let mut val = 0.0_f32;
+        for j in 0..n {
+            val += w[i * n + j].to_f32() * x[j];
+        }
+        *o = val;
+    }
+}
+
+/// Computes `o.len()` rows of the quantized `W @ x`, where `w` starts at the
+/// first row.
+///
+/// Also compiled into the SIMD entry points, where the integer dot products
+/// are auto-vectorized.
+#[inline(always)]
+pub fn matmul_q8_rows(x: QuantizedTensor, w: QuantizedTensor, o: &mut [f32], n: usize) {
+    let gs = x.group_size;
+    let (xq, xs) = (&x.q[..n], &x.s[..n / gs]);
+    for (i, o) in o.iter_mut().enumerate() {
+        let wq = &w.q[i * n..][..n];
+        let ws = &w.s[i * n / gs..][..n / gs];
+        let mut val = 0.0_f32;
+        // do the matmul in groups of gs
+        for (((xq, wq), xs), ws) in xq.chunks_exact(gs).zip(wq.chunks_exact(gs)).zip(xs).zip(ws) {
+            let ival: i32 = xq.iter().zip(wq).map(|(&x, &w)| x as i32 * w as i32).sum();
+            val += ival as f32 * ws * xs;
+        }
+        *o = val;
+    }
+}
+
+pub fn swiglu(x: &mut [f32], y: &[f32], size: usize) {
+    // silu(x)=x*σ(x), where σ(x) is the logistic sigmoid
+    for i in 0..size {
+        x[i] *= 1.0_f32 / (1.0_f32 + (-x[i]).exp());
+        x[i] *= y[i];
+    }
+}
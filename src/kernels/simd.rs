@@ -0,0 +1,240 @@
+//! Kernels written once over a vector abstraction, instantiated for every
+//! instruction set by [`simd_kernels!`].
+//!
+//! Everything here is `#[inline(always)]` so that it is compiled inside the
+//! `#[target_feature]` entry points and the intrinsics get inlined.
+
+use half::{bf16, f16};
+
+use super::Weight;
+
+/// A vector of `LANES` floats and the operations the kernels need on it.
+///
+/// # Safety
+///
+/// The methods may only be called when the CPU supports the instruction set.
+pub(super) trait Simd {
+    type V: Copy;
+    const LANES: usize;
+
+    unsafe fn splat(x: f32) -> Self::V;
+    /// unaligned load of `LANES` floats
+    unsafe fn load(p: *const f32) -> Self::V;
+    /// unaligned load of `LANES` f16 values, converted to floats
+    unsafe fn load_f16(p: *const f16) -> Self::V;
+    /// unaligned load of `LANES` bf16 values, converted to floats
+    unsafe fn load_bf16(p: *const bf16) -> Self::V;
+    /// unaligned store of `LANES` floats
+    unsafe fn store(p: *mut f32, v: Self::V);
+    unsafe fn add(a: Self::V, b: Self::V) -> Self::V;
+    unsafe fn mul(a: Self::V, b: Self::V) -> Self::V;
+    unsafe fn div(a: Self::V, b: Self::V) -> Self::V;
+    /// `a * b + c`
+    unsafe fn fmadd(a: Self::V, b: Self::V, c: Self::V) -> Self::V;
+    unsafe fn max(a: Self::V, b: Self::V) -> Self::V;
+    unsafe fn min(a: Self::V, b: Self::V) -> Self::V;
+    unsafe fn floor(a: Self::V) -> Self::V;
+    /// `2^n` for a vector of integral `n` in the normal exponent range
+    unsafe fn pow2i(n: Self::V) -> Self::V;
+    unsafe fn reduce_sum(a: Self::V) -> f32;
+    unsafe fn reduce_max(a: Self::V) -> f32;
+}
+
+/// `exp(x)` with the Cephes polynomial, accurate to a few ulp.
+#[inline(always)]
+unsafe fn exp<S: Simd>(x: S::V) -> S::V {
+    // keep 2^n inside the normal range, exp(88) is still finite
+    let x = S::min(S::max(x, S::splat(-87.0)), S::splat(88.0));
+    // express exp(x) as exp(g + n * ln(2))
+    let n = S::floor(S::fmadd(
+        x,
+        S::splat(std::f32::consts::LOG2_E),
+        S::splat(0.5),
+    ));
+    let g = S::fmadd(n, S::splat(-0.693_359_4), x);
+    let g = S::fmadd(n, S::splat(2.121_944_4e-4), g);
+
+    let mut y = S::splat(1.987_569_1e-4);
+    y = S::fmadd(y, g, S::splat(1.398_199_9e-3));
+    y = S::fmadd(y, g, S::splat(8.333_452e-3));
+    y = S::fmadd(y, g, S::splat(4.166_579_6e-2));
+    y = S::fmadd(y, g, S::splat(1.666_666_5e-1));
+    y = S::fmadd(y, g, S::splat(0.5));
+    y = S::fmadd(y, S::mul(g, g), g);
+    y = S::add(y, S::splat(1.0));
+    S::mul(y, S::pow2i(n))
+}
+
+/// Dot product of weights `a`, converted to floats on the fly, with `b`.
+#[inline(always)]
+pub(super) unsafe fn dot<S: Simd, W: Weight>(a: &[W], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (pa, pb) = (a.as_ptr(), b.as_ptr());
+    let lanes = S::LANES;
+    // four independent accumulators hide the latency of the fma
+    let mut acc = [S::splat(0.0); 4];
+    let mut i = 0;
+    while i + 4 * lanes <= n {
+        for (k, acc) in acc.iter_mut().enumerate() {
+            let j = i + k * lanes;
+            *acc = S::fmadd(W::load::<S>(pa.add(j)), S::load(pb.add(j)), *acc);
+        }
+        i += 4 * lanes;
+    }
+    while i + lanes <= n {
+        acc[0] = S::fmadd(W::load::<S>(pa.add(i)), S::load(pb.add(i)), acc[0]);
+        i += lanes;
+    }
+    let acc = S::add(S::add(acc[0], acc[1]), S::add(acc[2], acc[3]));
+    let mut sum = S::reduce_sum(acc);
+    for j in i..n {
+        sum += a[j].to_f32() * b[j];
+    }
+    sum
+}
+
+#[inline(always)]
+pub(super) unsafe fn matmul_rows<S: Simd, W: Weight>(x: &[f32], w: &[W], o: &mut [f32], n: usize) {
+    for (i, o) in o.iter_mut().enumerate() {
+        *o = dot::<S, W>(&w[i * n..][..n], x);
+    }
+}
+
+#[inline(always)]
+pub(super) unsafe fn rms_norm<S: Simd>(x: &[f32], weight: &[f32], output: &mut [f32], size: usize) {
+    let (x, weight, output) = (&x[..size], &weight[..size], &mut output[..size]);
+    let ss = dot::<S, f32>(x, x) / size as f32 + 1e-5_f32;
+    let scale = 1.0_f32 / ss.sqrt();
+
+    let lanes = S::LANES;
+    let vscale = S::splat(scale);
+    let mut i = 0;
+    while i + lanes <= size {
+        let v = S::mul(
+            S::mul(S::load(x.as_ptr().add(i)), vscale),
+            S::load(weight.as_ptr().add(i)),
+        );
+        S::store(output.as_mut_ptr().add(i), v);
+        i += lanes;
+    }
+    for j in i..size {
+        output[j] = x[j] * scale * weight[j];
+    }
+}
+
+#[inline(always)]
+pub(super) unsafe fn softmax<S: Simd>(x: &mut [f32], size: usize) {
+    let x = &mut x[..size];
+    let lanes = S::LANES;
+    let p = x.as_mut_ptr();
+
+    // find max value (for numerical stability)
+    let mut max = x[0];
+    let mut i = 0;
+    if size >= lanes {
+        let mut vmax = S::load(p);
+        while i + lanes <= size {
+            vmax = S::max(vmax, S::load(p.add(i)));
+            i += lanes;
+        }
+        max = S::reduce_max(vmax);
+    }
+    for &v in &x[i..] {
+        max = max.max(v);
+    }
+
+    // exp and sum
+    let vmax = S::splat(max);
+    let mut vsum = S::splat(0.0);
+    let mut i = 0;
+    while i + lanes <= size {
+        let v = exp::<S>(S::add(S::load(p.add(i)), S::mul(vmax, S::splat(-1.0))));
+        S::store(p.add(i), v);
+        vsum = S::add(vsum, v);
+        i += lanes;
+    }
+    let mut sum = S::reduce_sum(vsum);
+    for v in &mut x[i..] {
+        *v = (*v - max).exp();
+        sum += *v;
+    }
+
+    // normalize
+    let vsum = S::splat(sum);
+    let mut i = 0;
+    while i + lanes <= size {
+        S::store(p.add(i), S::div(S::load(p.add(i)), vsum));
+        i += lanes;
+    }
+    for v in &mut x[i..] {
+        *v /= sum;
+    }
+}
+
+#[inline(always)]
+pub(super) unsafe fn swiglu<S: Simd>(x: &mut [f32], y: &[f32], size: usize) {
+    let (x, y) = (&mut x[..size], &y[..size]);
+    let lanes = S::LANES;
+    let one = S::splat(1.0);
+    let mut i = 0;
+    while i + lanes <= size {
+        let v = S::load(x.as_ptr().add(i));
+        // silu(x)=x*σ(x), where σ(x) is the logistic sigmoid
+        let sigmoid = S::div(one, S::add(one, exp::<S>(S::mul(v, S::splat(-1.0)))));
+        let v = S::mul(S::mul(v, sigmoid), S::load(y.as_ptr().add(i)));
+        S::store(x.as_mut_ptr().add(i), v);
+        i += lanes;
+    }
+    for j in i..size {
+        x[j] *= 1.0_f32 / (1.0_f32 + (-x[j]).exp());
+        x[j] *= y[j];
+    }
+}
+
+/// Generates the `#[target_feature]` entry points of the kernels for the
+/// [`Simd`] implementation `$simd`, compiled with `$features`.
+macro_rules! simd_kernels {
+    ($simd:ty, $features:literal) => {
+        #[target_feature(enable = $features)]
+        pub unsafe fn matmul_rows(x: &[f32], w: &[f32], o: &mut [f32], n: usize) {
+            $crate::kernels::simd::matmul_rows::<$simd, f32>(x, w, o, n)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn matmul_f16_rows(x: &[f32], w: &[half::f16], o: &mut [f32], n: usize) {
+            $crate::kernels::simd::matmul_rows::<$simd, half::f16>(x, w, o, n)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn matmul_bf16_rows(x: &[f32], w: &[half::bf16], o: &mut [f32], n: usize) {
+            $crate::kernels::simd::matmul_rows::<$simd, half::bf16>(x, w, o, n)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn matmul_q8_rows(
+            x: $crate::kernels::QuantizedTensor,
+            w: $crate::kernels::QuantizedTensor,
+            o: &mut [f32],
+            n: usize,
+        ) {
+            $crate::kernels::scalar::matmul_q8_rows(x, w, o, n)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn rms_norm(x: &[f32], weight: &[f32], output: &mut [f32], size: usize) {
+            $crate::kernels::simd::rms_norm::<$simd>(x, weight, output, size)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn softmax(x: &mut [f32], size: usize) {
+            $crate::kernels::simd::softmax::<$simd>(x, size)
+        }
+
+        #[target_feature(enable = $features)]
+        pub unsafe fn swiglu(x: &mut [f32], y: &[f32], size: usize) {
+            $crate::kernels::simd::swiglu::<$simd>(x, y, size)
+        }
+    };
+}
+
+pub(super) use simd_kernels;
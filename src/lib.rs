@@ -0,0 +1,49 @@
+//! Inference for Llama-2 models stored in the llama2.c checkpoint format, in
+//! GGUF files or as HuggingFace safetensors.
+//!
+//! ```no_run
+//! use llama2_rs::{generate, Sampler, Tokenizer, Transformer};
+//!
+//! let mut transformer = Transformer::new("stories15M.bin")?;
+//! let tokenizer = Tokenizer::new("tokenizer.bin", transformer.config.vocab_size as usize)?;
+//! let mut sampler = Sampler::new(transformer.config.vocab_size, 1.0, 0.9, 42);
+//! generate(
+//!     &mut transformer,
+//!     &tokenizer,
+//!     &mut sampler,
+//!     "Once upon a time",
+//!     256,
+//!     |piece| print!("{piece}"),
+//! )?;
+//! # Ok::<(), llama2_rs::Error>(())
+//! ```
+//!
+//! To consume tokens programmatically, iterate over a [`Generator`] instead:
+//!
+//! ```no_run
+//! # use llama2_rs::{Generator, Sampler, Tokenizer, Transformer};
+//! # let mut transformer = Transformer::new("stories15M.bin")?;
+//! # let tokenizer = Tokenizer::new("tokenizer.bin", transformer.config.vocab_size as usize)?;
+//! # let mut sampler = Sampler::new(transformer.config.vocab_size, 1.0, 0.9, 42);
+//! let generator = Generator::new(&mut transformer, &tokenizer, &mut sampler, "Once", 256)?;
+//! for token in generator.take_while(|token| token.logprob > -10.0) {
+//!     println!("{} {:?} {}", token.pos, token.piece, token.logprob);
+//! }
+//! # Ok::<(), llama2_rs::Error>(())
+//! ```
+
+pub mod error;
+pub mod generator;
+pub mod gguf;
+pub mod kernels;
+pub mod safetensors;
+pub mod sampler;
+pub mod sentencepiece;
+pub mod tokenizer;
+pub mod transformer;
+
+pub use error::{Error, Result};
+pub use generator::{generate, GeneratedToken, Generator};
+pub use sampler::Sampler;
+pub use tokenizer::Tokenizer;
+pub use transformer::Transformer;
@@ -1,143 +1,460 @@
 // #![deny(warnings)]
 #![allow(clippy::iter_nth_zero)]
 
-use std::{env, process::exit};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{env, process::exit, str::FromStr};
 
-use log::{debug, info};
-use sampler::Sampler;
-use tokenizer::Tokenizer;
-use transformer::Transformer;
-
-mod kernels;
-mod sampler;
-mod tokenizer;
-mod transformer;
+use llama2_rs::tokenizer::{BpeTokenizer, StreamDecoder, Tokenize};
+use llama2_rs::transformer::{CheckpointVersion, WeightType};
+use llama2_rs::{gguf, kernels, safetensors, sentencepiece};
+use llama2_rs::{Error, Result, Sampler, Tokenizer, Transformer};
+use log::{debug, info, warn};
+use serde_json::json;
 
 extern crate env_logger;
 extern crate log;
 
 fn usage_helper() -> ! {
-    exit(-1);
+    eprint!("{USAGE_HELP}");
+    exit(1);
 }
 
 fn generate(
     transformer: &mut Transformer,
-    tokenizer: &Tokenizer,
+    tokenizer: &dyn Tokenize,
     sampler: &mut Sampler,
     prompt: &str,
     steps: u32,
-) {
-    let num_prompt_tokens = 0;
-    // +3 for '\0', ?BOS, ?EOS
-    let prompt_tokens = vec![0i32; prompt.len() + 3];
+) -> Result<()> {
+    // used to time our code, only initialized after first iteration.
+    let mut start = None;
+    let pos = llama2_rs::generate(transformer, tokenizer, sampler, prompt, steps, |piece| {
+        // print the token as string
+        safe_print(piece);
+        // init the timer here because the first iteration can be slower
+        start.get_or_insert_with(Instant::now);
+    })?;
+    println!();
 
-    // TODO: encode
-    if num_prompt_tokens < 1 {
-        panic!("Something is wrong, expected at least 1 prompt token");
+    // report achieved tok/s (pos-1 because the timer starts after first iteration)
+    if let Some(start) = start {
+        if pos > 1 {
+            let elapsed = start.elapsed().as_secs_f64();
+            eprintln!("achieved tok/s: {}", (pos - 1) as f64 / elapsed);
+        }
     }
+    Ok(())
+}
 
-    // start the main loop.
-    // used to time our code, only initialized after first iteration.
-    let mut start = 0;
+/// Prints a decoded piece, skipping raw bytes that are not printable.
+fn safe_print(piece: &str) {
+    // some pieces are raw bytes like <0x01>, only print the ones that are
+    // printable or whitespace, the rest are likely control codes.
+    if let [byte] = piece.as_bytes() {
+        if !(byte.is_ascii_graphic() || byte.is_ascii_whitespace()) {
+            return;
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(piece.as_bytes()).unwrap();
+    stdout.flush().unwrap();
+}
+
+fn chat(
+    transformer: &mut Transformer,
+    tokenizer: &dyn Tokenize,
+    sampler: &mut Sampler,
+    cli_user_prompt: Option<&str>,
+    cli_system_prompt: Option<&str>,
+    steps: u32,
+) {
+    // the tokens of the current user turn, rendered with the chat template
+    let mut prompt_tokens = Vec::new();
+    // index of the next prompt token to feed into the transformer
+    let mut user_idx = 0;
+    // whether it is the user's turn to speak
+    let mut user_turn = true;
     // will store the next token in the sequence
     let mut next = 0;
-    // kick off with the first token in the prompt.
-    let mut roken = prompt_tokens[0];
-    // position in the sequence
+    // position in the sequence, kept across turns so the kv cache is reused
     let mut pos = 0;
+    // decodes the Assistant's response, started after the last prompt token
+    let mut decoder = None;
 
-    loop {
-        if pos >= steps {
-            break;
+    while pos < steps {
+        // when it is the user's turn to contribute tokens to the dialog...
+        if user_turn {
+            // get the (optional) system prompt at position 0
+            let system_prompt = if pos == 0 {
+                match cli_system_prompt {
+                    Some(prompt) => prompt.to_string(),
+                    None => match read_stdin("Enter system prompt (optional): ") {
+                        Some(prompt) => prompt,
+                        None => break,
+                    },
+                }
+            } else {
+                String::new()
+            };
+            // get the user prompt, the cli one is only used on the first turn
+            let user_prompt = match cli_user_prompt.filter(|_| pos == 0) {
+                Some(prompt) => prompt.to_string(),
+                None => match read_stdin("User: ") {
+                    Some(prompt) => prompt,
+                    None => break,
+                },
+            };
+            // render user/system prompts into the Llama 2 Chat schema
+            let rendered = if system_prompt.is_empty() {
+                format!("[INST] {user_prompt} [/INST]")
+            } else {
+                format!("[INST] <<SYS>>\n{system_prompt}\n<</SYS>>\n\n{user_prompt} [/INST]")
+            };
+            // encode the rendered prompt into tokens
+            prompt_tokens = tokenizer.encode(&rendered, true, false);
+            user_idx = 0;
+            user_turn = false;
+            decoder = None;
+            safe_print("Assistant: ");
+        }
+
+        // determine the token to pass into the transformer next
+        let token = if user_idx < prompt_tokens.len() {
+            // if we are still processing the input prompt, force the next prompt token
+            user_idx += 1;
+            prompt_tokens[user_idx - 1]
+        } else {
+            // otherwise use the next token sampled from previous turn
+            next
+        };
+        // EOS token ends the Assistant turn
+        if Some(token) == tokenizer.eos() {
+            user_turn = true;
+        }
+
+        // forward the transformer to get logits for the next token
+        let logits = transformer.forward(token, pos);
+        next = sampler.sample(logits);
+        pos += 1;
+
+        if user_idx >= prompt_tokens.len() && Some(next) != tokenizer.eos() {
+            // the Assistant is responding, so print its output
+            let decoder = decoder.get_or_insert_with(|| StreamDecoder::after(tokenizer, token));
+            safe_print(&decoder.push(next));
+        }
+        if Some(next) == tokenizer.eos() {
+            println!();
         }
     }
+    println!();
 }
 
-fn chat(
-    transformer: Transformer,
-    tokenizer: Tokenizer,
-    sampler: Sampler,
-    cli_user_prompt: &[u8],
-    cli_system_prompt: &[u8],
-    steps: i32,
-) {
-    todo!()
+/// Prints `guide` and reads a line from stdin, returning `None` at end of input.
+fn read_stdin(guide: &str) -> Option<String> {
+    safe_print(guide);
+    let mut buffer = String::new();
+    match io::stdin().read_line(&mut buffer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(buffer.trim_end_matches(['\r', '\n']).to_string()),
+    }
 }
 
 const USAGE_HELP: &str = "\
 Usage: cargo run <checkpoint> [OPTIONS]
+       cargo run convert <checkpoint> <output> [CONVERT OPTIONS]
+       cargo run tokenizer export <tokenizer.model|model.gguf> <tokenizer.bin>
+       cargo run tokenize <tokenizer> [TOKENIZE OPTIONS] [text]
+       cargo run detokenize <tokenizer> [--json] <id>...
 Options:
-     --tokenizer-path <string>
+     --tokenizer-path <string>    (tokenizer.bin, a SentencePiece .model or a HF tokenizer.json,
+                                  default: the vocabulary of a .gguf checkpoint, the
+                                  tokenizer next to a HF checkpoint, or tokenizer.bin)
      --temperature <float>
      --top-p <float>
      --steps <int>
      --prompt <string>
+     --system-prompt <string>     (only used in chat mode)
      --rng-seed <int>
+     --mode <generate|chat>
+     --threads <int>              (default: RAYON_NUM_THREADS or one per core)
+     --weight-type <f32|f16|bf16> (default: as stored in the checkpoint)
+Convert options:
+     --version <0|1|2>            (default: 0, legacy fp32, 1 is fp32 and 2 is Q8_0)
+     --group-size <int>           (only with --version 2, default: the largest of 64, 32, ..., 2
+                                  that divides the dimensions)
+Tokenize options (the tokenizer is a tokenizer.bin, a SentencePiece .model, a HF tokenizer.json
+                  or a .gguf file):
+     --bos, --eos                 (add BOS or EOS around the text)
+     --special                    (encode special token text like <s> as the token)
+     --json                       (print JSON, also for detokenize)
+                                  the text is read from stdin when not given, without its
+                                  last newline
 ";
 
-fn main() {
-    env_logger::init();
-    let mut argv = env::args();
-    argv.next().unwrap();
-
-    struct Args {
-        checkpoint_path: String,
-        tokenizer_path: String,
-        temperature: f32,
-        topp: f32,
-        steps: u32,
-        rng_seed: u64,
-        prompt: String,
-        mode: String,
-    }
-
-    let mut args = Args {
-        checkpoint_path: argv.next().expect(USAGE_HELP),
-        tokenizer_path: String::from("tokenizer.bin"),
-        temperature: 1.0,
-        topp: 0.9,
-        steps: 256,
-        rng_seed: 0,
-        prompt: String::from(""),
-        mode: String::from("generate"),
-    };
+struct Args {
+    checkpoint_path: String,
+    tokenizer_path: Option<String>,
+    temperature: f32,
+    topp: f32,
+    steps: u32,
+    rng_seed: u64,
+    prompt: Option<String>,
+    system_prompt: Option<String>,
+    mode: String,
+    threads: usize,
+    weight_type: Option<WeightType>,
+}
 
-    loop {
-        match argv.next() {
-            Some(s) if s == "--tokenizer-path" => {
-                args.tokenizer_path = argv.next().map(String::from).expect(USAGE_HELP);
-            }
-            Some(s) if s == "--temperature" => {
-                args.temperature = argv.next().expect(USAGE_HELP).parse().unwrap();
-            }
-            Some(s) if s == "--top-p" => {
-                args.topp = argv.next().expect(USAGE_HELP).parse().unwrap();
-            }
-            Some(s) if s == "--steps" => {
-                args.steps = argv.next().expect(USAGE_HELP).parse().unwrap();
+impl Args {
+    fn parse(mut argv: impl Iterator<Item = String>) -> Result<Self> {
+        let mut args = Args {
+            checkpoint_path: argv
+                .next()
+                .ok_or_else(|| Error::Argument("missing checkpoint path".into()))?,
+            tokenizer_path: None,
+            temperature: 1.0,
+            topp: 0.9,
+            steps: 256,
+            rng_seed: 0,
+            prompt: None,
+            system_prompt: None,
+            mode: String::from("generate"),
+            threads: 0,
+            weight_type: None,
+        };
+
+        while let Some(flag) = argv.next() {
+            match flag.as_str() {
+                "--tokenizer-path" => args.tokenizer_path = Some(value(&mut argv, &flag)?),
+                "--temperature" => args.temperature = value(&mut argv, &flag)?,
+                "--top-p" => args.topp = value(&mut argv, &flag)?,
+                "--steps" => args.steps = value(&mut argv, &flag)?,
+                "--prompt" => args.prompt = Some(value(&mut argv, &flag)?),
+                "--system-prompt" => args.system_prompt = Some(value(&mut argv, &flag)?),
+                "--mode" => args.mode = value(&mut argv, &flag)?,
+                "--rng-seed" => args.rng_seed = value(&mut argv, &flag)?,
+                "--threads" => args.threads = value(&mut argv, &flag)?,
+                "--weight-type" => args.weight_type = Some(value(&mut argv, &flag)?),
+                _ => return Err(Error::Argument(format!("unknown option {flag:?}"))),
             }
-            Some(s) if s == "--prompt" => {
-                args.prompt = argv.next().expect(USAGE_HELP);
+        }
+        if args.mode != "generate" && args.mode != "chat" {
+            return Err(Error::Argument(format!(
+                "mode not supported: {:?}",
+                args.mode
+            )));
+        }
+        Ok(args)
+    }
+}
+
+/// Arguments of the `convert` command.
+struct ConvertArgs {
+    input_path: String,
+    output_path: String,
+    version: u32,
+    group_size: Option<usize>,
+}
+
+impl ConvertArgs {
+    fn parse(mut argv: impl Iterator<Item = String>) -> Result<Self> {
+        let mut path = |what: &str| {
+            argv.next()
+                .ok_or_else(|| Error::Argument(format!("missing {what} path")))
+        };
+        let mut args = ConvertArgs {
+            input_path: path("checkpoint")?,
+            output_path: path("output")?,
+            version: 0,
+            group_size: None,
+        };
+
+        while let Some(flag) = argv.next() {
+            match flag.as_str() {
+                "--version" => args.version = value(&mut argv, &flag)?,
+                "--group-size" => args.group_size = Some(value(&mut argv, &flag)?),
+                _ => return Err(Error::Argument(format!("unknown option {flag:?}"))),
             }
-            Some(s) if s == "--rng-seed" => {
-                args.rng_seed = argv.next().expect(USAGE_HELP).parse().unwrap();
+        }
+        if args.version > 2 {
+            return Err(Error::Argument(format!(
+                "version not supported: {}",
+                args.version
+            )));
+        }
+        if args.group_size.is_some() && args.version != 2 {
+            return Err(Error::Argument(
+                "--group-size only applies to --version 2".into(),
+            ));
+        }
+        Ok(args)
+    }
+}
+
+/// Arguments of the `tokenize` and `detokenize` commands.
+struct TokenizeArgs {
+    tokenizer_path: String,
+    /// the text to encode, or the ids to decode
+    inputs: Vec<String>,
+    bos: bool,
+    eos: bool,
+    special: bool,
+    json: bool,
+}
+
+impl TokenizeArgs {
+    /// Parses the arguments of `tokenize`, or of `detokenize` if `decode`.
+    fn parse(mut argv: impl Iterator<Item = String>, decode: bool) -> Result<Self> {
+        let mut args = TokenizeArgs {
+            tokenizer_path: argv
+                .next()
+                .ok_or_else(|| Error::Argument("missing tokenizer path".into()))?,
+            inputs: Vec::new(),
+            bos: false,
+            eos: false,
+            special: false,
+            json: false,
+        };
+
+        for arg in argv {
+            match arg.as_str() {
+                "--bos" if !decode => args.bos = true,
+                "--eos" if !decode => args.eos = true,
+                "--special" if !decode => args.special = true,
+                "--json" => args.json = true,
+                flag if flag.starts_with("--") => {
+                    return Err(Error::Argument(format!("unknown option {flag:?}")))
+                }
+                _ => args.inputs.push(arg),
             }
-            None => break,
-            _ => panic!("{USAGE_HELP}"),
         }
+        if decode && args.inputs.is_empty() {
+            return Err(Error::Argument("missing token ids".into()));
+        }
+        Ok(args)
     }
 
+    /// Loads the tokenizer, picking the format by the file extension.
+    fn tokenizer(&self) -> Result<Box<dyn Tokenize>> {
+        let path = &self.tokenizer_path;
+        Ok(if sentencepiece::is_model(path) {
+            Box::new(Tokenizer::from_sentencepiece(path)?)
+        } else if path.ends_with(".json") {
+            Box::new(BpeTokenizer::new(path)?)
+        } else if gguf::is_gguf(path) {
+            Box::new(Tokenizer::from_gguf(path)?)
+        } else {
+            Box::new(Tokenizer::open(path)?)
+        })
+    }
+}
+
+/// Parses the value following `flag` on the command line.
+fn value<T: FromStr>(argv: &mut impl Iterator<Item = String>, flag: &str) -> Result<T> {
+    let value = argv
+        .next()
+        .ok_or_else(|| Error::Argument(format!("missing value for {flag}")))?;
+    value
+        .parse()
+        .map_err(|_| Error::Argument(format!("invalid value for {flag}: {value:?}")))
+}
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        if let Error::Argument(_) = e {
+            usage_helper();
+        }
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut argv = env::args().skip(1).peekable();
+    if argv.next_if_eq("convert").is_some() {
+        return convert(ConvertArgs::parse(argv)?);
+    }
+    if argv.next_if_eq("tokenizer").is_some() {
+        return tokenizer_command(argv);
+    }
+    if argv.next_if_eq("tokenize").is_some() {
+        return tokenize(TokenizeArgs::parse(argv, false)?);
+    }
+    if argv.next_if_eq("detokenize").is_some() {
+        return detokenize(TokenizeArgs::parse(argv, true)?);
+    }
+    let mut args = Args::parse(argv)?;
+
+    // parameter validation/overrides
+    if args.rng_seed == 0 {
+        // a zero xorshift state never advances, so seed from the clock instead
+        args.rng_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+    }
+    if args.temperature < 0.0 {
+        args.temperature = 0.0;
+    }
+    if !(0.0..=1.0).contains(&args.topp) {
+        args.topp = 0.9;
+    }
+
+    kernels::init_thread_pool(args.threads);
+
     info!("checkpoint_path: {}", args.checkpoint_path);
 
-    let mut transformer = Transformer::new(args.checkpoint_path);
+    let mut transformer = match args.weight_type {
+        Some(weight_type) => Transformer::with_weight_type(&args.checkpoint_path, weight_type)?,
+        None => Transformer::new(&args.checkpoint_path)?,
+    };
 
     if args.steps == 0 || args.steps > transformer.config.seq_len {
         args.steps = transformer.config.seq_len;
     }
     debug!("steps: {}", args.steps);
 
-    // build the Tokenizer via the model .bin file.
-    let tokenizer = Tokenizer::new(args.tokenizer_path, transformer.config.vocab_size as usize);
+    // build the Tokenizer via the tokenizer .bin file, or from the checkpoint itself.
+    let vocab_size = transformer.config.vocab_size as usize;
+    let tokenizer_path = match &args.tokenizer_path {
+        Some(path) => Some(PathBuf::from(path)),
+        // HF checkpoints keep their tokenizer next to the weights
+        None => safetensors::find(&args.checkpoint_path).and_then(|model| {
+            ["tokenizer.model", "tokenizer.json"]
+                .into_iter()
+                .map(|name| model.with_file_name(name))
+                .find(|path| path.is_file())
+        }),
+    };
+    let tokenizer_source = tokenizer_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(&args.checkpoint_path));
+    let tokenizer: Box<dyn Tokenize> = match tokenizer_path {
+        Some(path) if sentencepiece::is_model(&path) => {
+            Box::new(Tokenizer::from_sentencepiece(path)?)
+        }
+        Some(path) if path.extension().is_some_and(|e| e == "json") => {
+            Box::new(BpeTokenizer::new(path)?)
+        }
+        Some(path) => Box::new(Tokenizer::new(path, vocab_size)?),
+        None if gguf::is_gguf(&args.checkpoint_path) => {
+            Box::new(Tokenizer::from_gguf(&args.checkpoint_path)?)
+        }
+        None => Box::new(Tokenizer::new("tokenizer.bin", vocab_size)?),
+    };
+    // prompt tokens beyond the model's vocabulary have no embedding
+    if tokenizer.vocab_size() > vocab_size {
+        return Err(Error::Header {
+            path: tokenizer_source,
+            reason: format!(
+                "the vocabulary has {} tokens, more than the {vocab_size} of the model",
+                tokenizer.vocab_size()
+            ),
+        });
+    }
     let mut sampler = Sampler::new(
         transformer.config.vocab_size,
         args.temperature,
@@ -148,15 +465,214 @@ fn main() {
     if args.mode == "generate" {
         generate(
             &mut transformer,
-            &tokenizer,
+            tokenizer.as_ref(),
+            &mut sampler,
+            args.prompt.as_deref().unwrap_or(""),
+            args.steps,
+        )?;
+    } else {
+        chat(
+            &mut transformer,
+            tokenizer.as_ref(),
             &mut sampler,
-            &args.prompt,
+            args.prompt.as_deref(),
+            args.system_prompt.as_deref(),
             args.steps,
         );
-    } else if args.mode == "chat" {
-        todo!()
+    }
+    Ok(())
+}
+
+/// Writes the checkpoint at `input_path` as a llama2.c checkpoint.
+fn convert(args: ConvertArgs) -> Result<()> {
+    let transformer = Transformer::new(&args.input_path)?;
+    let config = &transformer.config;
+    let version = match args.version {
+        0 => CheckpointVersion::Legacy,
+        1 => CheckpointVersion::V1,
+        _ => {
+            let divides = |group_size: usize| {
+                group_size != 0
+                    && (config.dim as usize).is_multiple_of(group_size)
+                    && (config.hidden_dim as usize).is_multiple_of(group_size)
+            };
+            let group_size = match args.group_size {
+                Some(group_size) => group_size,
+                // like export.py, back off from 64 until the group size fits,
+                // but not down to groups of single values
+                None => {
+                    let group_size = [64, 32, 16, 8, 4, 2]
+                        .into_iter()
+                        .find(|&group_size| divides(group_size))
+                        .ok_or_else(|| {
+                            Error::Argument(format!(
+                                "no group size from 64 down to 2 divides dim {} and hidden_dim {}, \
+                                 choose one with --group-size",
+                                config.dim, config.hidden_dim
+                            ))
+                        })?;
+                    if group_size != 64 {
+                        warn!("reducing the group size to {group_size} to fit the dimensions");
+                    }
+                    group_size
+                }
+            };
+            if !divides(group_size) {
+                return Err(Error::Argument(format!(
+                    "group size {group_size} must divide dim {} and hidden_dim {}",
+                    config.dim, config.hidden_dim
+                )));
+            }
+            CheckpointVersion::V2 { group_size }
+        }
+    };
+    info!("writing {} as {version:?}", args.output_path);
+    transformer.export(&args.output_path, version)?;
+    eprintln!("wrote {}", args.output_path);
+    Ok(())
+}
+
+/// Runs `tokenizer export`, writing the vocabulary of a SentencePiece model
+/// or a GGUF file in the tokenizer.bin layout.
+fn tokenizer_command(mut argv: impl Iterator<Item = String>) -> Result<()> {
+    match argv.next().as_deref() {
+        Some("export") => {}
+        Some(command) => {
+            return Err(Error::Argument(format!(
+                "unknown tokenizer command {command:?}"
+            )))
+        }
+        None => return Err(Error::Argument("missing tokenizer command".into())),
+    }
+    let mut path = |what: &str| {
+        argv.next()
+            .ok_or_else(|| Error::Argument(format!("missing {what} path")))
+    };
+    let (input_path, output_path) = (path("input")?, path("output")?);
+    if let Some(flag) = argv.next() {
+        return Err(Error::Argument(format!("unknown option {flag:?}")));
+    }
+
+    let tokenizer = if sentencepiece::is_model(&input_path) {
+        Tokenizer::from_sentencepiece(&input_path)?
+    } else if gguf::is_gguf(&input_path) {
+        Tokenizer::from_gguf(&input_path)?
+    } else {
+        return Err(Error::Argument(format!(
+            "{input_path:?} is neither a SentencePiece .model nor a GGUF file"
+        )));
+    };
+    tokenizer.export(&output_path)?;
+    eprintln!("wrote {output_path}, {} tokens", tokenizer.vocab_size());
+    Ok(())
+}
+
+/// The vocabulary entry of `token` as printed by `tokenize` and `detokenize`.
+fn token_json(tokenizer: &dyn Tokenize, token: u32) -> serde_json::Value {
+    json!({
+        "id": token,
+        "piece": tokenizer.piece(token),
+        "score": tokenizer.score(token),
+        "byte": tokenizer.byte(token),
+    })
+}
+
+/// Runs `tokenize`, printing the id, score and piece of every token of the
+/// text, and whether it is the byte fallback of a character not in the
+/// vocabulary.
+fn tokenize(args: TokenizeArgs) -> Result<()> {
+    let tokenizer = args.tokenizer()?;
+    let text = if args.inputs.is_empty() {
+        let mut text = io::read_to_string(io::stdin()).map_err(|source| Error::Io {
+            path: "<stdin>".into(),
+            source,
+        })?;
+        // like llama2.c's read_stdin, drop the newline ending the input
+        if text.ends_with('\n') {
+            text.pop();
+        }
+        text
+    } else {
+        args.inputs.join(" ")
+    };
+    let tokens = if args.special {
+        tokenizer.encode_with_special(&text, args.bos, args.eos)
+    } else {
+        tokenizer.encode(&text, args.bos, args.eos)
+    };
+
+    if args.json {
+        let tokens = tokens
+            .iter()
+            .map(|&token| token_json(tokenizer.as_ref(), token));
+        let output = json!({
+            "vocab_size": tokenizer.vocab_size(),
+            "max_token_len": tokenizer.max_token_len(),
+            "tokens": tokens.collect::<Vec<_>>(),
+        });
+        println!("{output:#}");
+        return Ok(());
+    }
+    // pieces are quoted and escaped, so the column is as wide as the
+    // longest of them rather than the longest token
+    let pieces = tokens
+        .iter()
+        .map(|&token| format!("{:?}", tokenizer.piece(token)))
+        .collect::<Vec<_>>();
+    let width = pieces.iter().map(|p| p.chars().count()).max().unwrap_or(0);
+    let mut stdout = io::stdout().lock();
+    for (&token, piece) in tokens.iter().zip(&pieces) {
+        // vocabularies without scores leave the column empty
+        let score = tokenizer
+            .score(token)
+            .map_or_else(String::new, |score| score.to_string());
+        let mut line = format!("{token:>7} {score:>12} {piece:<width$}");
+        if let Some(byte) = tokenizer.byte(token) {
+            line.push_str(&format!(" byte fallback 0x{byte:02X}"));
+        }
+        writeln!(stdout, "{line}").unwrap();
+    }
+    eprintln!(
+        "{} tokens for {} bytes of text, vocab_size {}, max_token_len {}",
+        tokens.len(),
+        text.len(),
+        tokenizer.vocab_size(),
+        tokenizer.max_token_len()
+    );
+    Ok(())
+}
+
+/// Runs `detokenize`, printing the text of a list of token ids.
+fn detokenize(args: TokenizeArgs) -> Result<()> {
+    let tokenizer = args.tokenizer()?;
+    let tokens = args
+        .inputs
+        .iter()
+        .map(|id| {
+            id.parse::<u32>()
+                .ok()
+                .filter(|&id| (id as usize) < tokenizer.vocab_size())
+                .ok_or_else(|| {
+                    Error::Argument(format!(
+                        "invalid token id {id:?}, the vocabulary has {} tokens",
+                        tokenizer.vocab_size()
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let text = tokenizer.decode_all(&tokens);
+
+    if args.json {
+        let tokens = tokens
+            .iter()
+            .map(|&token| token_json(tokenizer.as_ref(), token));
+        let output = json!({
+            "text": text,
+            "tokens": tokens.collect::<Vec<_>>(),
+        });
+        println!("{output:#}");
     } else {
-        println!("mode not supported");
-        usage_helper();
+        println!("{text}");
     }
+    Ok(())
 }
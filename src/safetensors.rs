@@ -0,0 +1,296 @@
+//! Reader for safetensors, the checkpoint format of HuggingFace.
+//!
+//! ```plain_text
+//! (
+//!     header_size: u64,
+//!     header: [u8; header_size],
+//!     tensor data
+//! )
+//! ```
+//!
+//! The header is a JSON object mapping tensor names to
+//! `{"dtype": "F16", "shape": [rows, cols], "data_offsets": [begin, end]}`,
+//! with the offsets relative to the start of the tensor data. An optional
+//! `__metadata__` entry maps strings to strings.
+//!
+//! Large models are split in shards, `model-00001-of-00002.safetensors` and
+//! so on, next to a `model.safetensors.index.json` whose `weight_map` tells
+//! the shard of every tensor.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use half::{bf16, f16};
+use memmap2::Mmap;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// File name of a model in one piece.
+const SINGLE_FILE: &str = "model.safetensors";
+/// File name of the index of a sharded model.
+const INDEX_FILE: &str = "model.safetensors.index.json";
+
+/// The safetensors model at `path`, if it is one: `path` may be a
+/// `.safetensors` file, an index file or a directory holding either.
+pub fn find(path: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        return [INDEX_FILE, SINGLE_FILE]
+            .into_iter()
+            .map(|name| path.join(name))
+            .find(|path| path.is_file());
+    }
+    let name = path.file_name()?.to_str()?;
+    (name.ends_with(".safetensors") || name.ends_with(".safetensors.index.json"))
+        .then(|| path.into())
+}
+
+/// Element type of a tensor.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum Dtype {
+    F32,
+    F16,
+    BF16,
+    /// any other type, not supported
+    Other(String),
+}
+
+impl From<String> for Dtype {
+    fn from(dtype: String) -> Self {
+        match dtype.as_str() {
+            "F32" => Self::F32,
+            "F16" => Self::F16,
+            "BF16" => Self::BF16,
+            _ => Self::Other(dtype),
+        }
+    }
+}
+
+impl Dtype {
+    /// Number of bytes taken by `len` values, `None` for unsupported types
+    /// or on overflow.
+    pub fn size_of(&self, len: usize) -> Option<usize> {
+        match self {
+            Self::F32 => len.checked_mul(4),
+            Self::F16 | Self::BF16 => len.checked_mul(2),
+            Self::Other(_) => None,
+        }
+    }
+
+    /// Converts `data` of this type to floats, appending them to `out`.
+    ///
+    /// `data` must hold whole values of a supported type.
+    pub fn dequantize(&self, data: &[u8], out: &mut Vec<f32>) {
+        match self {
+            Self::F32 => out.extend(
+                data.chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+            ),
+            Self::F16 => out.extend(
+                data.chunks_exact(2)
+                    .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32()),
+            ),
+            Self::BF16 => out.extend(
+                data.chunks_exact(2)
+                    .map(|b| bf16::from_le_bytes(b.try_into().unwrap()).to_f32()),
+            ),
+            Self::Other(dtype) => unreachable!("unsupported dtype {dtype}"),
+        }
+    }
+}
+
+/// The description of a tensor in the header.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TensorInfo {
+    pub dtype: Dtype,
+    /// dimensions, outermost first: a matrix of `d` rows of `n` values is `[d, n]`
+    pub shape: Vec<usize>,
+    /// byte range of the data, relative to the start of the tensor data
+    pub data_offsets: (usize, usize),
+    /// index of the shard holding the data
+    #[serde(skip)]
+    shard: usize,
+}
+
+impl TensorInfo {
+    /// Number of values in the tensor, saturating at `usize::MAX`.
+    ///
+    /// Tensors read by [`SafeTensors::open`] are checked not to overflow.
+    pub fn len(&self) -> usize {
+        self.checked_len().unwrap_or(usize::MAX)
+    }
+
+    fn checked_len(&self) -> Option<usize> {
+        self.shape
+            .iter()
+            .try_fold(1_usize, |len, &dim| len.checked_mul(dim))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// One memory mapped safetensors file.
+struct Shard {
+    mmap: Mmap,
+    /// offset of the tensor data in the file
+    data_offset: usize,
+}
+
+/// A safetensors model, in one file or in shards, with the headers parsed.
+pub struct SafeTensors {
+    shards: Vec<Shard>,
+    path: PathBuf,
+    pub metadata: HashMap<String, String>,
+    pub tensors: HashMap<String, TensorInfo>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    weight_map: HashMap<String, String>,
+}
+
+impl SafeTensors {
+    /// Opens a `.safetensors` file, or every shard listed by an index file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut model = Self {
+            shards: Vec::new(),
+            path: path.into(),
+            metadata: HashMap::new(),
+            tensors: HashMap::new(),
+        };
+        if !path.to_string_lossy().ends_with(".json") {
+            model.add_shard(path)?;
+            return Ok(model);
+        }
+
+        let index: Index = read_json(path)?;
+        let mut shards = index.weight_map.values().collect::<Vec<_>>();
+        shards.sort();
+        shards.dedup();
+        for shard in shards {
+            model.add_shard(&path.with_file_name(shard))?;
+        }
+        for (name, shard) in &index.weight_map {
+            if !model.tensors.contains_key(name) {
+                return Err(Error::header(
+                    path,
+                    format!("tensor {name} is not in {shard}"),
+                ));
+            }
+        }
+        Ok(model)
+    }
+
+    /// Maps the file at `path` and adds its tensors.
+    fn add_shard(&mut self, path: &Path) -> Result<()> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+        let truncated = |what: &str, expected: usize| Error::Truncated {
+            path: path.into(),
+            what: what.into(),
+            expected,
+            actual: mmap.len(),
+        };
+
+        let header_size = mmap.get(..8).ok_or_else(|| truncated("header size", 8))?;
+        let header_size = u64::from_le_bytes(header_size.try_into().unwrap());
+        let data_offset = usize::try_from(header_size)
+            .ok()
+            .and_then(|size| size.checked_add(8))
+            .filter(|&end| end <= mmap.len())
+            .ok_or_else(|| truncated("header", header_size.saturating_add(8) as usize))?;
+        let mut header: HashMap<String, serde_json::Value> =
+            serde_json::from_slice(&mmap[8..data_offset])
+                .map_err(|e| Error::header(path, format!("invalid JSON, {e}")))?;
+
+        if let Some(metadata) = header.remove("__metadata__") {
+            let metadata: HashMap<String, String> = serde_json::from_value(metadata)
+                .map_err(|e| Error::header(path, format!("invalid __metadata__, {e}")))?;
+            self.metadata.extend(metadata);
+        }
+        let data_size = mmap.len() - data_offset;
+        for (name, info) in header {
+            let mut info: TensorInfo = serde_json::from_value(info)
+                .map_err(|e| Error::header(path, format!("invalid tensor {name}, {e}")))?;
+            info.shard = self.shards.len();
+            if info.checked_len().is_none() {
+                return Err(Error::header(
+                    path,
+                    format!("tensor {name} has too many values, shape {:?}", info.shape),
+                ));
+            }
+            // check that the data of every supported tensor is in the file
+            if let Some(size) = info.dtype.size_of(info.len()) {
+                let (begin, end) = info.data_offsets;
+                if end.checked_sub(begin) != Some(size) {
+                    return Err(Error::header(
+                        path,
+                        format!(
+                            "tensor {name} has offsets {:?}, expected {size} bytes",
+                            info.data_offsets
+                        ),
+                    ));
+                }
+                if end > data_size {
+                    return Err(truncated(&format!("tensor {name}"), data_offset + end));
+                }
+            }
+            if self.tensors.contains_key(&name) {
+                return Err(Error::header(
+                    path,
+                    format!("tensor {name} is also in another shard"),
+                ));
+            }
+            self.tensors.insert(name, info);
+        }
+
+        self.shards.push(Shard { mmap, data_offset });
+        Ok(())
+    }
+
+    /// The path the model was opened from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.get(name)
+    }
+
+    /// The raw data of `tensor`, which must be of a supported type.
+    pub fn tensor_data(&self, tensor: &TensorInfo) -> &[u8] {
+        let (shard, offset) = self.data_location(tensor);
+        let (begin, end) = tensor.data_offsets;
+        &self.shards[shard].mmap[offset..][..end - begin]
+    }
+
+    /// Number of files the model is stored in.
+    pub(crate) fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard holding the data of `tensor` and offset of the data
+    /// in that file.
+    pub(crate) fn data_location(&self, tensor: &TensorInfo) -> (usize, usize) {
+        let shard = &self.shards[tensor.shard];
+        (tensor.shard, shard.data_offset + tensor.data_offsets.0)
+    }
+
+    /// The mappings of the shards in the order of [`SafeTensors::data_location`],
+    /// for the tensors to be read in place.
+    pub(crate) fn into_mmaps(self) -> Vec<Mmap> {
+        self.shards.into_iter().map(|shard| shard.mmap).collect()
+    }
+}
+
+/// Reads the JSON file at `path`, such as an index or a `config.json`.
+pub(crate) fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let json = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    serde_json::from_slice(&json).map_err(|e| Error::header(path, format!("invalid JSON, {e}")))
+}
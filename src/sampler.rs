@@ -1,3 +1,7 @@
+use crate::kernels::softmax;
+
+/// struct used when sorting probabilities during top-p sampling
+#[derive(Debug, Clone, Copy)]
 pub struct ProbeIndex {
     pub prob: f32,
     pub index: u32,
@@ -5,6 +9,7 @@ pub struct ProbeIndex {
 
 pub struct Sampler {
     pub vocab_size: u32,
+    /// buffer used in top-p sampling
     pub prob_index: Vec<ProbeIndex>,
     pub temperature: f32,
     pub topp: f32,
@@ -22,14 +27,126 @@ impl Sampler {
         }
     }
 
-    pub fn random_u32(mut state: u64) -> u32 {
-        state ^= state >> 12;
-        state ^= state << 25;
-        state ^= state >> 27;
-        (state.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
+    /// Samples the next token from the logits.
+    ///
+    /// The logits are modified in place: they are scaled by the temperature
+    /// and turned into probabilities unless sampling is greedy.
+    pub fn sample(&mut self, logits: &mut [f32]) -> u32 {
+        let logits = &mut logits[..self.vocab_size as usize];
+        if self.temperature == 0.0 {
+            // greedy argmax sampling: take the token with the highest probability
+            return argmax(logits);
+        }
+
+        // apply the temperature to the logits
+        for logit in logits.iter_mut() {
+            *logit /= self.temperature;
+        }
+        // apply softmax to the logits to get the probabilities for next token
+        softmax(logits, logits.len());
+        // flip a (float) coin (this is our source of entropy for sampling)
+        let coin = self.random_f32();
+        if self.topp <= 0.0 || self.topp >= 1.0 {
+            // simply sample from the predicted probability distribution
+            sample_mult(logits, coin)
+        } else {
+            // top-p (nucleus) sampling, clamping the least likely tokens to zero
+            self.sample_topp(logits, coin)
+        }
+    }
+
+    /// Top-p sampling (or "nucleus sampling") samples from the smallest set of
+    /// tokens that exceed probability `topp`. This way we never sample tokens that
+    /// have very low probabilities and are less likely to go "off the rails".
+    /// `coin` is a random number in [0, 1), usually from `random_f32()`.
+    fn sample_topp(&mut self, probabilities: &[f32], coin: f32) -> u32 {
+        let n = probabilities.len();
+        // quicksort indices in descending order of probabilities.
+        // values smaller than (1 - topp) / (n - 1) cannot be part of the result
+        // so for efficiency we crop these out as candidates before sorting.
+        let cutoff = (1.0 - self.topp) / (n - 1) as f32;
+        self.prob_index.clear();
+        self.prob_index.extend(
+            probabilities
+                .iter()
+                .enumerate()
+                .filter(|(_, &prob)| prob >= cutoff)
+                .map(|(index, &prob)| ProbeIndex {
+                    prob,
+                    index: index as u32,
+                }),
+        );
+        self.prob_index
+            .sort_unstable_by(|a, b| b.prob.total_cmp(&a.prob));
+        if self.prob_index.is_empty() {
+            // no token reaches the cutoff when the distribution is flat, when
+            // there is a single token or when the probabilities are NaN
+            return argmax(probabilities);
+        }
+
+        // truncate the list where cumulative probability exceeds topp
+        let mut cumulative_prob = 0.0;
+        // in case of rounding errors consider all elements
+        let mut last_idx = self.prob_index.len() - 1;
+        for (i, item) in self.prob_index.iter().enumerate() {
+            cumulative_prob += item.prob;
+            if cumulative_prob > self.topp {
+                // we've exceeded topp by including last_idx
+                last_idx = i;
+                break;
+            }
+        }
+
+        // sample from the truncated list
+        let r = coin * cumulative_prob;
+        let mut cdf = 0.0;
+        for item in &self.prob_index[..=last_idx] {
+            cdf += item.prob;
+            if r < cdf {
+                return item.index;
+            }
+        }
+        // in case of rounding errors
+        self.prob_index[last_idx].index
+    }
+
+    /// xorshift rng: <https://en.wikipedia.org/wiki/Xorshift#xorshift.2A>
+    pub fn random_u32(&mut self) -> u32 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        (self.rng_state.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
     }
 
-    pub fn random_f32(mut state: u64) -> f32 {
-        (Self::random_u32(state) >> 8) as f32 / 16777216.0
+    /// random float32 in [0, 1)
+    pub fn random_f32(&mut self) -> f32 {
+        (self.random_u32() >> 8) as f32 / 16777216.0
+    }
+}
+
+/// Returns the index with the highest probability.
+fn argmax(probabilities: &[f32]) -> u32 {
+    let mut max_i = 0;
+    let mut max_p = probabilities[0];
+    for (i, &p) in probabilities.iter().enumerate().skip(1) {
+        if p > max_p {
+            max_i = i;
+            max_p = p;
+        }
+    }
+    max_i as u32
+}
+
+/// Samples an index from `probabilities` (they must sum to 1!).
+/// `coin` is a random number in [0, 1), usually from `random_f32()`.
+fn sample_mult(probabilities: &[f32], coin: f32) -> u32 {
+    let mut cdf = 0.0;
+    for (i, &p) in probabilities.iter().enumerate() {
+        cdf += p;
+        if coin < cdf {
+            return i as u32;
+        }
     }
+    // in case of rounding errors
+    (probabilities.len() - 1) as u32
 }
@@ -0,0 +1,289 @@
+//! Reader for the `tokenizer.model` files of SentencePiece, a serialized
+//! `ModelProto` protobuf message.
+//!
+//! ```plain_text
+//! ModelProto {
+//!     1: repeated SentencePiece { 1: piece: string, 2: score: float, 3: type: enum },
+//!     2: TrainerSpec { 3: model_type: enum, 40: unk_id, 41: bos_id, 42: eos_id, 43: pad_id },
+//!     3: NormalizerSpec, ...
+//! }
+//! ```
+//!
+//! Only the fields above are read, everything else is skipped. Fields are
+//! tagged with `field_number << 3 | wire_type`, integers are varints and
+//! messages and strings are prefixed with their length, see
+//! <https://protobuf.dev/programming-guides/encoding/>.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::tokenizer::utok;
+
+/// Whether `path` names a SentencePiece model, by its `.model` extension.
+pub fn is_model(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|extension| extension == "model")
+}
+
+/// Role of a piece in the vocabulary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceType {
+    Normal,
+    /// the piece replacing characters that are not in the vocabulary
+    Unknown,
+    /// control symbols like `<s>`, never produced from text
+    Control,
+    /// pieces always kept whole when encoding
+    UserDefined,
+    Unused,
+    /// `<0xNN>`, the fallback for bytes of characters not in the vocabulary
+    Byte,
+    /// any other type
+    Other(u64),
+}
+
+impl PieceType {
+    fn from_u64(ty: u64) -> Self {
+        match ty {
+            1 => Self::Normal,
+            2 => Self::Unknown,
+            3 => Self::Control,
+            4 => Self::UserDefined,
+            5 => Self::Unused,
+            6 => Self::Byte,
+            ty => Self::Other(ty),
+        }
+    }
+}
+
+/// Algorithm the model was trained with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelType {
+    Unigram,
+    Bpe,
+    Word,
+    Char,
+    /// any other type
+    Other(u64),
+}
+
+impl ModelType {
+    fn from_u64(ty: u64) -> Self {
+        match ty {
+            1 => Self::Unigram,
+            2 => Self::Bpe,
+            3 => Self::Word,
+            4 => Self::Char,
+            ty => Self::Other(ty),
+        }
+    }
+}
+
+/// One entry of the vocabulary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Piece {
+    /// the text of the piece, with `▁` standing for spaces
+    pub piece: String,
+    pub score: f32,
+    pub ty: PieceType,
+}
+
+/// A parsed SentencePiece model.
+#[derive(Debug, Clone)]
+pub struct SentencePieceModel {
+    path: PathBuf,
+    /// the vocabulary, indexed by token id
+    pub pieces: Vec<Piece>,
+    pub model_type: ModelType,
+    /// ids of the special pieces, `None` when disabled
+    pub unk_id: Option<utok>,
+    pub bos_id: Option<utok>,
+    pub eos_id: Option<utok>,
+    pub pad_id: Option<utok>,
+}
+
+impl SentencePieceModel {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        Self::parse(&bytes, path)
+    }
+
+    /// Parses the `ModelProto` in `bytes`, read from `path`.
+    pub fn parse(bytes: &[u8], path: &Path) -> Result<Self> {
+        let mut model = Self {
+            path: path.into(),
+            pieces: Vec::new(),
+            // the defaults of sentencepiece_model.proto
+            model_type: ModelType::Unigram,
+            unk_id: Some(0),
+            bos_id: Some(1),
+            eos_id: Some(2),
+            pad_id: None,
+        };
+        let mut reader = Reader::new(bytes, path);
+        while let Some((field, value)) = reader.field()? {
+            match (field, value) {
+                (1, Value::Bytes(start, end)) => {
+                    let piece = model.piece(reader.message(start, end))?;
+                    model.pieces.push(piece);
+                }
+                (2, Value::Bytes(start, end)) => model.trainer_spec(reader.message(start, end))?,
+                _ => {}
+            }
+        }
+        if model.pieces.is_empty() {
+            return Err(Error::header(path, "no pieces in the model"));
+        }
+        Ok(model)
+    }
+
+    /// The path the model was read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn piece(&self, mut reader: Reader) -> Result<Piece> {
+        let mut piece = Piece {
+            piece: String::new(),
+            score: 0.0,
+            ty: PieceType::Normal,
+        };
+        while let Some((field, value)) = reader.field()? {
+            match (field, value) {
+                (1, Value::Bytes(start, end)) => {
+                    piece.piece =
+                        String::from_utf8(reader.bytes[start..end].to_vec()).map_err(|_| {
+                            Error::InvalidToken {
+                                path: self.path.clone(),
+                                index: self.pieces.len(),
+                            }
+                        })?;
+                }
+                (2, Value::Fixed32(score)) => piece.score = f32::from_bits(score),
+                (3, Value::Varint(ty)) => piece.ty = PieceType::from_u64(ty),
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+
+    fn trainer_spec(&mut self, mut reader: Reader) -> Result<()> {
+        // int32 fields, negative ids are sign extended to 64 bits
+        let id = |value| utok::try_from(value as i64).ok();
+        while let Some((field, value)) = reader.field()? {
+            match (field, value) {
+                (3, Value::Varint(ty)) => self.model_type = ModelType::from_u64(ty),
+                (40, Value::Varint(value)) => self.unk_id = id(value),
+                (41, Value::Varint(value)) => self.bos_id = id(value),
+                (42, Value::Varint(value)) => self.eos_id = id(value),
+                (43, Value::Varint(value)) => self.pad_id = id(value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of a field, by wire type.
+enum Value {
+    Varint(u64),
+    /// a 64-bit value, skipped since none of the fields read have one
+    Fixed64,
+    /// the range of a string, bytes or embedded message in the file
+    Bytes(usize, usize),
+    Fixed32(u32),
+}
+
+/// Sequential reads over the fields of one message.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// end of the message
+    end: usize,
+    path: &'a Path,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], path: &'a Path) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            end: bytes.len(),
+            path,
+        }
+    }
+
+    /// A reader over the embedded message at `start..end`.
+    fn message(&self, start: usize, end: usize) -> Self {
+        Self {
+            pos: start,
+            end,
+            ..*self
+        }
+    }
+
+    /// Takes the next `len` bytes of the message.
+    fn take(&mut self, len: usize) -> Result<usize> {
+        let start = self.pos;
+        self.pos = start
+            .checked_add(len)
+            .filter(|&end| end <= self.end)
+            .ok_or_else(|| Error::Truncated {
+                path: self.path.into(),
+                what: "protobuf message".into(),
+                expected: start.saturating_add(len),
+                actual: self.end,
+            })?;
+        Ok(start)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes[self.take(1)?];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::header(
+            self.path,
+            format!("varint at byte {} is too long", self.pos),
+        ))
+    }
+
+    /// The next field number and value, `None` at the end of the message.
+    fn field(&mut self) -> Result<Option<(u64, Value)>> {
+        if self.pos == self.end {
+            return Ok(None);
+        }
+        let tag = self.varint()?;
+        let value = match tag & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed64
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).unwrap_or(usize::MAX);
+                let start = self.take(len)?;
+                Value::Bytes(start, self.pos)
+            }
+            5 => {
+                let start = self.take(4)?;
+                Value::Fixed32(u32::from_le_bytes(
+                    self.bytes[start..][..4].try_into().unwrap(),
+                ))
+            }
+            ty => {
+                return Err(Error::header(
+                    self.path,
+                    format!("unsupported wire type {ty} at byte {}", self.pos),
+                ))
+            }
+        };
+        Ok(Some((tag >> 3, value)))
+    }
+}
@@ -1,38 +1,314 @@
 /// !ref: https://github.com/YdrMaster/llama2.rs/blob/main/src/tokenizer.rs
-use memmap2::Mmap;
-use std::{fs::File, path::Path};
+use crate::error::{Error, Result};
+use crate::gguf::{Gguf, Value};
+use crate::sentencepiece::{ModelType, PieceType, SentencePieceModel};
+use log::warn;
+use memmap2::{Mmap, MmapMut};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::{fs::File, mem::size_of, path::Path};
+
+pub use bpe::BpeTokenizer;
+pub use stream::StreamDecoder;
+
+mod bpe;
+mod stream;
 
 /// `utok` for token id.
 #[allow(non_camel_case_types)]
-pub(super) type utok = u32;
+pub type utok = u32;
+
+/// llama2.c 的 tokenizer.bin 中未知字符、BOS 和 EOS 的序号，其他格式的词表从文件中读取。
+pub(super) const UNKNOWN: utok = 0;
+pub const BOS: utok = 1;
+pub const EOS: utok = 2;
+
+/// 各种 tokenizer 的共同接口，生成文本时只依赖这些方法。
+pub trait Tokenize {
+    /// 词表的大小。
+    fn vocab_size(&self) -> usize;
+
+    /// 序列开始的 token，没有时为 `None`。
+    fn bos(&self) -> Option<utok>;
+
+    /// 序列结束的 token，没有时为 `None`。
+    fn eos(&self) -> Option<utok>;
 
-pub(super) const _UNKNOWN: utok = 0;
-pub(super) const BOS: utok = 1;
-pub(super) const EOS: utok = 2;
+    /// 把 `text` 编码为 token 序列，可选地在首尾加上 BOS 和 EOS。
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok>;
+
+    /// 与 `encode` 相同，但 `text` 中特殊 token 的文本直接编码为特殊 token，
+    /// 例如 `<s>` 编码为 BOS。只应该用于可信的文本，比如对话模板。
+    fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Vec<utok>;
+
+    /// 解码 `token` 之后的 `next`，得到它的原始字节。
+    ///
+    /// 一个字符的 UTF-8 编码可能分在几个 token 中，需要拼接成文本时使用 [`StreamDecoder`]。
+    fn decode(&self, token: utok, next: utok) -> &[u8];
+
+    /// 解码文本开头的 `token`，得到它的原始字节，与 BOS 之后的 token 一样处理。
+    fn decode_first(&self, token: utok) -> &[u8];
+
+    /// 词表中 `token` 的文本，用于查看词表，解码使用 [`Tokenize::decode`]。
+    fn piece(&self, token: utok) -> &str;
+
+    /// 词表中 `token` 的分数，词表没有分数时为 `None`。
+    fn score(&self, _token: utok) -> Option<f32> {
+        None
+    }
+
+    /// `token` 是字节回退的 `<0xNN>` token 时返回这个字节。
+    fn byte(&self, _token: utok) -> Option<u8> {
+        None
+    }
+
+    /// 词表中最长的文本的字节数。
+    fn max_token_len(&self) -> usize {
+        (0..self.vocab_size() as utok)
+            .map(|token| self.piece(token).len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// 把整个 token 序列解码为文本，BOS 和 EOS 不产生文本。
+    fn decode_all(&self, tokens: &[utok]) -> String {
+        let mut decoder = StreamDecoder::new(self);
+        let mut text = tokens
+            .iter()
+            .map(|&token| decoder.push(token))
+            .collect::<String>();
+        text.push_str(&decoder.finish());
+        text
+    }
+}
 
 /// Tokenizer 的功能是建立 token 字符串和一个序号之间的关系。
-pub(super) struct Tokenizer {
+pub struct Tokenizer {
     /// tokenizer 文件的内存映射。
     mmap: Mmap,
     /// 保存每个序号对应的对象在文件中的偏移，用于从序号查询 token 字符串。
     words_offset: Vec<usize>,
     /// 保存根据 token 字符串字典序排序的序号，用于从 token 字符串查询序号。
     sorted_indices: Vec<utok>,
+    /// 每个序号的文本在 `find_token` 中对应的序号，词表中没有重复文本时就是它自己。
+    canonical: Vec<utok>,
+    /// 两个 token 的文本拼接后对应的 token 及其分数，键为 `canonical` 序号。
+    merges: HashMap<(utok, utok), (utok, f32)>,
     byte_pieces: [u8; 256],
+    /// 每个字节对应的 `<0xNN>` token，词表中没有时为 `None`。
+    byte_tokens: [Option<utok>; 256],
+    /// 没有对应 token 的字节编码为的 token，为 `None` 时丢弃这样的字节。
+    unk: Option<utok>,
+    /// 序列开始和结束的 token。
+    bos: Option<utok>,
+    eos: Option<utok>,
+    /// 特殊 token 的文本和序号，长的在前，`encode_with_special` 在文本中匹配它们。
+    special: Vec<(String, utok)>,
 }
 
 impl Tokenizer {
-    pub fn new(tokenizer: impl AsRef<Path>, vocab_size: usize) -> Self {
-        let mmap = unsafe { Mmap::map(&File::open(tokenizer).unwrap()) }.unwrap();
+    pub fn new(tokenizer: impl AsRef<Path>, vocab_size: usize) -> Result<Self> {
+        let path = tokenizer.as_ref();
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+        Self::from_mmap(mmap, vocab_size, path)
+    }
+
+    /// 读取 tokenizer.bin 中的全部 token，词表大小由文件长度决定。
+    pub fn open(tokenizer: impl AsRef<Path>) -> Result<Self> {
+        let path = tokenizer.as_ref();
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+        // 最后一个不完整的 token 也计入，由 `from_mmap` 报告截断
+        let mut vocab_size = 0;
+        let mut offset = size_of::<u32>();
+        while offset < mmap.len() {
+            vocab_size += 1;
+            offset = file::item_len(&mmap, offset).map_or(usize::MAX, |len| offset + len);
+        }
+        Self::from_mmap(mmap, vocab_size, path)
+    }
+
+    /// 从 GGUF 模型文件的 `tokenizer.ggml.*` 元数据构建 tokenizer，
+    /// 词表在内存中转换为 tokenizer.bin 的结构。
+    pub fn from_gguf(path: impl AsRef<Path>) -> Result<Self> {
+        let gguf = Gguf::open(path)?;
+        let path = gguf.path();
+        let model = gguf
+            .get("tokenizer.ggml.model")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if model != "llama" {
+            return Err(Error::unsupported(
+                path,
+                format!("tokenizer model {model:?}, expected \"llama\""),
+            ));
+        }
+        let tokens = gguf
+            .get("tokenizer.ggml.tokens")
+            .and_then(Value::as_array)
+            .ok_or_else(|| Error::header(path, "missing tokenizer.ggml.tokens"))?;
+        let scores = gguf.get("tokenizer.ggml.scores").and_then(Value::as_array);
+        let types = gguf
+            .get("tokenizer.ggml.token_type")
+            .and_then(Value::as_array);
+        let id = |key: &str, default: utok| match gguf.get(key).and_then(Value::as_u64) {
+            Some(id) => utok::try_from(id).ok(),
+            None => Some(default),
+        };
+        let unk = id("tokenizer.ggml.unknown_token_id", UNKNOWN);
+        let bos = id("tokenizer.ggml.bos_token_id", BOS);
+        let eos = id("tokenizer.ggml.eos_token_id", EOS);
+
+        // SentencePiece 用 ▁ 表示空格，tokenizer.bin 中是空格
+        let pieces = tokens
+            .iter()
+            .enumerate()
+            .map(|(index, token)| {
+                let piece = token.as_str().ok_or_else(|| Error::InvalidToken {
+                    path: path.into(),
+                    index,
+                })?;
+                let score = scores
+                    .and_then(|scores| scores.get(index))
+                    .and_then(Value::as_f32)
+                    .unwrap_or(0.0);
+                Ok((piece.replace('\u{2581}', " "), score))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut tokenizer = Self::from_pieces(&pieces, path)?;
+        tokenizer.unk = unk.filter(|&i| (i as usize) < pieces.len());
+        (tokenizer.bos, tokenizer.eos) = (bos, eos);
+        // llama.cpp 的 token 类型：2 未知，3 控制，4 用户定义
+        tokenizer.special.clear();
+        for (index, (piece, _)) in pieces.iter().enumerate() {
+            let ty = types
+                .and_then(|types| types.get(index))
+                .and_then(Value::as_u64);
+            if matches!(ty, Some(2..=4)) {
+                tokenizer.add_special_token(piece.as_str(), index as utok);
+            }
+        }
+        Ok(tokenizer)
+    }
+
+    /// 从 SentencePiece 的 `tokenizer.model` 构建 tokenizer，
+    /// 与 llama2.c 的 `tokenizer.py` 导出的 tokenizer.bin 完全一致。
+    pub fn from_sentencepiece(path: impl AsRef<Path>) -> Result<Self> {
+        let model = SentencePieceModel::open(path)?;
+        let path = model.path();
+        if model.model_type != ModelType::Bpe {
+            warn!(
+                "{}: {:?} model, encoding merges pieces like BPE",
+                path.display(),
+                model.model_type
+            );
+        }
+        let pieces = model
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(index, piece)| {
+                let index = Some(index as utok);
+                // tokenizer.py 把 BOS 和 EOS 写成独占一行的 <s> 和 </s>
+                let text = if index == model.bos_id {
+                    "\n<s>\n".into()
+                } else if index == model.eos_id {
+                    "\n</s>\n".into()
+                } else {
+                    piece.piece.replace('\u{2581}', " ")
+                };
+                (text, piece.score)
+            })
+            .collect::<Vec<_>>();
+        let mut tokenizer = Self::from_pieces(&pieces, path)?;
+        tokenizer.unk = model.unk_id.filter(|&i| (i as usize) < pieces.len());
+        (tokenizer.bos, tokenizer.eos) = (model.bos_id, model.eos_id);
+        tokenizer.special.clear();
+        for (index, piece) in model.pieces.iter().enumerate() {
+            if matches!(
+                piece.ty,
+                PieceType::Unknown | PieceType::Control | PieceType::UserDefined
+            ) {
+                let text = piece.piece.replace('\u{2581}', " ");
+                tokenizer.add_special_token(text, index as utok);
+            }
+        }
+        Ok(tokenizer)
+    }
+
+    /// 把 `(文本, 分数)` 形式的词表在内存中转换为 tokenizer.bin 的结构，
+    /// `path` 用于报告错误。
+    fn from_pieces(pieces: &[(String, f32)], path: &Path) -> Result<Self> {
+        let max_token_len = pieces.iter().map(|(piece, _)| piece.len()).max();
+        let mut bytes = (max_token_len.unwrap_or(0) as u32).to_le_bytes().to_vec();
+        for (piece, score) in pieces {
+            bytes.extend(score.to_le_bytes());
+            bytes.extend((piece.len() as u32).to_le_bytes());
+            bytes.extend(piece.as_bytes());
+        }
+
+        let mut mmap = MmapMut::map_anon(bytes.len()).map_err(|e| Error::io(path, e))?;
+        mmap.copy_from_slice(&bytes);
+        let mmap = mmap.make_read_only().map_err(|e| Error::io(path, e))?;
+        Self::from_mmap(mmap, pieces.len(), path)
+    }
+
+    /// 把词表以 tokenizer.bin 的结构写入 `path`。
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        for (key, id, expected) in [("bos", self.bos, BOS), ("eos", self.eos, EOS)] {
+            if id != Some(expected) {
+                warn!("{key} is {id:?}, but llama2.c always uses {expected}");
+            }
+        }
+        let len = self
+            .words_offset
+            .last()
+            .map_or(size_of::<u32>(), |&offset| {
+                offset + file::item_len(&self.mmap, offset).unwrap()
+            });
+        std::fs::write(path, &self.mmap[..len]).map_err(|e| Error::io(path, e))
+    }
+
+    /// 词表的大小。
+    #[inline]
+    pub fn vocab_size(&self) -> usize {
+        self.words_offset.len()
+    }
+
+    /// 解析映射在 `mmap` 中的 tokenizer.bin，`path` 用于报告错误。
+    fn from_mmap(mmap: Mmap, vocab_size: usize, path: &Path) -> Result<Self> {
+        let truncated = |what: String, expected: usize| Error::Truncated {
+            path: path.into(),
+            what,
+            expected,
+            actual: mmap.len(),
+        };
+        if mmap.len() < size_of::<u32>() {
+            return Err(truncated("max_token_len".into(), size_of::<u32>()));
+        }
 
         let mut words_offset = Vec::<usize>::with_capacity(vocab_size);
         let mut sorted_indices = Vec::<utok>::with_capacity(vocab_size);
         {
-            let mut offset = std::mem::size_of::<u32>();
+            let mut offset = size_of::<u32>();
             for index in 0..vocab_size as utok {
+                let len = file::item_len(&mmap, offset).ok_or_else(|| {
+                    truncated(format!("token {index}"), offset + file::HEADER_LEN)
+                })?;
+                let text = mmap
+                    .get(offset + file::HEADER_LEN..offset + len)
+                    .ok_or_else(|| truncated(format!("token {index}"), offset + len))?;
+                if std::str::from_utf8(text).is_err() {
+                    return Err(Error::InvalidToken {
+                        path: path.into(),
+                        index: index as usize,
+                    });
+                }
                 words_offset.push(offset);
                 sorted_indices.push(index);
-                offset += file::item_len(&mmap, offset);
+                offset += len;
             }
         }
         sorted_indices.sort_by_key(|&index| file::map(&mmap, words_offset[index as usize]).0);
@@ -41,86 +317,216 @@ impl Tokenizer {
             mmap,
             words_offset,
             sorted_indices,
+            canonical: Vec::new(),
+            merges: HashMap::new(),
             byte_pieces: [0; 256],
+            byte_tokens: [None; 256],
+            unk: Some(UNKNOWN).filter(|&i| i < vocab_size as utok),
+            bos: Some(BOS),
+            eos: Some(EOS),
+            special: Vec::new(),
         };
         for i in 0..=255u8 {
             ans.byte_pieces[i as usize] = i;
         }
-        ans
+        // 字节 token 不一定在 3..259，同一个字节有多个 token 时用第一个
+        for index in 0..vocab_size as utok {
+            if let Some(byte) = ans.byte(index) {
+                ans.byte_tokens[byte as usize].get_or_insert(index);
+            }
+        }
+        // tokenizer.bin 没有 token 的类型，只有 llama2.c 的三个特殊 token，
+        // tokenizer.py 把 BOS 和 EOS 写成独占一行
+        for index in [UNKNOWN, BOS, EOS]
+            .into_iter()
+            .filter(|&i| i < vocab_size as utok)
+        {
+            let text = ans.map_str(index).trim_matches('\n').to_string();
+            ans.add_special_token(text, index);
+        }
+        ans.canonical = (0..vocab_size as utok)
+            .map(|index| ans.find_token(ans.map_str(index)).unwrap())
+            .collect();
+        // 把每个 token 的文本在每个字符边界处切成两半，两半都在词表中时就是一次合并
+        for index in 0..vocab_size as utok {
+            let (piece, score) = file::map(&ans.mmap, ans.words_offset[index as usize]);
+            let merged = ans.canonical[index as usize];
+            if merged != index {
+                continue;
+            }
+            for mid in (0..=piece.len()).filter(|&mid| piece.is_char_boundary(mid)) {
+                let (Some(left), Some(right)) =
+                    (ans.find_token(&piece[..mid]), ans.find_token(&piece[mid..]))
+                else {
+                    continue;
+                };
+                ans.merges.insert((left, right), (merged, score));
+            }
+        }
+        Ok(ans)
+    }
+
+    /// 设置序列开始的 token，`None` 时不加 BOS。
+    pub fn set_bos(&mut self, bos: Option<utok>) {
+        self.bos = bos;
+    }
+
+    /// 设置序列结束的 token，`None` 时不加 EOS，生成也不会因为 EOS 停止。
+    pub fn set_eos(&mut self, eos: Option<utok>) {
+        self.eos = eos;
+    }
+
+    /// 登记一个特殊 token，`encode_with_special` 把文本中的 `text` 编码为 `id`，
+    /// 替换之前登记的同样的文本。
+    pub fn add_special_token(&mut self, text: impl Into<String>, id: utok) {
+        let text = text.into();
+        self.special.retain(|(special, _)| *special != text);
+        self.special.push((text, id));
+        self.special
+            .sort_by_key(|(text, _)| std::cmp::Reverse(text.len()));
+    }
+
+    /// 登记的特殊 token 的文本和序号，长的在前。
+    pub fn special_tokens(&self) -> &[(String, utok)] {
+        &self.special
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn max_token_len(&self) -> usize {
-        (unsafe { *self.mmap.as_ptr().cast::<u32>() }) as _
+        // 文件至少有 4 个字节，已在 `from_mmap` 中检查
+        u32::from_le_bytes(self.mmap[..4].try_into().unwrap()) as _
     }
 
     pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
-        #[inline(always)]
-        const fn byte_index(b: u8) -> utok {
-            b as utok + 3
-        }
-
         let mut tokens = Vec::<utok>::with_capacity(text.len() + 2);
-        if bos {
-            tokens.push(BOS);
+        if let Some(bos) = self.bos.filter(|_| bos) {
+            tokens.push(bos);
         }
         if !text.is_empty() {
-            tokens.push(self.find_token(" ").unwrap())
+            tokens.extend(self.find_token(" "));
         }
+        self.push_chars(text, &mut tokens);
 
-        text.chars().map(|c| c.to_string()).for_each(|c| {
-            if let Some(index) = self.find_token(&c) {
-                tokens.extend([index]);
-            } else {
-                tokens.extend(c.bytes().map(byte_index));
-            }
-        });
+        self.merge(&mut tokens);
+
+        if let Some(bos) = self.bos.filter(|_| bos) {
+            assert_eq!(tokens[0], bos);
+        }
+        if let Some(eos) = self.eos.filter(|_| eos) {
+            tokens.push(eos);
+        }
+        tokens
+    }
 
-        loop {
-            let mut best_score = std::f32::NEG_INFINITY;
-            let mut replacement = None;
-            for (i, pair) in tokens.windows(2).enumerate() {
-                let pair = format!("{}{}", self.map_str(pair[0]), self.map_str(pair[1]));
-                if let Some(index) = self.find_token(&pair) {
-                    let (_, score) = file::map(&self.mmap, self.words_offset[index as usize]);
-                    if score > best_score {
-                        best_score = score;
-                        replacement = Some((i, index));
-                    }
+    /// 见 [`Tokenize::encode_with_special`]，只有文本开头的普通文本前面加上空格。
+    pub fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        let mut tokens = Vec::<utok>::with_capacity(text.len() + 2);
+        if let Some(bos) = self.bos.filter(|_| bos) {
+            tokens.push(bos);
+        }
+        let mut rest = text;
+        while !rest.is_empty() {
+            let at_start = rest.len() == text.len();
+            let (plain, special) = match find_special(rest, &self.special) {
+                Some((pos, content, id)) => {
+                    let plain = &rest[..pos];
+                    rest = &rest[pos + content.len()..];
+                    (plain, Some(id))
                 }
-            }
-            match replacement {
-                Some((i, j)) => {
-                    tokens[i] = j;
-                    tokens.remove(i + 1);
+                None => (std::mem::take(&mut rest), None),
+            };
+            if !plain.is_empty() {
+                let mut segment = Vec::with_capacity(plain.len() + 1);
+                if at_start {
+                    segment.extend(self.find_token(" "));
                 }
-                None => break,
+                self.push_chars(plain, &mut segment);
+                self.merge(&mut segment);
+                tokens.extend(segment);
             }
+            tokens.extend(special);
         }
-
-        if bos {
-            assert_eq!(tokens[0], BOS);
-        }
-        if eos {
-            tokens.push(EOS);
+        if let Some(eos) = self.eos.filter(|_| eos) {
+            tokens.push(eos);
         }
         tokens
     }
 
-    pub fn decode(&self, token: utok, next: utok) -> &str {
-        let piece = self.map_str(next);
-        if let Some(byte) = piece.strip_prefix("<0x").and_then(|s| s.strip_suffix('>')) {
-            let byte = u8::from_str_radix(byte, 16).unwrap();
-            let byte = &self.byte_pieces[byte as usize..][..1];
-            unsafe { std::str::from_utf8_unchecked(byte) }
-        } else if token == BOS && piece.starts_with(' ') {
-            &piece[1..]
+    /// 把 `text` 的每个字符编码为一个 token，不在词表中的字符编码为 UTF-8 的每个字节，
+    /// 词表中也没有这个字节时编码为未知 token。
+    fn push_chars(&self, text: &str, tokens: &mut Vec<utok>) {
+        let mut buf = [0; 4];
+        for c in text.chars() {
+            let c = c.encode_utf8(&mut buf);
+            if let Some(index) = self.find_token(c) {
+                tokens.push(index);
+            } else {
+                tokens.extend(
+                    c.bytes()
+                        .filter_map(|b| self.byte_tokens[b as usize].or(self.unk)),
+                );
+            }
+        }
+    }
+
+    /// 反复合并分数最高的一对相邻 token，分数相同时合并最左边的一对，
+    /// 直到没有可以合并的 token。
+    fn merge(&self, tokens: &mut Vec<utok>) {
+        merge_pairs(tokens, |left, right| {
+            let pair = (
+                self.canonical[left as usize],
+                self.canonical[right as usize],
+            );
+            let &(merged, score) = self.merges.get(&pair)?;
+            // 与逐对比较 `score > best_score` 一致：-inf 和 NaN 从不合并，-0.0 等于 0.0
+            (score > f32::NEG_INFINITY).then_some((Score(score + 0.0), merged))
+        });
+    }
+
+    /// `<0xNN>` 解码为一个字节，不一定是完整的 UTF-8 字符；BOS 之后的 token 去掉开头的空格。
+    /// 模型的词表可能比 tokenizer 大，超出词表的 token 解码为空。
+    pub fn decode(&self, token: utok, next: utok) -> &[u8] {
+        self.decode_piece(next, Some(token) == self.bos)
+    }
+
+    /// 解码文本开头的 `token`，和 BOS 之后一样去掉开头的空格，没有 BOS 时也是如此。
+    pub fn decode_first(&self, token: utok) -> &[u8] {
+        self.decode_piece(token, true)
+    }
+
+    /// `token` 的原始字节，`strip` 时去掉开头的空格。
+    fn decode_piece(&self, token: utok, strip: bool) -> &[u8] {
+        if token as usize >= self.vocab_size() {
+            return &[];
+        }
+        let piece = self.map_str(token);
+        if let Some(byte) = self.byte(token) {
+            &self.byte_pieces[byte as usize..][..1]
+        } else if strip && piece.starts_with(' ') {
+            &piece.as_bytes()[1..]
         } else {
-            piece
+            piece.as_bytes()
         }
     }
 
+    /// 词表中 `token` 的文本，空格没有被替换为 `▁`。
+    #[inline]
+    pub fn piece(&self, token: utok) -> &str {
+        self.map_str(token)
+    }
+
+    /// 词表中 `token` 的分数，合并时分数高的优先。
+    #[inline]
+    pub fn score(&self, token: utok) -> f32 {
+        file::map(&self.mmap, self.words_offset[token as usize]).1
+    }
+
+    /// `token` 是 `<0xNN>` 形式的字节时返回这个字节。
+    pub fn byte(&self, token: utok) -> Option<u8> {
+        let byte = self.map_str(token).strip_prefix("<0x")?.strip_suffix('>')?;
+        u8::from_str_radix(byte, 16).ok()
+    }
+
     #[inline]
     fn find_token(&self, token: &str) -> Option<utok> {
         self.sorted_indices
@@ -135,6 +541,170 @@ impl Tokenizer {
     }
 }
 
+/// 找到 `text` 中最靠前的特殊 token，同一位置取最长的，返回位置、文本和序号。
+/// `special` 按长度从长到短排列。
+fn find_special<'a>(text: &str, special: &'a [(String, utok)]) -> Option<(usize, &'a str, utok)> {
+    special
+        .iter()
+        .filter(|(content, _)| !content.is_empty())
+        .filter_map(|(content, id)| Some((text.find(content.as_str())?, content.as_str(), *id)))
+        .min_by_key(|&(pos, _, _)| pos)
+}
+
+/// 反复合并 `pair` 给出的优先级最高的一对相邻 token，优先级相同时合并最左边的一对，
+/// 直到没有可以合并的 token。`pair` 返回一对 token 合并的优先级和结果，不能合并时为 `None`。
+///
+/// token 串成双向链表，候选的合并放在堆中，失效的候选在弹出时丢弃，
+/// 总共 O(n log n)。
+fn merge_pairs<P: Ord>(tokens: &mut Vec<utok>, pair: impl Fn(utok, utok) -> Option<(P, utok)>) {
+    const NONE: usize = usize::MAX;
+    let n = tokens.len();
+    let mut prev = (0..n).map(|i| i.wrapping_sub(1)).collect::<Vec<_>>();
+    let mut next = (1..=n)
+        .map(|i| if i < n { i } else { NONE })
+        .collect::<Vec<_>>();
+    let mut removed = vec![false; n];
+    let mut heap = BinaryHeap::new();
+    let candidate = |tokens: &[utok], left: usize, right: usize| {
+        let (priority, merged) = pair(tokens[left], tokens[right])?;
+        Some(Candidate {
+            priority,
+            left,
+            right,
+            pair: (tokens[left], tokens[right]),
+            merged,
+        })
+    };
+    heap.extend((1..n).filter_map(|right| candidate(tokens, right - 1, right)));
+
+    while let Some(c) = heap.pop() {
+        // 两边的 token 已经变了，或者不再相邻
+        if removed[c.left] || next[c.left] != c.right || (tokens[c.left], tokens[c.right]) != c.pair
+        {
+            continue;
+        }
+        tokens[c.left] = c.merged;
+        removed[c.right] = true;
+        next[c.left] = next[c.right];
+        if next[c.left] != NONE {
+            prev[next[c.left]] = c.left;
+        }
+        if prev[c.left] != NONE {
+            heap.extend(candidate(tokens, prev[c.left], c.left));
+        }
+        if next[c.left] != NONE {
+            heap.extend(candidate(tokens, c.left, next[c.left]));
+        }
+    }
+
+    let mut i = 0;
+    tokens.retain(|_| {
+        i += 1;
+        !removed[i - 1]
+    });
+}
+
+/// 按 `total_cmp` 排序的分数，用作合并的优先级。
+struct Score(f32);
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+/// 一次候选的合并，优先级高的优先，优先级相同时靠左的优先。
+struct Candidate<P> {
+    priority: P,
+    left: usize,
+    right: usize,
+    /// 放入堆时两边的 token，用于判断候选是否失效
+    pair: (utok, utok),
+    merged: utok,
+}
+
+impl<P: Ord> Ord for Candidate<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.left.cmp(&self.left))
+    }
+}
+
+impl<P: Ord> PartialOrd for Candidate<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: Ord> PartialEq for Candidate<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<P: Ord> Eq for Candidate<P> {}
+
+impl Tokenize for Tokenizer {
+    fn vocab_size(&self) -> usize {
+        self.vocab_size()
+    }
+
+    fn bos(&self) -> Option<utok> {
+        self.bos
+    }
+
+    fn eos(&self) -> Option<utok> {
+        self.eos
+    }
+
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        self.encode(text, bos, eos)
+    }
+
+    fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        self.encode_with_special(text, bos, eos)
+    }
+
+    fn decode(&self, token: utok, next: utok) -> &[u8] {
+        self.decode(token, next)
+    }
+
+    fn decode_first(&self, token: utok) -> &[u8] {
+        self.decode_first(token)
+    }
+
+    fn piece(&self, token: utok) -> &str {
+        self.piece(token)
+    }
+
+    fn score(&self, token: utok) -> Option<f32> {
+        Some(self.score(token))
+    }
+
+    fn byte(&self, token: utok) -> Option<u8> {
+        self.byte(token)
+    }
+
+    fn max_token_len(&self) -> usize {
+        self.max_token_len()
+    }
+}
+
 mod file {
     //! 文件结构：
     //!
@@ -157,15 +727,20 @@ mod file {
         len: u32,
     }
 
-    /// 获取 `offset` 处对象的长度。
+    /// 对象头的长度。
+    pub const HEADER_LEN: usize = std::mem::size_of::<TokenHeader>();
+
+    /// 获取 `offset` 处对象的长度，对象头越界时返回 `None`。
     #[inline]
-    pub fn item_len(mmap: &Mmap, offset: usize) -> usize {
-        let slice = &mmap.as_ref()[offset..];
+    pub fn item_len(mmap: &Mmap, offset: usize) -> Option<usize> {
+        let slice = mmap.get(offset..).filter(|s| s.len() >= HEADER_LEN)?;
         let header = unsafe { slice.as_ptr().cast::<TokenHeader>().read_unaligned() };
-        std::mem::size_of::<TokenHeader>() + header.len as usize
+        Some(HEADER_LEN + header.len as usize)
     }
 
     /// 获取 `offset` 处对象的内容。
+    ///
+    /// 对象的文本已在 `Tokenizer::from_mmap` 中验证为 UTF-8。
     #[inline]
     pub fn map(mmap: &Mmap, offset: usize) -> (&str, f32) {
         let slice = &mmap.as_ref()[offset..];
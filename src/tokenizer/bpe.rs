@@ -0,0 +1,512 @@
+//! HuggingFace `tokenizer.json` 中的字节级 BPE 模型，GPT-2、Llama 3 等模型使用。
+//!
+//! 编码时文本先在 `added_tokens` 处切开，其余部分由 pre-tokenizer 切成词。
+//! 每个词的 UTF-8 字节按 GPT-2 的 `bytes_to_unicode` 映射为可见字符，
+//! 再按 `merges` 的顺序合并相邻的符号，排在前面的先合并。
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use fancy_regex::Regex;
+use log::warn;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{find_special, merge_pairs, utok, Tokenize};
+use crate::error::{Error, Result};
+use crate::safetensors::read_json;
+
+/// GPT-2 的切词正则，`ByteLevel` 的 `use_regex` 为真时使用。
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+#[derive(Deserialize)]
+struct TokenizerJson {
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    normalizer: Option<Value>,
+    pre_tokenizer: Option<Value>,
+    post_processor: Option<Value>,
+    model: Model,
+}
+
+#[derive(Deserialize)]
+struct AddedToken {
+    id: utok,
+    content: String,
+    #[serde(default)]
+    special: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum PreTokenizer {
+    ByteLevel {
+        #[serde(default)]
+        add_prefix_space: bool,
+        #[serde(default = "use_regex")]
+        use_regex: bool,
+    },
+    Split {
+        pattern: Pattern,
+        behavior: String,
+        #[serde(default)]
+        invert: bool,
+    },
+    Sequence {
+        pretokenizers: Vec<PreTokenizer>,
+    },
+}
+
+/// `ByteLevel` 的 `use_regex` 默认为真。
+fn use_regex() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+enum Pattern {
+    Regex(String),
+    String(String),
+}
+
+#[derive(Deserialize)]
+struct Model {
+    #[serde(rename = "type")]
+    ty: Option<String>,
+    vocab: HashMap<String, utok>,
+    #[serde(default)]
+    merges: Vec<Merge>,
+    /// 整个词都在词表中时不再合并
+    #[serde(default)]
+    ignore_merges: bool,
+    unk_token: Option<String>,
+}
+
+/// 一条合并规则，旧版本写成 `"a b"`，新版本写成 `["a", "b"]`。
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Merge {
+    Joined(String),
+    Pair(String, String),
+}
+
+/// `tokenizer_config.json` 中的 BOS 和 EOS。
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    Token { content: String },
+}
+
+impl SpecialToken {
+    fn content(&self) -> &str {
+        match self {
+            Self::Content(content) | Self::Token { content } => content,
+        }
+    }
+}
+
+/// pre-tokenizer 的一步，依次作用于每个词。
+enum Step {
+    /// 在正则的每个匹配处切开，匹配和匹配之间的部分都成为词
+    Split(Regex),
+    /// 给不以空格开头的词加上空格
+    PrefixSpace,
+}
+
+/// 字节级 BPE tokenizer。
+pub struct BpeTokenizer {
+    path: PathBuf,
+    /// 字节映射后的 token 文本到序号
+    vocab: HashMap<String, utok>,
+    /// 相邻两个 token 合并的排名和结果
+    merges: HashMap<(utok, utok), (usize, utok)>,
+    /// 每个字节映射后的单字符 token
+    byte_tokens: [Option<utok>; 256],
+    /// 每个序号解码后的字节
+    pieces: Vec<Vec<u8>>,
+    /// 每个序号在词表中的文本，字节映射为可见字符
+    texts: Vec<String>,
+    /// pre-tokenizer 的步骤
+    steps: Vec<Step>,
+    /// 在文本中直接匹配的非特殊 added token，长的在前
+    added_tokens: Vec<(String, utok)>,
+    /// 所有 added token，包括特殊的，长的在前，`encode_with_special` 匹配它们
+    special_tokens: Vec<(String, utok)>,
+    ignore_merges: bool,
+    unk: Option<utok>,
+    bos: Option<utok>,
+    eos: Option<utok>,
+}
+
+impl BpeTokenizer {
+    /// 读取 `tokenizer.json`，BOS 和 EOS 来自旁边的 `tokenizer_config.json`，
+    /// 没有时 BOS 来自 post-processor 的模板。
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json: TokenizerJson = read_json(path)?;
+        let unsupported = |reason: String| Error::unsupported(path, reason);
+        if let Some(ty) = json.model.ty.as_deref().filter(|&ty| ty != "BPE") {
+            return Err(unsupported(format!(
+                "tokenizer model {ty:?}, expected \"BPE\""
+            )));
+        }
+        if let Some(normalizer) = json.normalizer.as_ref().filter(|n| !n.is_null()) {
+            warn!("normalizer {normalizer} is not supported, ignoring it");
+        }
+
+        let pre_tokenizer = json
+            .pre_tokenizer
+            .filter(|p| !p.is_null())
+            .ok_or_else(|| unsupported("no pre-tokenizer, expected ByteLevel".into()))?;
+        let pre_tokenizer: PreTokenizer = serde_json::from_value(pre_tokenizer)
+            .map_err(|e| unsupported(format!("pre-tokenizer {e}")))?;
+        let mut steps = Vec::new();
+        if !pre_tokenizer.steps(&mut steps).map_err(unsupported)? {
+            return Err(unsupported(
+                "pre-tokenizer without ByteLevel, only byte-level BPE is supported".into(),
+            ));
+        }
+
+        let mut byte_tokens = [None; 256];
+        for (token, &c) in byte_tokens.iter_mut().zip(&BYTE_CHARS) {
+            *token = json
+                .model
+                .vocab
+                .get(c.encode_utf8(&mut [0; 4]) as &str)
+                .copied();
+        }
+        let mut merges = HashMap::with_capacity(json.model.merges.len());
+        for (rank, merge) in json.model.merges.iter().enumerate() {
+            let (a, b) = match merge {
+                Merge::Joined(merge) => merge
+                    .split_once(' ')
+                    .ok_or_else(|| Error::header(path, format!("invalid merge {merge:?}")))?,
+                Merge::Pair(a, b) => (a.as_str(), b.as_str()),
+            };
+            let vocab = &json.model.vocab;
+            let (Some(&a_id), Some(&b_id), Some(&merged)) =
+                (vocab.get(a), vocab.get(b), vocab.get(&format!("{a}{b}")))
+            else {
+                warn!("merge {a:?} {b:?} is not in the vocabulary, skipping it");
+                continue;
+            };
+            // 重复的规则以第一条为准
+            merges.entry((a_id, b_id)).or_insert((rank, merged));
+        }
+
+        let vocab_size = json
+            .model
+            .vocab
+            .values()
+            .chain(json.added_tokens.iter().map(|token| &token.id))
+            .max()
+            .map_or(0, |&id| id as usize + 1);
+        let mut pieces = vec![Vec::new(); vocab_size];
+        let mut texts = vec![String::new(); vocab_size];
+        let char_bytes = BYTE_CHARS
+            .iter()
+            .enumerate()
+            .map(|(byte, &c)| (c, byte as u8))
+            .collect::<HashMap<_, _>>();
+        for (token, &id) in &json.model.vocab {
+            let mut bytes = Vec::with_capacity(token.len());
+            for c in token.chars() {
+                match char_bytes.get(&c) {
+                    Some(&byte) => bytes.push(byte),
+                    None => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+            pieces[id as usize] = bytes;
+            texts[id as usize].clone_from(token);
+        }
+        let mut added_tokens = Vec::new();
+        let mut special_tokens = Vec::new();
+        for token in &json.added_tokens {
+            pieces[token.id as usize] = token.content.clone().into_bytes();
+            texts[token.id as usize].clone_from(&token.content);
+            if !token.special {
+                added_tokens.push((token.content.clone(), token.id));
+            }
+            special_tokens.push((token.content.clone(), token.id));
+        }
+        added_tokens.sort_by_key(|(content, _)| std::cmp::Reverse(content.len()));
+        special_tokens.sort_by_key(|(content, _)| std::cmp::Reverse(content.len()));
+
+        let mut tokenizer = Self {
+            path: path.into(),
+            unk: json
+                .model
+                .unk_token
+                .as_ref()
+                .and_then(|unk| json.model.vocab.get(unk))
+                .copied(),
+            vocab: json.model.vocab,
+            merges,
+            byte_tokens,
+            pieces,
+            texts,
+            steps,
+            added_tokens,
+            special_tokens,
+            ignore_merges: json.model.ignore_merges,
+            bos: None,
+            eos: None,
+        };
+        let token_id = |content: &str| {
+            json.added_tokens
+                .iter()
+                .find(|token| token.content == content)
+                .map(|token| token.id)
+                .or_else(|| tokenizer.vocab.get(content).copied())
+        };
+        let config_path = path.with_file_name("tokenizer_config.json");
+        let (bos, eos) = if config_path.is_file() {
+            let config: TokenizerConfig = read_json(&config_path)?;
+            let id = |token: Option<SpecialToken>| token.and_then(|t| token_id(t.content()));
+            (id(config.bos_token), id(config.eos_token))
+        } else {
+            let bos = json.post_processor.as_ref().and_then(template_bos);
+            (bos.and_then(token_id), None)
+        };
+        (tokenizer.bos, tokenizer.eos) = (bos, eos);
+        Ok(tokenizer)
+    }
+
+    /// 读取的文件。
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 把 `word` 编码为 token，追加到 `tokens`。
+    fn encode_word(&self, word: &str, tokens: &mut Vec<utok>) {
+        if self.ignore_merges {
+            let mapped = word
+                .bytes()
+                .map(|b| BYTE_CHARS[b as usize])
+                .collect::<String>();
+            if let Some(&token) = self.vocab.get(&mapped) {
+                tokens.push(token);
+                return;
+            }
+        }
+
+        let mut symbols = word
+            .bytes()
+            .filter_map(|b| self.byte_tokens[b as usize].or(self.unk))
+            .collect::<Vec<_>>();
+        // 每次合并排名最靠前的一对，同样的对先合并左边的
+        merge_pairs(&mut symbols, |left, right| {
+            let &(rank, merged) = self.merges.get(&(left, right))?;
+            Some((Reverse(rank), merged))
+        });
+        tokens.extend(symbols);
+    }
+
+    /// 编码 `text`，其中 `added_tokens` 的文本直接编码为对应的 token。
+    fn encode_with(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+        added_tokens: &[(String, utok)],
+    ) -> Vec<utok> {
+        let mut tokens = Vec::with_capacity(text.len() + 2);
+        if let Some(bos) = self.bos.filter(|_| bos) {
+            tokens.push(bos);
+        }
+        let mut rest = text;
+        while !rest.is_empty() {
+            let Some((pos, content, id)) = find_special(rest, added_tokens) else {
+                self.encode_text(rest, &mut tokens);
+                break;
+            };
+            if pos > 0 {
+                self.encode_text(&rest[..pos], &mut tokens);
+            }
+            tokens.push(id);
+            rest = &rest[pos + content.len()..];
+        }
+        if let Some(eos) = self.eos.filter(|_| eos) {
+            tokens.push(eos);
+        }
+        tokens
+    }
+
+    /// 对一段不含 added token 的文本执行 pre-tokenizer 并编码。
+    fn encode_text(&self, text: &str, tokens: &mut Vec<utok>) {
+        let mut words = vec![text.to_string()];
+        for step in &self.steps {
+            match step {
+                Step::Split(regex) => {
+                    words = words
+                        .iter()
+                        .flat_map(|word| split_isolated(regex, word))
+                        .collect();
+                }
+                Step::PrefixSpace => {
+                    for word in &mut words {
+                        if !word.starts_with(' ') {
+                            word.insert(0, ' ');
+                        }
+                    }
+                }
+            }
+        }
+        for word in words {
+            self.encode_word(&word, tokens);
+        }
+    }
+}
+
+impl Tokenize for BpeTokenizer {
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    fn bos(&self) -> Option<utok> {
+        self.bos
+    }
+
+    fn eos(&self) -> Option<utok> {
+        self.eos
+    }
+
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        self.encode_with(text, bos, eos, &self.added_tokens)
+    }
+
+    fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        self.encode_with(text, bos, eos, &self.special_tokens)
+    }
+
+    /// 字节级 BPE 的 token 不一定是完整的 UTF-8 字符，超出词表的 token 解码为空。
+    fn decode(&self, _token: utok, next: utok) -> &[u8] {
+        self.pieces.get(next as usize).map_or(&[], Vec::as_slice)
+    }
+
+    fn decode_first(&self, token: utok) -> &[u8] {
+        self.decode(token, token)
+    }
+
+    /// 超出词表的 token 的文本为空。
+    fn piece(&self, token: utok) -> &str {
+        self.texts.get(token as usize).map_or("", String::as_str)
+    }
+}
+
+impl PreTokenizer {
+    /// 把 pre-tokenizer 展开为步骤，追加到 `steps`，返回其中是否有 `ByteLevel`。
+    fn steps(self, steps: &mut Vec<Step>) -> std::result::Result<bool, String> {
+        let regex = |pattern: &str| {
+            Regex::new(pattern).map_err(|e| format!("pre-tokenizer pattern {pattern:?}, {e}"))
+        };
+        match self {
+            Self::ByteLevel {
+                add_prefix_space,
+                use_regex,
+            } => {
+                if add_prefix_space {
+                    steps.push(Step::PrefixSpace);
+                }
+                if use_regex {
+                    steps.push(Step::Split(regex(GPT2_PATTERN)?));
+                }
+                Ok(true)
+            }
+            Self::Split {
+                pattern,
+                behavior,
+                invert,
+            } => {
+                if behavior != "Isolated" || invert {
+                    return Err(format!(
+                        "split behavior {behavior:?} with invert {invert}, expected \"Isolated\""
+                    ));
+                }
+                let pattern = match pattern {
+                    Pattern::Regex(pattern) => pattern,
+                    Pattern::String(string) => fancy_regex::escape(&string).into_owned(),
+                };
+                steps.push(Step::Split(regex(&pattern)?));
+                Ok(false)
+            }
+            Self::Sequence { pretokenizers } => {
+                let mut byte_level = false;
+                for pre_tokenizer in pretokenizers {
+                    byte_level |= pre_tokenizer.steps(steps)?;
+                }
+                Ok(byte_level)
+            }
+        }
+    }
+}
+
+/// 把 `word` 在 `regex` 的每个匹配处切开，匹配本身和匹配之间的部分都保留。
+fn split_isolated(regex: &Regex, word: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut last = 0;
+    for m in regex.find_iter(word) {
+        let Ok(m) = m else {
+            warn!("pre-tokenizer pattern failed on {word:?}, keeping the rest whole");
+            break;
+        };
+        if m.start() > last {
+            words.push(word[last..m.start()].to_string());
+        }
+        if m.end() > m.start() {
+            words.push(m.as_str().to_string());
+        }
+        last = m.end();
+    }
+    if last < word.len() {
+        words.push(word[last..].to_string());
+    }
+    words
+}
+
+/// post-processor 模板中第一个特殊 token，也就是 BOS。
+fn template_bos(post_processor: &Value) -> Option<&str> {
+    match post_processor["type"].as_str()? {
+        "TemplateProcessing" => post_processor["single"]
+            .as_array()?
+            .first()?
+            .get("SpecialToken")?["id"]
+            .as_str(),
+        "Sequence" => post_processor["processors"]
+            .as_array()?
+            .iter()
+            .find_map(template_bos),
+        _ => None,
+    }
+}
+
+/// GPT-2 的 `bytes_to_unicode`：可见字节映射为自身，其余字节依次映射为 U+0100 起的字符。
+const BYTE_CHARS: [char; 256] = byte_chars();
+
+const fn byte_chars() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    let mut byte = 0;
+    while byte < 256 {
+        let visible = matches!(byte, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff);
+        chars[byte] = if visible {
+            byte as u8 as char
+        } else {
+            n += 1;
+            match char::from_u32(0xff + n) {
+                Some(c) => c,
+                None => unreachable!(),
+            }
+        };
+        byte += 1;
+    }
+    chars
+}
@@ -0,0 +1,88 @@
+use super::{utok, Tokenize};
+
+/// 逐个 token 解码出文本。
+///
+/// 一个字符的 UTF-8 编码可能分在几个 token 中，不完整的字节留到后面的 token 补齐后再输出，
+/// 所以每次只输出完整的字符。不是合法 UTF-8 的字节输出为 U+FFFD。
+pub struct StreamDecoder<'a, T: Tokenize + ?Sized = dyn Tokenize + 'a> {
+    tokenizer: &'a T,
+    /// 上一个 token，在文本的开头时没有意义
+    prev: utok,
+    /// 是否还在文本的开头，即还没有解码过 BOS 和 EOS 以外的 token
+    at_start: bool,
+    /// 还不是完整字符的字节
+    pending: Vec<u8>,
+}
+
+impl<'a, T: Tokenize + ?Sized> StreamDecoder<'a, T> {
+    /// 从文本的开头解码，开头和 BOS 之后一样去掉第一个 token 开头的空格。
+    pub fn new(tokenizer: &'a T) -> Self {
+        Self {
+            tokenizer,
+            prev: 0,
+            at_start: true,
+            pending: Vec::new(),
+        }
+    }
+
+    /// 接在 `prev` 之后解码，`prev` 本身不产生文本。
+    pub fn after(tokenizer: &'a T, prev: utok) -> Self {
+        Self {
+            prev,
+            at_start: false,
+            ..Self::new(tokenizer)
+        }
+    }
+
+    /// 解码下一个 token，返回由它补齐的文本，可能为空。
+    pub fn push(&mut self, token: utok) -> String {
+        let prev = std::mem::replace(&mut self.prev, token);
+        let tokenizer = self.tokenizer;
+        if [tokenizer.bos(), tokenizer.eos()].contains(&Some(token)) {
+            return String::new();
+        }
+        let bytes = if std::mem::take(&mut self.at_start) {
+            tokenizer.decode_first(token)
+        } else {
+            tokenizer.decode(prev, token)
+        };
+        self.pending.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        let mut rest = &self.pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // 字符还没有结束，等待后面的 token
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let consumed = self.pending.len() - rest.len();
+        self.pending.drain(..consumed);
+        text
+    }
+
+    /// 结束解码，返回剩下的不完整字节，每段输出为 U+FFFD。
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
@@ -1,14 +1,27 @@
 use std::fs::File;
-use std::io::{Read, Seek};
-use std::mem::size_of;
-use std::os::fd::AsRawFd;
-use std::ptr::{self, NonNull};
+use std::mem::{align_of, size_of};
+use std::path::Path;
+use std::str::FromStr;
 
-use libc::mmap;
-use log::{debug, info};
+use half::{bf16, f16};
+use log::{debug, info, warn};
+use memmap2::{Mmap, MmapMut};
+
+use crate::error::{Error, Result};
+use crate::gguf::Gguf;
+use crate::kernels::{
+    matmul, matmul_bf16, matmul_f16, matmul_q8, rms_norm, softmax, QuantizedTensor, QuantizedVec,
+    SwiGLU,
+};
+use crate::safetensors::{self as st, SafeTensors};
+use header::Header;
+
+mod export;
+mod gguf;
+mod header;
+mod safetensors;
 
 /// Transformer configuration
-#[repr(C)]
 #[derive(Debug, Default)]
 pub struct TransformerConfig {
     /// transformer dimension
@@ -21,108 +34,721 @@ pub struct TransformerConfig {
     pub num_heads: u32,
     /// number of key/value heads (can be < query heads because of multiquery)
     pub num_kv_heads: u32,
-    /// vovabulary size, usually 256
+    /// vocabulary size, usually 32000
     pub vocab_size: u32,
     /// max sequence length
     pub seq_len: u32,
 }
 
-/// Transformer weights
+/// Storage format of the weight matrices of a checkpoint.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WeightType {
+    /// plain fp32
+    #[default]
+    F32,
+    /// int8 values in groups of `group_size`, each with one fp32 scale
+    Q8_0 { group_size: usize },
+    /// IEEE half precision, upcast to fp32 inside the matmul
+    F16,
+    /// bfloat16, upcast to fp32 inside the matmul
+    BF16,
+}
+
+impl WeightType {
+    /// Number of bytes taken by `size` values, or `None` on overflow.
+    fn size_of(self, size: usize) -> Option<usize> {
+        match self {
+            Self::F32 => size.checked_mul(size_of::<f32>()),
+            Self::F16 | Self::BF16 => size.checked_mul(size_of::<f16>()),
+            Self::Q8_0 { group_size } => (size / group_size)
+                .checked_mul(size_of::<f32>())?
+                .checked_add(size),
+        }
+    }
+}
+
+impl FromStr for WeightType {
+    type Err = String;
+
+    /// Parses the floating point types, `f32`, `f16` or `bf16`.
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            "bf16" => Ok(Self::BF16),
+            _ => Err(format!(
+                "unknown weight type {s:?}, expected f32, f16 or bf16"
+            )),
+        }
+    }
+}
+
+impl WeightType {
+    /// Writes `values` to `out` in this format, in native byte order.
+    fn encode(self, values: &[f32], xq: &mut QuantizedVec, out: &mut [u8]) {
+        match self {
+            Self::F32 => {
+                for (out, v) in out.chunks_exact_mut(4).zip(values) {
+                    out.copy_from_slice(&v.to_ne_bytes());
+                }
+            }
+            Self::F16 => {
+                for (out, &v) in out.chunks_exact_mut(2).zip(values) {
+                    out.copy_from_slice(&f16::from_f32(v).to_ne_bytes());
+                }
+            }
+            Self::BF16 => {
+                for (out, &v) in out.chunks_exact_mut(2).zip(values) {
+                    out.copy_from_slice(&bf16::from_f32(v).to_ne_bytes());
+                }
+            }
+            Self::Q8_0 { group_size } => {
+                let w = xq.quantize(values, group_size);
+                let (q, s) = out.split_at_mut(values.len());
+                for (q, &v) in q.iter_mut().zip(w.q) {
+                    *q = v as u8;
+                }
+                for (s, v) in s.chunks_exact_mut(4).zip(w.s) {
+                    s.copy_from_slice(&v.to_ne_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// The llama2.c checkpoint formats [`Transformer::export`] writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointVersion {
+    /// the original headerless fp32 format
+    Legacy,
+    /// fp32 weights behind a versioned header, `export.py --version 1`
+    V1,
+    /// Q8_0 weight matrices in groups of `group_size`, `export.py --version 2`
+    V2 { group_size: usize },
+}
+
+/// Where and how the tensors are stored in a checkpoint.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// size of the header, the first tensor starts right after it
+    header_size: usize,
+    /// whether the tensors follow the order of llama2.c's `export.py`, norms
+    /// first and without `freq_cis`, rather than the legacy order
+    exported: bool,
+    /// whether the classifier is shared with the token embedding
+    shared_weights: bool,
+    /// storage of the weight matrices, the norms are always fp32
+    matrix_type: WeightType,
+}
+
+/// The view of `freq_cis`, which is never loaded.
+static FREQ_CIS: TensorView = TensorView {
+    layers: Vec::new(),
+    size: 0,
+    dtype: WeightType::F32,
+};
+
+/// Order of [`TransformerConfig::tensors`] in legacy checkpoints.
+const LEGACY_ORDER: [usize; 13] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+/// Order of [`TransformerConfig::tensors`] in exported checkpoints.
+const EXPORT_ORDER: [usize; 12] = [1, 6, 10, 0, 2, 3, 4, 5, 7, 8, 9, 12];
+
+impl TransformerConfig {
+    /// Checks that the dimensions are consistent with each other.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if [
+            self.dim,
+            self.hidden_dim,
+            self.num_layers,
+            self.num_heads,
+            self.num_kv_heads,
+            self.vocab_size,
+            self.seq_len,
+        ]
+        .contains(&0)
+        {
+            return Err(format!("zero-sized model dimensions in {self:?}"));
+        }
+        if !self.dim.is_multiple_of(self.num_heads) {
+            return Err(format!(
+                "dim {} is not a multiple of num_heads {}",
+                self.dim, self.num_heads
+            ));
+        }
+        if !self.num_heads.is_multiple_of(self.num_kv_heads) {
+            return Err(format!(
+                "num_heads {} is not a multiple of num_kv_heads {}",
+                self.num_heads, self.num_kv_heads
+            ));
+        }
+        if !(self.dim / self.num_heads).is_multiple_of(2) {
+            return Err(format!(
+                "head size {} must be even for RoPE",
+                self.dim / self.num_heads
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that the matrices of this model can be stored as `matrix_type`.
+    fn check_matrix_type(&self, matrix_type: WeightType) -> std::result::Result<(), String> {
+        if let WeightType::Q8_0 { group_size } = matrix_type {
+            // activations are quantized in groups too, so the group size has
+            // to divide the inputs of all matmuls
+            if group_size == 0
+                || !self.dim.is_multiple_of(group_size as u32)
+                || !self.hidden_dim.is_multiple_of(group_size as u32)
+            {
+                return Err(format!(
+                    "group size {group_size} must divide dim {} and hidden_dim {}",
+                    self.dim, self.hidden_dim
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The tensors of a checkpoint in legacy file order, as
+    /// `(name, floats per layer, number of layers, is a weight matrix)`.
+    ///
+    /// The classifier has no layers when it is shared with the token embedding.
+    fn tensors(&self, shared_weights: bool) -> [(&'static str, usize, usize, bool); 13] {
+        let dim = self.dim as usize;
+        let hidden_dim = self.hidden_dim as usize;
+        let n_layers = self.num_layers as usize;
+        let vocab_size = self.vocab_size as usize;
+        let head_size = dim / self.num_heads as usize;
+        let kv_dim = head_size * self.num_kv_heads as usize;
+        [
+            ("token_embedding", vocab_size * dim, 1, true),
+            ("rms_att_weight", dim, n_layers, false),
+            ("wq", dim * dim, n_layers, true),
+            ("wk", dim * kv_dim, n_layers, true),
+            ("wv", dim * kv_dim, n_layers, true),
+            ("wo", dim * dim, n_layers, true),
+            ("rms_ffn_weight", dim, n_layers, false),
+            ("w1", hidden_dim * dim, n_layers, true),
+            ("w2", dim * hidden_dim, n_layers, true),
+            ("w3", hidden_dim * dim, n_layers, true),
+            ("rms_final_weight", dim, 1, false),
+            // what used to be freq_cis_real and freq_cis_imag (for RoPE)
+            ("freq_cis", self.seq_len as usize * head_size / 2, 2, false),
+            ("wcls", vocab_size * dim, usize::from(!shared_weights), true),
+        ]
+    }
+}
+
+/// Location of a tensor in the buffers of [`TransformerWeights`].
+///
+/// Every layer is a block of `size` elements, a Q8_0 block holds the values
+/// followed by the group scales.
+#[derive(Debug, Default, Clone)]
+struct TensorView {
+    /// index of the buffer and offset in bytes of every layer
+    layers: Vec<(usize, usize)>,
+    /// number of elements in one layer
+    size: usize,
+    /// storage of the elements
+    dtype: WeightType,
+}
+
+impl TensorView {
+    /// Number of bytes of one layer.
+    #[inline]
+    fn layer_size(&self) -> usize {
+        self.dtype.size_of(self.size).unwrap()
+    }
+}
+
+/// A weight matrix borrowed from the checkpoint, in its storage format.
+#[derive(Debug, Clone, Copy)]
+pub enum Matrix<'a> {
+    F32(&'a [f32]),
+    Q8_0(QuantizedTensor<'a>),
+    F16(&'a [f16]),
+    BF16(&'a [bf16]),
+}
+
+impl Matrix<'_> {
+    /// `W (d, n) @ x (n,)`, quantizing `x` into `xq` first when the weights
+    /// are quantized.
+    pub fn matmul(self, x: &[f32], xq: &mut QuantizedVec, o: &mut [f32], n: usize, d: usize) {
+        match self {
+            Self::F32(w) => matmul(x, w, o, n, d),
+            Self::Q8_0(w) => matmul_q8(xq.quantize(&x[..n], w.group_size), w, o, n, d),
+            Self::F16(w) => matmul_f16(x, w, o, n, d),
+            Self::BF16(w) => matmul_bf16(x, w, o, n, d),
+        }
+    }
+
+    /// Copies row `i` of the matrix into `o`, which is as long as a row.
+    pub fn row(self, i: usize, o: &mut [f32]) {
+        let n = o.len();
+        match self {
+            Self::F32(w) => o.copy_from_slice(&w[i * n..][..n]),
+            Self::Q8_0(w) => QuantizedTensor {
+                q: &w.q[i * n..],
+                s: &w.s[i * n / w.group_size..],
+                ..w
+            }
+            .dequantize(o),
+            Self::F16(w) => {
+                for (o, w) in o.iter_mut().zip(&w[i * n..]) {
+                    *o = w.to_f32();
+                }
+            }
+            Self::BF16(w) => {
+                for (o, w) in o.iter_mut().zip(&w[i * n..]) {
+                    *o = w.to_f32();
+                }
+            }
+        }
+    }
+}
+
+/// Transformer weights, borrowed from the memory mapped checkpoint.
+///
+/// The mappings are owned by the weights and released when they are dropped,
+/// so every slice handed out by the accessors lives as long as the
+/// `Transformer`.
 pub struct TransformerWeights {
-    /// token embedding table
-    pub token_embedding: NonNull<f32>,
-    /// weights for rmsnorms
-    /// (layer, dim) rmsnorms weights
-    pub rms_att_weights: NonNull<f32>,
-    /// weights for ffn layers
-    pub rms_ffn_weights: NonNull<f32>,
+    /// the memory mapped checkpoint files, followed by an anonymous mapping
+    /// holding the tensors that could not be used in place, if any
+    buffers: Vec<Mmap>,
+    /// whether `wcls` is the token embedding
+    shared_weights: bool,
+    /// token embedding table (vocab_size, dim)
+    token_embedding: TensorView,
+    /// weights for rmsnorms (layer, dim)
+    rms_att_weight: TensorView,
+    /// weights for ffn rmsnorms (layer, dim)
+    rms_ffn_weight: TensorView,
     /// weights for matmuls, note dim == n_heads * head_size
-    // (layer, dim, n_heads * head_size)
-    pub wq: NonNull<f32>,
-    // (layer, dim, n_kv_heads * head_size)
-    pub wk: NonNull<f32>,
-    // (layer, dim, n_kv_heads * head_size)
-    pub wv: NonNull<f32>,
-    // (layer, dim, n_heads * head_size)
-    pub wo: NonNull<f32>,
-    // (layer, hidden_dim, dim)
-    pub w1: NonNull<f32>,
-    // (layer, hidden_dim, dim)
-    pub w2: NonNull<f32>,
-    // (layer, hidden_dim, dim)
-    pub w3: NonNull<f32>,
-    /// final rmsnorm
-    pub rms_final_weight: NonNull<f32>,
-    /// (optional) classifier weights for the logits, on the last layer
-    pub wcls: NonNull<f32>,
+    /// (layer, dim, n_heads * head_size)
+    wq: TensorView,
+    /// (layer, dim, n_kv_heads * head_size)
+    wk: TensorView,
+    /// (layer, dim, n_kv_heads * head_size)
+    wv: TensorView,
+    /// (layer, n_heads * head_size, dim)
+    wo: TensorView,
+    /// (layer, hidden_dim, dim)
+    w1: TensorView,
+    /// (layer, dim, hidden_dim)
+    w2: TensorView,
+    /// (layer, hidden_dim, dim)
+    w3: TensorView,
+    /// final rmsnorm (dim,)
+    rms_final_weight: TensorView,
+    /// classifier weights for the logits, on the last layer (vocab_size, dim)
+    wcls: TensorView,
 }
 
-impl Default for TransformerWeights {
-    fn default() -> Self {
+impl TransformerWeights {
+    /// Lays the tensors out over `mmap`, a llama2.c checkpoint, as described
+    /// by `layout`, checking that every tensor fits in the file.
+    fn new(mmap: Mmap, config: &TransformerConfig, layout: Layout, path: &Path) -> Result<Self> {
+        let tensors = config.tensors(layout.shared_weights);
+        let order: &[usize] = if layout.exported {
+            &EXPORT_ORDER
+        } else {
+            &LEGACY_ORDER
+        };
+        // the weights start right after the header
+        let mut offset = layout.header_size;
+        let mut views: [TensorView; 13] = Default::default();
+        for &i in order {
+            let (name, size, layers, matrix) = tensors[i];
+            let dtype = if matrix {
+                layout.matrix_type
+            } else {
+                WeightType::F32
+            };
+            let too_large = || Error::header(path, format!("tensor {name} is too large"));
+            let layer_size = dtype.size_of(size).ok_or_else(too_large)?;
+            // floats are read in place, so every block has to stay aligned
+            if !layer_size.is_multiple_of(size_of::<f32>()) {
+                return Err(Error::header(
+                    path,
+                    format!("tensor {name} has {layer_size} bytes per layer, not a multiple of 4"),
+                ));
+            }
+            let end = layer_size
+                .checked_mul(layers)
+                .and_then(|len| len.checked_add(offset))
+                .ok_or_else(too_large)?;
+            if end > mmap.len() {
+                return Err(Error::Truncated {
+                    path: path.into(),
+                    what: format!("tensor {name}"),
+                    expected: end,
+                    actual: mmap.len(),
+                });
+            }
+            views[i] = TensorView {
+                layers: (0..layers)
+                    .map(|layer| (0, offset + layer * layer_size))
+                    .collect(),
+                size,
+                dtype,
+            };
+            offset = end;
+        }
+        if offset < mmap.len() {
+            warn!(
+                "{}: {} trailing bytes after the last tensor",
+                path.display(),
+                mmap.len() - offset
+            );
+        }
+
+        Ok(Self::from_views(vec![mmap], views, layout.shared_weights))
+    }
+
+    /// Assembles the weights from the views of [`TransformerConfig::tensors`]
+    /// into `buffers`.
+    fn from_views(buffers: Vec<Mmap>, views: [TensorView; 13], shared_weights: bool) -> Self {
+        let [token_embedding, rms_att_weight, wq, wk, wv, wo, rms_ffn_weight, w1, w2, w3, rms_final_weight, _freq_cis, wcls] =
+            views;
         Self {
-            token_embedding: NonNull::dangling(),
-            rms_att_weights: NonNull::dangling(),
-            rms_ffn_weights: NonNull::dangling(),
-            wq: NonNull::dangling(),
-            wk: NonNull::dangling(),
-            wv: NonNull::dangling(),
-            wo: NonNull::dangling(),
-            w1: NonNull::dangling(),
-            w2: NonNull::dangling(),
-            w3: NonNull::dangling(),
-            rms_final_weight: NonNull::dangling(),
-            wcls: NonNull::dangling(),
+            buffers,
+            shared_weights,
+            wcls: if shared_weights {
+                token_embedding.clone()
+            } else {
+                wcls
+            },
+            token_embedding,
+            rms_att_weight,
+            rms_ffn_weight,
+            wq,
+            wk,
+            wv,
+            wo,
+            w1,
+            w2,
+            w3,
+            rms_final_weight,
+        }
+    }
+
+    /// The views in the order of [`TransformerConfig::tensors`].
+    fn views(&self) -> [&TensorView; 13] {
+        [
+            &self.token_embedding,
+            &self.rms_att_weight,
+            &self.wq,
+            &self.wk,
+            &self.wv,
+            &self.wo,
+            &self.rms_ffn_weight,
+            &self.w1,
+            &self.w2,
+            &self.w3,
+            &self.rms_final_weight,
+            &FREQ_CIS,
+            &self.wcls,
+        ]
+    }
+
+    /// Storage format of the weight matrices of the layers.
+    pub fn matrix_type(&self) -> WeightType {
+        self.wq.dtype
+    }
+
+    /// Converts every matrix not stored as `matrix_type` into an anonymous
+    /// mapping, the other tensors stay where they are.
+    fn convert(
+        mut self,
+        config: &TransformerConfig,
+        matrix_type: WeightType,
+        path: &Path,
+    ) -> Result<Self> {
+        let tensors = config.tensors(self.shared_weights);
+        let owned = self.buffers.len();
+        let mut new_views = self.views().map(TensorView::clone);
+        for (view, &(name, size, _, matrix)) in new_views.iter_mut().zip(&tensors) {
+            if !matrix || view.dtype == matrix_type {
+                continue;
+            }
+            matrix_type
+                .size_of(size)
+                .ok_or_else(|| Error::header(path, format!("tensor {name} is too large")))?;
+            view.dtype = matrix_type;
+            view.layers
+                .iter_mut()
+                .for_each(|(buffer, _)| *buffer = owned);
+        }
+        if new_views
+            .iter()
+            .all(|view| view.layers.iter().all(|&(buffer, _)| buffer != owned))
+        {
+            return Ok(self);
         }
+
+        let views = self.views();
+        let mut floats = Vec::new();
+        let mut xq = QuantizedVec::default();
+        let mmap = fill_owned(&mut new_views, owned, path, |i, layer, new_view, out| {
+            self.encode_layer(views[i], layer, new_view.dtype, &mut floats, &mut xq, out);
+        })?;
+        self.buffers.push(mmap);
+        Ok(Self::from_views(
+            self.buffers,
+            new_views,
+            self.shared_weights,
+        ))
+    }
+
+    /// Writes `layer` of a tensor to `out` stored as `dtype`, converting it
+    /// through the scratch buffers `floats` and `xq` unless it already is.
+    fn encode_layer(
+        &self,
+        view: &TensorView,
+        layer: usize,
+        dtype: WeightType,
+        floats: &mut Vec<f32>,
+        xq: &mut QuantizedVec,
+        out: &mut [u8],
+    ) {
+        if view.dtype == dtype {
+            out.copy_from_slice(self.bytes(view, layer));
+            return;
+        }
+        // the whole layer, as one long row
+        floats.resize(view.size, 0.0);
+        self.matrix(view, layer).row(0, floats);
+        dtype.encode(floats, xq, out);
+    }
+
+    /// The bytes of `layer` of a tensor.
+    #[inline]
+    fn bytes(&self, view: &TensorView, layer: usize) -> &[u8] {
+        let (buffer, offset) = view.layers[layer];
+        &self.buffers[buffer][offset..][..view.layer_size()]
+    }
+
+    /// `layer` of an fp32 tensor.
+    #[inline]
+    fn layer(&self, view: &TensorView, layer: usize) -> &[f32] {
+        debug_assert_eq!(view.dtype, WeightType::F32);
+        cast(self.bytes(view, layer))
+    }
+
+    /// `layer` of a weight matrix.
+    #[inline]
+    fn matrix(&self, view: &TensorView, layer: usize) -> Matrix<'_> {
+        let bytes = self.bytes(view, layer);
+        match view.dtype {
+            WeightType::F32 => Matrix::F32(cast(bytes)),
+            WeightType::F16 => Matrix::F16(cast(bytes)),
+            WeightType::BF16 => Matrix::BF16(cast(bytes)),
+            WeightType::Q8_0 { group_size } => {
+                let (q, s) = bytes.split_at(view.size);
+                Matrix::Q8_0(QuantizedTensor {
+                    // SAFETY: i8 and u8 have the same layout
+                    q: unsafe { &*(q as *const [u8] as *const [i8]) },
+                    s: cast(s),
+                    group_size,
+                })
+            }
+        }
+    }
+
+    /// token embedding table (vocab_size, dim)
+    pub fn token_embedding(&self) -> Matrix<'_> {
+        self.matrix(&self.token_embedding, 0)
+    }
+
+    /// attention rmsnorm weights of `layer` (dim,)
+    pub fn rms_att_weight(&self, layer: usize) -> &[f32] {
+        self.layer(&self.rms_att_weight, layer)
+    }
+
+    /// ffn rmsnorm weights of `layer` (dim,)
+    pub fn rms_ffn_weight(&self, layer: usize) -> &[f32] {
+        self.layer(&self.rms_ffn_weight, layer)
+    }
+
+    /// query projection of `layer` (dim, dim)
+    pub fn wq(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.wq, layer)
+    }
+
+    /// key projection of `layer` (kv_dim, dim)
+    pub fn wk(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.wk, layer)
+    }
+
+    /// value projection of `layer` (kv_dim, dim)
+    pub fn wv(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.wv, layer)
+    }
+
+    /// attention output projection of `layer` (dim, dim)
+    pub fn wo(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.wo, layer)
+    }
+
+    /// ffn gate projection of `layer` (hidden_dim, dim)
+    pub fn w1(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.w1, layer)
+    }
+
+    /// ffn down projection of `layer` (dim, hidden_dim)
+    pub fn w2(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.w2, layer)
+    }
+
+    /// ffn up projection of `layer` (hidden_dim, dim)
+    pub fn w3(&self, layer: usize) -> Matrix<'_> {
+        self.matrix(&self.w3, layer)
+    }
+
+    /// final rmsnorm weights (dim,)
+    pub fn rms_final_weight(&self) -> &[f32] {
+        self.layer(&self.rms_final_weight, 0)
+    }
+
+    /// classifier weights (vocab_size, dim)
+    pub fn wcls(&self) -> Matrix<'_> {
+        self.matrix(&self.wcls, 0)
     }
 }
 
+/// Places the layers of `views` in buffer `owned` one after the other in a
+/// new anonymous mapping, and fills them with `fill(tensor, layer, view,
+/// bytes)`.
+fn fill_owned(
+    views: &mut [TensorView; 13],
+    owned: usize,
+    path: &Path,
+    mut fill: impl FnMut(usize, usize, &TensorView, &mut [u8]),
+) -> Result<Mmap> {
+    let mut len = 0_usize;
+    for view in views.iter_mut() {
+        let layer_size = view.layer_size();
+        for (buffer, offset) in &mut view.layers {
+            if *buffer == owned {
+                *offset = len;
+                // keep every layer aligned for `cast`
+                len = len
+                    .checked_add(layer_size.next_multiple_of(size_of::<f32>()))
+                    .ok_or_else(|| Error::header(path, "the converted weights are too large"))?;
+            }
+        }
+    }
+
+    // an empty anonymous mapping cannot be created
+    let mut mmap = MmapMut::map_anon(len.max(1)).map_err(|e| Error::io(path, e))?;
+    for (i, view) in views.iter().enumerate() {
+        let layer_size = view.layer_size();
+        for (layer, &(buffer, offset)) in view.layers.iter().enumerate() {
+            if buffer == owned {
+                fill(i, layer, view, &mut mmap[offset..][..layer_size]);
+            }
+        }
+    }
+    mmap.make_read_only().map_err(|e| Error::io(path, e))
+}
+
+/// Whether `offset` into a mapping is aligned for `dtype`, so that a layer
+/// stored there can be used in place.
+#[inline]
+fn is_aligned(offset: usize, dtype: WeightType) -> bool {
+    match dtype {
+        WeightType::F32 => offset.is_multiple_of(align_of::<f32>()),
+        WeightType::F16 | WeightType::BF16 => offset.is_multiple_of(align_of::<f16>()),
+        WeightType::Q8_0 { .. } => false,
+    }
+}
+
+/// Views bytes of the checkpoint as floats of type `T`, one of `f32`, `f16`
+/// and `bf16`.
+#[inline]
+fn cast<T: Float>(bytes: &[u8]) -> &[T] {
+    // SAFETY: any bit pattern is a valid float. The mappings are page
+    // aligned and the layers used in place are checked to be aligned when
+    // they are loaded, a misaligned view is a bug and panics.
+    let (head, data, tail) = unsafe { bytes.align_to::<T>() };
+    assert!(
+        head.is_empty() && tail.is_empty(),
+        "tensor data at {:p} is not aligned for {}",
+        bytes.as_ptr(),
+        std::any::type_name::<T>()
+    );
+    data
+}
+
+/// Float types the weights are stored as, for which [`cast`] is sound.
+trait Float {}
+impl Float for f32 {}
+impl Float for f16 {}
+impl Float for bf16 {}
+
+/// Buffers for the "wave" of activations in the forward pass.
+#[derive(Debug, Default)]
 pub struct RunState {
-    /// activation at current time stamp.
-    pub x: NonNull<f32>,
+    /// activation at current time stamp (dim,)
+    x: Vec<f32>,
     /// same, but inside a residual branch (dim,)
-    pub xb: NonNull<f32>,
+    xb: Vec<f32>,
     /// an additional buffer just for convenience (dim,)
-    pub xb2: NonNull<f32>,
+    xb2: Vec<f32>,
     /// buffer for hidden dimension in the ffn (hidden_dim,)
-    pub hb: NonNull<f32>,
+    hb: Vec<f32>,
     /// buffer for hidden dimension in the ffn (hidden_dim,)
-    pub hb2: NonNull<f32>,
+    hb2: Vec<f32>,
     /// query (dim,)
-    pub q: NonNull<f32>,
-    /// key (dim,)
-    pub k: NonNull<f32>,
-    /// value (dim,)
-    pub v: NonNull<f32>,
+    q: Vec<f32>,
+    /// the input of a matmul quantized for Q8_0 weights
+    xq: QuantizedVec,
     /// buffer for scores/attention values (n_heads, seq_len)
-    pub att: NonNull<f32>,
-    /// output logits
-    pub logits: NonNull<f32>,
-    // (layer, seq_len, dim)
-    pub key_cache: NonNull<f32>,
-    // (layer, seq_len, dim)
-    pub value_cache: NonNull<f32>,
+    att: Vec<f32>,
+    /// output logits (vocab_size,)
+    logits: Vec<f32>,
+    /// (layer, seq_len, kv_dim), the row at the current position is the key
+    key_cache: Vec<f32>,
+    /// (layer, seq_len, kv_dim), the row at the current position is the value
+    value_cache: Vec<f32>,
 }
 
-impl Default for RunState {
-    fn default() -> Self {
+impl RunState {
+    pub fn new(config: &TransformerConfig) -> Self {
+        let dim = config.dim as usize;
+        let hidden_dim = config.hidden_dim as usize;
+        let n_layers = config.num_layers as usize;
+        let seq_len = config.seq_len as usize;
+        let n_heads = config.num_heads as usize;
+        let kv_dim = (dim * config.num_kv_heads as usize) / n_heads;
+
         Self {
-            x: NonNull::dangling(),
-            xb: NonNull::dangling(),
-            xb2: NonNull::dangling(),
-            hb: NonNull::dangling(),
-            hb2: NonNull::dangling(),
-            q: NonNull::dangling(),
-            k: NonNull::dangling(),
-            v: NonNull::dangling(),
-            att: NonNull::dangling(),
-            logits: NonNull::dangling(),
-            key_cache: NonNull::dangling(),
-            value_cache: NonNull::dangling(),
+            x: vec![0.0; dim],
+            xb: vec![0.0; dim],
+            xb2: vec![0.0; dim],
+            hb: vec![0.0; hidden_dim],
+            hb2: vec![0.0; hidden_dim],
+            q: vec![0.0; dim],
+            xq: QuantizedVec::default(),
+            att: vec![0.0; n_heads * seq_len],
+            logits: vec![0.0; config.vocab_size as usize],
+            key_cache: vec![0.0; n_layers * seq_len * kv_dim],
+            value_cache: vec![0.0; n_layers * seq_len * kv_dim],
         }
     }
+
+    /// The logits produced by the last forward pass.
+    #[inline]
+    pub fn logits(&self) -> &[f32] {
+        &self.logits
+    }
 }
 
 /// Transformer model
+///
+/// Loads llama2.c checkpoints in every version as well as llama models in
+/// GGUF files or in HuggingFace safetensors.
 pub struct Transformer {
     /// transformer configuration
     pub config: TransformerConfig,
@@ -130,152 +756,236 @@ pub struct Transformer {
     pub weights: TransformerWeights,
     /// buffers for the "wave" of activations in the forward pass
     pub state: RunState,
-    // file descriptor for memory mapping.
-    pub fd: usize,
-    // memory mapped data.
-    pub data: NonNull<u8>,
-    // size of the checkpoint file in bytes.
-    pub file_size: usize,
 }
 
-impl Default for Transformer {
-    fn default() -> Self {
-        Self {
-            config: TransformerConfig::default(),
-            weights: TransformerWeights::default(),
-            state: RunState::default(),
-            fd: 0,
-            data: NonNull::dangling(),
-            file_size: 0,
-        }
+impl Transformer {
+    /// Loads a checkpoint, keeping the weight matrices in the format they
+    /// are stored in.
+    pub fn new(checkpoint_path: impl AsRef<Path>) -> Result<Self> {
+        let (config, weights) = Self::read_checkpoint(checkpoint_path.as_ref())?;
+        let state = RunState::new(&config);
+        Ok(Self {
+            config,
+            weights,
+            state,
+        })
     }
-}
 
-impl Transformer {
-    pub fn new(checkpoint_path: String) -> Self {
-        let mut transformer = Self::read_checkpoint(checkpoint_path);
-        transformer.alloc_run_state();
-        transformer
-    }
-
-    fn read_checkpoint(checkpoint_path: String) -> Self {
-        let mut transformer = Transformer::default();
-        let mut file = File::open(checkpoint_path).unwrap();
-        // read config header
-        file.read_exact(unsafe {
-            std::slice::from_raw_parts_mut(
-                &mut transformer.config as *mut _ as *mut u8,
-                size_of::<TransformerConfig>(),
-            )
+    /// Loads a checkpoint and converts its weight matrices to `matrix_type`,
+    /// to trade memory for accuracy.
+    pub fn with_weight_type(
+        checkpoint_path: impl AsRef<Path>,
+        matrix_type: WeightType,
+    ) -> Result<Self> {
+        let path = checkpoint_path.as_ref();
+        let (config, weights) = Self::read_checkpoint(path)?;
+        config
+            .check_matrix_type(matrix_type)
+            .map_err(|reason| Error::unsupported(path, reason))?;
+        let weights = weights.convert(&config, matrix_type, path)?;
+        info!("weight matrices: {:?}", weights.matrix_type());
+        let state = RunState::new(&config);
+        Ok(Self {
+            config,
+            weights,
+            state,
         })
-        .unwrap();
-        info!("config: {:?}", transformer.config);
-
-        // negative vocab size is hacky way of signaling unshared weights. bit yikes.
-        let shared_weights = transformer.config.vocab_size > 0;
-        // transformer.config.vocab_size = transformer.config.vocab_size.abs();
-        // figure out the file size
-        transformer.file_size = file.seek(std::io::SeekFrom::End(0)).unwrap() as usize;
-        debug!("file size: {:#x}", transformer.file_size);
-
-        let fd = file.as_raw_fd();
-        debug!("fd: {}", fd);
-        let data = unsafe {
-            mmap(
-                ptr::null_mut(),
-                transformer.file_size,
-                libc::PROT_READ,
-                libc::MAP_PRIVATE,
-                fd,
-                0,
-            )
-        };
-        debug!("data: {:#x}", data as usize);
+    }
 
-        if data.is_null() {
-            panic!("failed to mmap file");
+    fn read_checkpoint(path: &Path) -> Result<(TransformerConfig, TransformerWeights)> {
+        if let Some(path) = st::find(path) {
+            let model = SafeTensors::open(path)?;
+            let (config, shared_weights) = safetensors::config(&model)?;
+            info!("config: {config:?}, shared classifier: {shared_weights}");
+            config
+                .validate()
+                .map_err(|reason| Error::header(model.path(), reason))?;
+            let weights = safetensors::load(model, &config, shared_weights)?;
+            return Ok((config, weights));
         }
-        transformer.data = NonNull::new(data as *mut u8).unwrap();
-        unsafe {
-            transformer.mmap_weights(transformer.data, shared_weights);
+
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        // memory map the whole checkpoint, the file can be closed once it is mapped
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+
+        if crate::gguf::has_magic(&mmap) {
+            let gguf = Gguf::from_mmap(mmap, path)?;
+            let (config, shared_weights) = gguf::config(&gguf)?;
+            info!("config: {config:?}, shared classifier: {shared_weights}");
+            config
+                .validate()
+                .map_err(|reason| Error::header(path, reason))?;
+            let weights = gguf::load(gguf, &config, shared_weights)?;
+            return Ok((config, weights));
         }
 
-        transformer
+        let header = Header::parse(&mmap, path)?;
+        debug!("header: {header:?}");
+        let (config, layout) = header
+            .into_layout()
+            .map_err(|reason| Error::header(path, reason))?;
+        info!("config: {config:?}, layout: {layout:?}");
+        config
+            .validate()
+            .map_err(|reason| Error::header(path, reason))?;
+        config
+            .check_matrix_type(layout.matrix_type)
+            .map_err(|reason| Error::header(path, reason))?;
+        debug!("file size: {:#x}", mmap.len());
+
+        let weights = TransformerWeights::new(mmap, &config, layout, path)?;
+        Ok((config, weights))
     }
 
-    unsafe fn mmap_weights(&mut self, ptr: NonNull<u8>, shared_weights: bool) {
-        let mut ptr = ptr.as_ptr() as *const f32;
+    /// Runs one step of the model for `token` at position `pos` and
+    /// returns the logits over the vocabulary.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token` is not below `vocab_size` or `pos` is not below
+    /// `seq_len`.
+    pub fn forward(&mut self, token: u32, pos: u32) -> &mut [f32] {
         let config = &self.config;
-        let weights = &mut self.weights;
-        let head_size = config.dim / config.num_heads;
-        let n_layers = config.num_layers;
-        let dim = config.dim;
-        let hidden_dim = config.hidden_dim;
+        let w = &self.weights;
+        let dim = config.dim as usize;
+        let hidden_dim = config.hidden_dim as usize;
+        let n_layers = config.num_layers as usize;
+        let n_heads = config.num_heads as usize;
+        let seq_len = config.seq_len as usize;
+        let vocab_size = config.vocab_size as usize;
+        let kv_dim = (dim * config.num_kv_heads as usize) / n_heads;
+        // integer multiplier of the kv sharing in multiquery
+        let kv_mul = n_heads / config.num_kv_heads as usize;
+        let head_size = dim / n_heads;
+        assert!(
+            token < config.vocab_size,
+            "token {token} is outside the vocabulary of {vocab_size} tokens"
+        );
+        assert!(
+            pos < config.seq_len,
+            "position {pos} is beyond the context of {seq_len} tokens"
+        );
+        let token = token as usize;
+        let pos = pos as usize;
 
-        weights.token_embedding = NonNull::new(ptr as *mut f32).unwrap();
+        let RunState {
+            x,
+            xb,
+            xb2,
+            hb,
+            hb2,
+            q,
+            xq,
+            att,
+            logits,
+            key_cache,
+            value_cache,
+        } = &mut self.state;
 
-        ptr = ptr.add((config.vocab_size * dim) as usize);
-        weights.rms_att_weights = NonNull::new(ptr as *mut f32).unwrap();
+        // copy the token embedding into x
+        w.token_embedding().row(token, x);
 
-        ptr = ptr.add((config.num_layers * dim) as usize);
-        weights.wq = NonNull::new(ptr as *mut f32).unwrap();
+        // forward all the layers
+        for l in 0..n_layers {
+            // attention rmsnorm
+            rms_norm(x, w.rms_att_weight(l), xb, dim);
 
-        ptr = ptr.add((n_layers * dim * (config.num_heads * head_size)) as usize);
-        weights.wk = NonNull::new(ptr as *mut f32).unwrap();
+            // key and value point to the kv cache
+            let key_cache = &mut key_cache[l * seq_len * kv_dim..][..seq_len * kv_dim];
+            let value_cache = &mut value_cache[l * seq_len * kv_dim..][..seq_len * kv_dim];
 
-        ptr = ptr.add((n_layers * dim * (config.num_kv_heads * head_size)) as usize);
-        weights.wv = NonNull::new(ptr as *mut f32).unwrap();
+            // qkv matmuls for this position
+            w.wq(l).matmul(xb, xq, q, dim, dim);
+            let k = &mut key_cache[pos * kv_dim..][..kv_dim];
+            w.wk(l).matmul(xb, xq, k, dim, kv_dim);
+            let v = &mut value_cache[pos * kv_dim..][..kv_dim];
+            w.wv(l).matmul(xb, xq, v, dim, kv_dim);
 
-        ptr = ptr.add((n_layers * dim * (config.num_kv_heads * head_size)) as usize);
-        weights.wo = NonNull::new(ptr as *mut f32).unwrap();
+            // RoPE relative positional encoding: complex-valued rotate q and k in each head
+            let k = &mut key_cache[pos * kv_dim..][..kv_dim];
+            for i in (0..dim).step_by(2) {
+                let head_dim = i % head_size;
+                let freq = 1.0_f32 / 10000.0_f32.powf(head_dim as f32 / head_size as f32);
+                let val = pos as f32 * freq;
+                let (fci, fcr) = val.sin_cos();
+                rotate(&mut q[i..i + 2], fcr, fci);
+                if i < kv_dim {
+                    rotate(&mut k[i..i + 2], fcr, fci);
+                }
+            }
 
-        ptr = ptr.add((n_layers * dim * (config.num_heads * head_size)) as usize);
-        weights.rms_ffn_weights = NonNull::new(ptr as *mut f32).unwrap();
+            // multihead attention. iterate over all heads
+            for h in 0..n_heads {
+                // get the query vector for this head
+                let q = &q[h * head_size..][..head_size];
+                // attention scores for this head
+                let att = &mut att[h * seq_len..][..pos + 1];
+                // iterate over all timesteps, including the current one
+                for (t, score) in att.iter_mut().enumerate() {
+                    // get the key vector for this head and at this timestep
+                    let k = &key_cache[t * kv_dim + (h / kv_mul) * head_size..][..head_size];
+                    // calculate the attention score as the dot product of q and k
+                    let dot: f32 = q.iter().zip(k).map(|(q, k)| q * k).sum();
+                    *score = dot / (head_size as f32).sqrt();
+                }
 
-        ptr = ptr.add((n_layers * dim) as usize);
-        weights.w1 = NonNull::new(ptr as *mut f32).unwrap();
+                // softmax the scores to get attention weights, from 0..pos inclusively
+                softmax(att, pos + 1);
 
-        ptr = ptr.add((n_layers * dim * hidden_dim) as usize);
-        weights.w2 = NonNull::new(ptr as *mut f32).unwrap();
+                // weighted sum of the values, store back into xb
+                let xb = &mut xb[h * head_size..][..head_size];
+                xb.fill(0.0);
+                for (t, &a) in att.iter().enumerate() {
+                    // get the value vector for this head and at this timestep
+                    let v = &value_cache[t * kv_dim + (h / kv_mul) * head_size..][..head_size];
+                    // accumulate the weighted value into xb
+                    for (xb, &v) in xb.iter_mut().zip(v) {
+                        *xb += a * v;
+                    }
+                }
+            }
 
-        ptr = ptr.add((n_layers * hidden_dim * dim) as usize);
-        weights.w3 = NonNull::new(ptr as *mut f32).unwrap();
+            // final matmul to get the output of the attention
+            w.wo(l).matmul(xb, xq, xb2, dim, dim);
 
-        ptr = ptr.add((n_layers * dim * hidden_dim) as usize);
-        weights.rms_final_weight = NonNull::new(ptr as *mut f32).unwrap();
+            // residual connection back into x
+            for (x, &xb2) in x.iter_mut().zip(xb2.iter()) {
+                *x += xb2;
+            }
 
-        ptr = ptr.add(dim as usize);
-        ptr = ptr.add((config.seq_len * head_size) as usize / 2);
-        ptr = ptr.add((config.seq_len * head_size) as usize / 2);
+            // ffn rmsnorm
+            rms_norm(x, w.rms_ffn_weight(l), xb, dim);
 
-        weights.wcls = if shared_weights {
-            weights.token_embedding
-        } else {
-            NonNull::new(ptr as *mut f32).unwrap()
-        };
-    }
+            // Now for FFN in PyTorch we have: self.w2(F.silu(self.w1(x)) * self.w3(x))
+            // first calculate self.w1(x) and self.w3(x)
+            w.w1(l).matmul(xb, xq, hb, dim, hidden_dim);
+            w.w3(l).matmul(xb, xq, hb2, dim, hidden_dim);
 
-    fn alloc_run_state(&mut self) {
-        let config = &self.config;
-        let state = &mut self.state;
-        let dim = config.dim as usize;
-        let hidden_dim = config.hidden_dim as usize;
-        let n_layers = config.num_layers as usize;
-        let seq_num = config.seq_len as usize;
-        let n_heads = config.num_heads as usize;
-        let kv_dim = (dim * config.num_kv_heads as usize) / n_heads;
+            // SwiGLU non-linearity
+            SwiGLU(hb, hb2, hidden_dim);
 
-        state.x = NonNull::new(vec![0.0; dim].as_mut_ptr()).unwrap();
-        state.xb = NonNull::new(vec![0.0; dim].as_mut_ptr()).unwrap();
-        state.xb2 = NonNull::new(vec![0.0; dim].as_mut_ptr()).unwrap();
-        state.hb = NonNull::new(vec![0.0; hidden_dim].as_mut_ptr()).unwrap();
-        state.hb2 = NonNull::new(vec![0.0; hidden_dim].as_mut_ptr()).unwrap();
-        state.q = NonNull::new(vec![0.0; dim].as_mut_ptr()).unwrap();
-        state.key_cache =
-            NonNull::new(vec![0.0; n_layers * seq_num * kv_dim].as_mut_ptr()).unwrap();
-        state.value_cache =
-            NonNull::new(vec![0.0; n_layers * seq_num * kv_dim].as_mut_ptr()).unwrap();
-        state.att = NonNull::new(vec![0.0; n_heads * seq_num].as_mut_ptr()).unwrap();
-        state.logits = NonNull::new(vec![0.0; config.vocab_size as usize].as_mut_ptr()).unwrap();
+            // final matmul to get the output of the ffn
+            w.w2(l).matmul(hb, xq, xb, hidden_dim, dim);
+
+            // residual connection
+            for (x, &xb) in x.iter_mut().zip(xb.iter()) {
+                *x += xb;
+            }
+        }
+
+        // final rmsnorm
+        rms_norm(x, w.rms_final_weight(), xb, dim);
+
+        // classifier into logits
+        w.wcls().matmul(xb, xq, logits, dim, vocab_size);
+        logits
     }
 }
+
+/// Rotates the pair `v = (v0, v1)` by the angle whose cosine and sine are `fcr` and `fci`.
+#[inline]
+fn rotate(v: &mut [f32], fcr: f32, fci: f32) {
+    let (v0, v1) = (v[0], v[1]);
+    v[0] = v0 * fcr - v1 * fci;
+    v[1] = v0 * fci + v1 * fcr;
+}
@@ -0,0 +1,100 @@
+//! Writes a loaded model back out as a llama2.c checkpoint.
+//!
+//! Whatever the model was loaded from, the tensors are written in the order
+//! and storage format of the target version. Matrices that are not stored
+//! that way are converted one layer at a time, so the model is never copied
+//! as a whole. Legacy files also get the `freq_cis` table that llama2.c
+//! used to read RoPE from.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::header::Header;
+use super::{
+    CheckpointVersion, Layout, Transformer, TransformerConfig, WeightType, EXPORT_ORDER,
+    LEGACY_ORDER,
+};
+use crate::error::{Error, Result};
+use crate::kernels::QuantizedVec;
+
+impl Transformer {
+    /// Writes the model to `path` as a checkpoint of `version`, converting
+    /// the weight matrices as needed.
+    pub fn export(&self, path: impl AsRef<Path>, version: CheckpointVersion) -> Result<()> {
+        let path = path.as_ref();
+        let config = &self.config;
+        let weights = &self.weights;
+        let (exported, matrix_type) = match version {
+            CheckpointVersion::Legacy => (false, WeightType::F32),
+            CheckpointVersion::V1 => (true, WeightType::F32),
+            CheckpointVersion::V2 { group_size } => (true, WeightType::Q8_0 { group_size }),
+        };
+        config
+            .check_matrix_type(matrix_type)
+            .map_err(|reason| Error::unsupported(path, reason))?;
+        let layout = Layout {
+            header_size: 0,
+            exported,
+            shared_weights: weights.shared_weights,
+            matrix_type,
+        };
+        let header = Header::from_layout(config, layout)
+            .map_err(|reason| Error::unsupported(path, reason))?;
+
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        let mut out = BufWriter::new(file);
+        let io = |e| Error::io(path, e);
+        out.write_all(&header.to_bytes()).map_err(io)?;
+
+        let tensors = config.tensors(weights.shared_weights);
+        let views = weights.views();
+        let order: &[usize] = if exported {
+            &EXPORT_ORDER
+        } else {
+            &LEGACY_ORDER
+        };
+        let mut floats = Vec::new();
+        let mut bytes = Vec::new();
+        let mut xq = QuantizedVec::default();
+        for &i in order {
+            let (name, size, layers, matrix) = tensors[i];
+            if name == "freq_cis" {
+                write_freq_cis(config, &mut out).map_err(io)?;
+                continue;
+            }
+            let (view, dtype) = (views[i], if matrix { matrix_type } else { WeightType::F32 });
+            for layer in 0..layers {
+                if view.dtype == dtype {
+                    out.write_all(weights.bytes(view, layer)).map_err(io)?;
+                    continue;
+                }
+                bytes.resize(dtype.size_of(size).unwrap(), 0);
+                weights.encode_layer(view, layer, dtype, &mut floats, &mut xq, &mut bytes);
+                out.write_all(&bytes).map_err(io)?;
+            }
+        }
+        out.flush().map_err(io)
+    }
+}
+
+/// Writes the cosines then the sines of the RoPE angles, `(seq_len, head_size / 2)`
+/// each, the same values the forward pass computes on the fly.
+fn write_freq_cis(config: &TransformerConfig, out: &mut impl Write) -> std::io::Result<()> {
+    let head_size = config.dim / config.num_heads;
+    let angles = || {
+        (0..config.seq_len).flat_map(move |pos| {
+            (0..head_size).step_by(2).map(move |head_dim| {
+                let freq = 1.0_f32 / 10000.0_f32.powf(head_dim as f32 / head_size as f32);
+                pos as f32 * freq
+            })
+        })
+    };
+    for angle in angles() {
+        out.write_all(&angle.cos().to_le_bytes())?;
+    }
+    for angle in angles() {
+        out.write_all(&angle.sin().to_le_bytes())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! Llama models in GGUF files, as converted by llama.cpp.
+//!
+//! llama.cpp already permutes the query and key projections for the
+//! interleaved RoPE of llama2.c, so the tensors are used as they are. f32, f16
+//! and bf16 tensors are read in place from the mapped file. Q8_0 matrices are
+//! split into values and scales, and every other type becomes fp32, in an
+//! anonymous mapping.
+
+use log::warn;
+
+use super::{
+    fill_owned, is_aligned, TensorView, TransformerConfig, TransformerWeights, WeightType,
+};
+use crate::error::{Error, Result};
+use crate::gguf::{GgmlType, Gguf, TensorInfo, Value};
+
+/// GGUF names of the tensors of [`TransformerConfig::tensors`], `{}` stands
+/// for the layer.
+const TENSOR_NAMES: [&str; 13] = [
+    "token_embd.weight",
+    "blk.{}.attn_norm.weight",
+    "blk.{}.attn_q.weight",
+    "blk.{}.attn_k.weight",
+    "blk.{}.attn_v.weight",
+    "blk.{}.attn_output.weight",
+    "blk.{}.ffn_norm.weight",
+    "blk.{}.ffn_gate.weight",
+    "blk.{}.ffn_down.weight",
+    "blk.{}.ffn_up.weight",
+    "output_norm.weight",
+    // freq_cis is not stored, RoPE is computed on the fly
+    "",
+    "output.weight",
+];
+
+/// Index of `ffn_down` in [`TENSOR_NAMES`], the only matrix with rows of `hidden_dim`.
+const FFN_DOWN: usize = 8;
+
+/// Reads the config from the `llama.*` metadata, along with whether the
+/// classifier is shared with the token embedding.
+pub(super) fn config(gguf: &Gguf) -> Result<(TransformerConfig, bool)> {
+    let path = gguf.path();
+    let arch = gguf
+        .get("general.architecture")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if arch != "llama" {
+        return Err(Error::unsupported(
+            path,
+            format!("architecture {arch:?}, expected \"llama\""),
+        ));
+    }
+    let get = |key: &str| {
+        let key = format!("llama.{key}");
+        gguf.get(&key)
+            .and_then(Value::as_u64)
+            .and_then(|value| u32::try_from(value).ok())
+            .ok_or_else(|| Error::header(path, format!("missing or invalid metadata {key}")))
+    };
+
+    let token_embd = tensor(gguf, TENSOR_NAMES[0])?;
+    let vocab_size = token_embd
+        .dims
+        .get(1)
+        .and_then(|&len| u32::try_from(len).ok())
+        .ok_or_else(|| {
+            Error::header(
+                path,
+                format!("invalid dims {:?} of token_embd", token_embd.dims),
+            )
+        })?;
+    let num_heads = get("attention.head_count")?;
+    let config = TransformerConfig {
+        dim: get("embedding_length")?,
+        hidden_dim: get("feed_forward_length")?,
+        num_layers: get("block_count")?,
+        num_heads,
+        num_kv_heads: match gguf.get("llama.attention.head_count_kv") {
+            Some(_) => get("attention.head_count_kv")?,
+            None => num_heads,
+        },
+        vocab_size,
+        seq_len: get("context_length")?,
+    };
+
+    // the forward pass hardcodes these
+    if let Some(base) = gguf.get("llama.rope.freq_base").and_then(Value::as_f32) {
+        if base != 10000.0 {
+            warn!("RoPE frequency base {base} is not supported, using 10000");
+        }
+    }
+    if let Some(eps) = gguf
+        .get("llama.attention.layer_norm_rms_epsilon")
+        .and_then(Value::as_f32)
+    {
+        if eps != 1e-5 {
+            warn!("rmsnorm epsilon {eps} is not supported, using 1e-5");
+        }
+    }
+
+    Ok((config, gguf.tensor(TENSOR_NAMES[12]).is_none()))
+}
+
+/// Lays the tensors of the model out over the file, converting the ones
+/// the kernels cannot use in place into an anonymous mapping.
+pub(super) fn load(
+    gguf: Gguf,
+    config: &TransformerConfig,
+    shared_weights: bool,
+) -> Result<TransformerWeights> {
+    let path = gguf.path();
+    let group_size = GgmlType::Q8_0_BLOCK;
+    // the activations are quantized in groups as well
+    let can_quantize = config.dim.is_multiple_of(group_size as u32)
+        && config.hidden_dim.is_multiple_of(group_size as u32);
+
+    // find the tensors of every layer and choose how to store them, buffer 0
+    // is the file and buffer 1 the converted tensors
+    let mut views: [TensorView; 13] = Default::default();
+    let mut sources: [Vec<&TensorInfo>; 13] = Default::default();
+    for (i, (name, size, layers, matrix)) in config.tensors(shared_weights).into_iter().enumerate()
+    {
+        if TENSOR_NAMES[i].is_empty() || layers == 0 {
+            continue;
+        }
+        let row = if i == FFN_DOWN {
+            config.hidden_dim
+        } else {
+            config.dim
+        };
+        let infos = (0..layers)
+            .map(|layer| {
+                let info = tensor(&gguf, &TENSOR_NAMES[i].replace("{}", &layer.to_string()))?;
+                if info.dims.first() != Some(&row.into()) || info.len() != size as u64 {
+                    return Err(Error::header(
+                        path,
+                        format!(
+                            "tensor {} has dims {:?}, expected {size} values in rows of {row}",
+                            info.name, info.dims
+                        ),
+                    ));
+                }
+                if info.ggml_type.size_of(info.len()).is_none() {
+                    return Err(Error::unsupported(
+                        path,
+                        format!("tensor {} has type {:?}", info.name, info.ggml_type),
+                    ));
+                }
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // matrices keep their type if every layer has the same
+        let ggml_type = infos[0].ggml_type;
+        let same_type = matrix && infos.iter().all(|info| info.ggml_type == ggml_type);
+        let dtype = match ggml_type {
+            GgmlType::Q8_0 if same_type && can_quantize => WeightType::Q8_0 { group_size },
+            GgmlType::F16 if same_type => WeightType::F16,
+            GgmlType::BF16 if same_type => WeightType::BF16,
+            _ => WeightType::F32,
+        };
+        dtype
+            .size_of(size)
+            .ok_or_else(|| Error::header(path, format!("tensor {name} is too large")))?;
+        // f32, f16 and bf16 data is used in place, Q8_0 blocks are split
+        // into the values and the scales
+        let layers = infos
+            .iter()
+            .map(|info| {
+                let offset = gguf.data_offset(info);
+                if is_stored_as(info.ggml_type, dtype) && is_aligned(offset, dtype) {
+                    (0, offset)
+                } else {
+                    (1, 0)
+                }
+            })
+            .collect();
+        views[i] = TensorView {
+            layers,
+            size,
+            dtype,
+        };
+        sources[i] = infos;
+    }
+
+    let mut floats = Vec::new();
+    let converted = fill_owned(&mut views, 1, path, |i, layer, view, out| {
+        let info = sources[i][layer];
+        let data = gguf.tensor_data(info);
+        match view.dtype {
+            WeightType::Q8_0 { .. } => {
+                let (q, s) = out.split_at_mut(view.size);
+                let blocks = data.chunks_exact(group_size + 2);
+                for ((block, q), s) in blocks
+                    .zip(q.chunks_exact_mut(group_size))
+                    .zip(s.chunks_exact_mut(4))
+                {
+                    let (d, qs) = block.split_at(2);
+                    let d = half::f16::from_le_bytes([d[0], d[1]]).to_f32();
+                    q.copy_from_slice(qs);
+                    s.copy_from_slice(&d.to_ne_bytes());
+                }
+            }
+            // misaligned in the file
+            dtype if is_stored_as(info.ggml_type, dtype) => out.copy_from_slice(data),
+            _ => {
+                floats.clear();
+                info.ggml_type.dequantize(data, &mut floats);
+                for (out, value) in out.chunks_exact_mut(4).zip(&floats) {
+                    out.copy_from_slice(&value.to_ne_bytes());
+                }
+            }
+        }
+    })?;
+    Ok(TransformerWeights::from_views(
+        vec![gguf.into_mmap(), converted],
+        views,
+        shared_weights,
+    ))
+}
+
+/// Whether `ggml_type` is stored the way the kernels read `dtype`, so that
+/// the data can be used as it is.
+fn is_stored_as(ggml_type: GgmlType, dtype: WeightType) -> bool {
+    matches!(
+        (ggml_type, dtype),
+        (GgmlType::F32, WeightType::F32)
+            | (GgmlType::F16, WeightType::F16)
+            | (GgmlType::BF16, WeightType::BF16)
+    )
+}
+
+/// The tensor called `name`, which has to be in the file.
+fn tensor<'a>(gguf: &'a Gguf, name: &str) -> Result<&'a TensorInfo> {
+    gguf.tensor(name)
+        .ok_or_else(|| Error::header(gguf.path(), format!("missing tensor {name}")))
+}
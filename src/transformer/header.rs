@@ -0,0 +1,276 @@
+//! Checkpoint headers, one struct per llama2.c format version.
+//!
+//! Legacy files start right away with the config. Versioned files start with
+//! [`MAGIC`] and a version number, followed by the config and the fields of
+//! that version, zero padded to [`VERSIONED_SIZE`] bytes.
+
+use std::mem::size_of;
+use std::path::Path;
+
+use log::warn;
+
+use super::{Layout, TransformerConfig, WeightType};
+use crate::error::{Error, Result};
+
+/// Magic number of the versioned checkpoints, "ak42" in ASCII.
+pub(super) const MAGIC: u32 = 0x616b3432;
+
+/// Size of the header of versioned checkpoints, padding included.
+pub(super) const VERSIONED_SIZE: usize = 256;
+
+/// The legacy llama2.c checkpoint header, seven `i32`s as written by `export.py`.
+///
+/// A negative `vocab_size` is a hacky way of signaling that the classifier is
+/// not shared with the token embedding and is stored after the other tensors.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ConfigHeader(pub [i32; 7]);
+
+impl ConfigHeader {
+    /// size of the header in bytes
+    pub const SIZE: usize = size_of::<Self>();
+
+    /// The header of `config`, with a negative `vocab_size` if `signed` and
+    /// the classifier is not shared.
+    fn new(
+        config: &TransformerConfig,
+        shared_weights: bool,
+        signed: bool,
+    ) -> std::result::Result<Self, String> {
+        let to_i32 = |name: &str, value: u32| {
+            i32::try_from(value).map_err(|_| format!("{name} {value} does not fit in an i32"))
+        };
+        let vocab_size = to_i32("vocab_size", config.vocab_size)?;
+        Ok(Self([
+            to_i32("dim", config.dim)?,
+            to_i32("hidden_dim", config.hidden_dim)?,
+            to_i32("num_layers", config.num_layers)?,
+            to_i32("num_heads", config.num_heads)?,
+            to_i32("num_kv_heads", config.num_kv_heads)?,
+            if signed && !shared_weights {
+                -vocab_size
+            } else {
+                vocab_size
+            },
+            to_i32("seq_len", config.seq_len)?,
+        ]))
+    }
+
+    fn parse(bytes: &[u8]) -> Self {
+        let mut fields = [0; 7];
+        for (field, bytes) in fields.iter_mut().zip(bytes.chunks_exact(size_of::<i32>())) {
+            *field = i32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Self(fields)
+    }
+
+    /// Splits the header into the config and whether the classifier is shared.
+    fn into_config(self) -> std::result::Result<(TransformerConfig, bool), String> {
+        let [dim, hidden_dim, num_layers, num_heads, num_kv_heads, vocab_size, seq_len] = self.0;
+        let unsigned = |name: &str, value: i32| {
+            u32::try_from(value).map_err(|_| format!("negative {name} {value}"))
+        };
+        let config = TransformerConfig {
+            dim: unsigned("dim", dim)?,
+            hidden_dim: unsigned("hidden_dim", hidden_dim)?,
+            num_layers: unsigned("num_layers", num_layers)?,
+            num_heads: unsigned("num_heads", num_heads)?,
+            num_kv_heads: unsigned("num_kv_heads", num_kv_heads)?,
+            vocab_size: vocab_size.unsigned_abs(),
+            seq_len: unsigned("seq_len", seq_len)?,
+        };
+        Ok((config, vocab_size > 0))
+    }
+
+    /// The config of a versioned header, where the sign of `vocab_size`
+    /// carries no meaning.
+    fn into_versioned_config(self) -> std::result::Result<TransformerConfig, String> {
+        if self.0[5] < 0 {
+            return Err(format!("negative vocab_size {}", self.0[5]));
+        }
+        Ok(self.into_config()?.0)
+    }
+}
+
+/// Header of a version 1 checkpoint, fp32 weights in export order.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct V1Header {
+    pub config: ConfigHeader,
+    /// whether the classifier is shared with the token embedding
+    pub shared_classifier: bool,
+}
+
+/// Header of a version 2 checkpoint, Q8_0 weight matrices in export order.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct V2Header {
+    pub config: ConfigHeader,
+    /// whether the classifier is shared with the token embedding
+    pub shared_classifier: bool,
+    /// number of weights sharing a scaling factor
+    pub group_size: i32,
+}
+
+/// The header of a checkpoint in any of the supported versions.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Header {
+    /// a headerless legacy file, starting with the config
+    Legacy(ConfigHeader),
+    V1(V1Header),
+    V2(V2Header),
+}
+
+impl Header {
+    /// Parses the header at the start of the checkpoint `bytes`, read from `path`.
+    pub fn parse(bytes: &[u8], path: &Path) -> Result<Self> {
+        let truncated = |expected| Error::Truncated {
+            path: path.into(),
+            what: "config header".into(),
+            expected,
+            actual: bytes.len(),
+        };
+        let i32_at = |offset: usize| {
+            i32::from_le_bytes(bytes[offset..][..size_of::<i32>()].try_into().unwrap())
+        };
+
+        if bytes.len() < size_of::<u32>() || i32_at(0) as u32 != MAGIC {
+            if bytes.len() < ConfigHeader::SIZE {
+                return Err(truncated(ConfigHeader::SIZE));
+            }
+            return Ok(Self::Legacy(ConfigHeader::parse(bytes)));
+        }
+
+        if bytes.len() < VERSIONED_SIZE {
+            return Err(truncated(VERSIONED_SIZE));
+        }
+        // magic and version, then the fields of the version
+        let fields = &bytes[2 * size_of::<i32>()..VERSIONED_SIZE];
+        let config = ConfigHeader::parse(fields);
+        let shared_classifier = fields[ConfigHeader::SIZE] != 0;
+        let (header, len) = match i32_at(size_of::<u32>()) {
+            1 => (
+                Self::V1(V1Header {
+                    config,
+                    shared_classifier,
+                }),
+                ConfigHeader::SIZE + 1,
+            ),
+            2 => (
+                Self::V2(V2Header {
+                    config,
+                    shared_classifier,
+                    group_size: i32_at(2 * size_of::<i32>() + ConfigHeader::SIZE + 1),
+                }),
+                ConfigHeader::SIZE + 1 + size_of::<i32>(),
+            ),
+            version => {
+                return Err(Error::header(
+                    path,
+                    format!("unsupported checkpoint version {version}, expected 1 or 2"),
+                ))
+            }
+        };
+        if fields[len..].iter().any(|&b| b != 0) {
+            warn!("{}: the header padding is not zeroed", path.display());
+        }
+        Ok(header)
+    }
+
+    /// The header describing `config` stored with `layout`, the inverse of
+    /// [`Header::into_layout`].
+    pub fn from_layout(
+        config: &TransformerConfig,
+        layout: Layout,
+    ) -> std::result::Result<Self, String> {
+        let shared_classifier = layout.shared_weights;
+        Ok(match (layout.exported, layout.matrix_type) {
+            (false, WeightType::F32) => {
+                Self::Legacy(ConfigHeader::new(config, shared_classifier, true)?)
+            }
+            (true, WeightType::F32) => Self::V1(V1Header {
+                config: ConfigHeader::new(config, shared_classifier, false)?,
+                shared_classifier,
+            }),
+            (true, WeightType::Q8_0 { group_size }) => Self::V2(V2Header {
+                config: ConfigHeader::new(config, shared_classifier, false)?,
+                shared_classifier,
+                group_size: i32::try_from(group_size)
+                    .map_err(|_| format!("group size {group_size} does not fit in an i32"))?,
+            }),
+            (_, matrix_type) => {
+                return Err(format!(
+                    "no checkpoint version stores {matrix_type:?} weights"
+                ))
+            }
+        })
+    }
+
+    /// The header as written at the start of a checkpoint, padding included.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        let config = |bytes: &mut Vec<u8>, config: &ConfigHeader| {
+            bytes.extend(config.0.iter().flat_map(|field| field.to_le_bytes()));
+        };
+        match self {
+            Self::Legacy(header) => config(&mut bytes, &header),
+            Self::V1(header) => {
+                bytes.extend(MAGIC.to_le_bytes());
+                bytes.extend(1_i32.to_le_bytes());
+                config(&mut bytes, &header.config);
+                bytes.push(header.shared_classifier.into());
+            }
+            Self::V2(header) => {
+                bytes.extend(MAGIC.to_le_bytes());
+                bytes.extend(2_i32.to_le_bytes());
+                config(&mut bytes, &header.config);
+                bytes.push(header.shared_classifier.into());
+                bytes.extend(header.group_size.to_le_bytes());
+            }
+        }
+        bytes.resize(self.size(), 0);
+        bytes
+    }
+
+    /// Size of the header, the first tensor starts right after it.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Legacy(_) => ConfigHeader::SIZE,
+            Self::V1(_) | Self::V2(_) => VERSIONED_SIZE,
+        }
+    }
+
+    /// Splits the header into the config and the layout of the tensors.
+    pub fn into_layout(self) -> std::result::Result<(TransformerConfig, Layout), String> {
+        let header_size = self.size();
+        Ok(match self {
+            Self::Legacy(config) => {
+                let (config, shared_weights) = config.into_config()?;
+                let layout = Layout {
+                    header_size,
+                    exported: false,
+                    shared_weights,
+                    matrix_type: WeightType::F32,
+                };
+                (config, layout)
+            }
+            Self::V1(header) => {
+                let layout = Layout {
+                    header_size,
+                    exported: true,
+                    shared_weights: header.shared_classifier,
+                    matrix_type: WeightType::F32,
+                };
+                (header.config.into_versioned_config()?, layout)
+            }
+            Self::V2(header) => {
+                let group_size = usize::try_from(header.group_size)
+                    .map_err(|_| format!("negative group size {}", header.group_size))?;
+                let layout = Layout {
+                    header_size,
+                    exported: true,
+                    shared_weights: header.shared_classifier,
+                    matrix_type: WeightType::Q8_0 { group_size },
+                };
+                (header.config.into_versioned_config()?, layout)
+            }
+        })
+    }
+}
@@ -0,0 +1,239 @@
+//! Llama models in HuggingFace's safetensors format, with the config read
+//! from the `config.json` next to them.
+//!
+//! HF permutes the rows of the query and key projections for its rotate-half
+//! RoPE, which pairs value `i` of a head with value `i + head_size / 2`.
+//! llama2.c pairs neighbours instead, so the rows are permuted back while the
+//! tensors are copied into an anonymous mapping. The other f32, f16 and bf16
+//! tensors are read in place from the mapped shards, safetensors is little
+//! endian like every supported CPU. Everything else becomes fp32.
+
+use log::warn;
+use serde::Deserialize;
+
+use super::{
+    fill_owned, is_aligned, TensorView, TransformerConfig, TransformerWeights, WeightType,
+};
+use crate::error::{Error, Result};
+use crate::safetensors::{read_json, Dtype, SafeTensors, TensorInfo};
+
+/// HF names of the tensors of [`TransformerConfig::tensors`], `{}` stands for
+/// the layer.
+const TENSOR_NAMES: [&str; 13] = [
+    "model.embed_tokens.weight",
+    "model.layers.{}.input_layernorm.weight",
+    "model.layers.{}.self_attn.q_proj.weight",
+    "model.layers.{}.self_attn.k_proj.weight",
+    "model.layers.{}.self_attn.v_proj.weight",
+    "model.layers.{}.self_attn.o_proj.weight",
+    "model.layers.{}.post_attention_layernorm.weight",
+    "model.layers.{}.mlp.gate_proj.weight",
+    "model.layers.{}.mlp.down_proj.weight",
+    "model.layers.{}.mlp.up_proj.weight",
+    "model.norm.weight",
+    // freq_cis is not stored, RoPE is computed on the fly
+    "",
+    "lm_head.weight",
+];
+
+/// Indices of `q_proj` and `k_proj` in [`TENSOR_NAMES`], the permuted tensors.
+const Q_PROJ: usize = 2;
+const K_PROJ: usize = 3;
+/// Index of `down_proj` in [`TENSOR_NAMES`], the only matrix with rows of `hidden_dim`.
+const DOWN_PROJ: usize = 8;
+
+/// The fields of `config.json` used by llama models.
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    model_type: Option<String>,
+    hidden_size: u32,
+    intermediate_size: u32,
+    num_hidden_layers: u32,
+    num_attention_heads: u32,
+    num_key_value_heads: Option<u32>,
+    vocab_size: u32,
+    max_position_embeddings: u32,
+    rms_norm_eps: Option<f32>,
+    rope_theta: Option<f32>,
+    rope_scaling: Option<serde_json::Value>,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+}
+
+/// Reads the config from the `config.json` next to the model, along with
+/// whether the classifier is shared with the token embedding.
+pub(super) fn config(model: &SafeTensors) -> Result<(TransformerConfig, bool)> {
+    let path = model.path().with_file_name("config.json");
+    let hf: HfConfig = read_json(&path)?;
+    match hf.model_type.as_deref() {
+        Some("llama") | None => {}
+        Some(model_type) => {
+            return Err(Error::unsupported(
+                &path,
+                format!("model type {model_type:?}, expected \"llama\""),
+            ))
+        }
+    }
+
+    // the forward pass hardcodes these
+    if let Some(theta) = hf.rope_theta.filter(|&theta| theta != 10000.0) {
+        warn!("RoPE theta {theta} is not supported, using 10000");
+    }
+    if let Some(scaling) = hf
+        .rope_scaling
+        .as_ref()
+        .filter(|scaling| !scaling.is_null())
+    {
+        warn!("RoPE scaling {scaling} is not supported, ignoring it");
+    }
+    if let Some(eps) = hf.rms_norm_eps.filter(|&eps| eps != 1e-5) {
+        warn!("rmsnorm epsilon {eps} is not supported, using 1e-5");
+    }
+
+    let config = TransformerConfig {
+        dim: hf.hidden_size,
+        hidden_dim: hf.intermediate_size,
+        num_layers: hf.num_hidden_layers,
+        num_heads: hf.num_attention_heads,
+        num_kv_heads: hf.num_key_value_heads.unwrap_or(hf.num_attention_heads),
+        vocab_size: hf.vocab_size,
+        seq_len: hf.max_position_embeddings,
+    };
+    let shared_weights = hf.tie_word_embeddings || model.tensor(TENSOR_NAMES[12]).is_none();
+    Ok((config, shared_weights))
+}
+
+/// Lays the tensors of the model out over the shards, copying the ones that
+/// cannot be used in place into an anonymous mapping.
+pub(super) fn load(
+    model: SafeTensors,
+    config: &TransformerConfig,
+    shared_weights: bool,
+) -> Result<TransformerWeights> {
+    let path = model.path();
+    let head_size = (config.dim / config.num_heads) as usize;
+
+    // find the tensors of every layer, the buffers are the shards followed
+    // by the copied tensors
+    let owned = model.num_shards();
+    let mut views: [TensorView; 13] = Default::default();
+    let mut sources: [Vec<&TensorInfo>; 13] = Default::default();
+    let mut rows = [0; 13];
+    for (i, (name, size, layers, matrix)) in config.tensors(shared_weights).into_iter().enumerate()
+    {
+        if TENSOR_NAMES[i].is_empty() || layers == 0 {
+            continue;
+        }
+        let row = if i == DOWN_PROJ {
+            config.hidden_dim
+        } else {
+            config.dim
+        } as usize;
+        let infos = (0..layers)
+            .map(|layer| {
+                let name = TENSOR_NAMES[i].replace("{}", &layer.to_string());
+                let info = model
+                    .tensor(&name)
+                    .ok_or_else(|| Error::header(path, format!("missing tensor {name}")))?;
+                if info.shape.last() != Some(&row) || info.len() != size {
+                    return Err(Error::header(
+                        path,
+                        format!(
+                            "tensor {name} has shape {:?}, expected {size} values in rows of {row}",
+                            info.shape
+                        ),
+                    ));
+                }
+                if info.dtype.size_of(info.len()).is_none() {
+                    return Err(Error::unsupported(
+                        path,
+                        format!("tensor {name} has dtype {:?}", info.dtype),
+                    ));
+                }
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // matrices keep their type if every layer has the same
+        let same_type = matrix && infos.iter().all(|info| info.dtype == infos[0].dtype);
+        let dtype = match infos[0].dtype {
+            Dtype::F16 if same_type => WeightType::F16,
+            Dtype::BF16 if same_type => WeightType::BF16,
+            _ => WeightType::F32,
+        };
+        dtype
+            .size_of(size)
+            .ok_or_else(|| Error::header(path, format!("tensor {name} is too large")))?;
+        // q and k are permuted, the rest is used in place when its type is
+        // kept and it is aligned
+        let layers = infos
+            .iter()
+            .map(|info| {
+                let (shard, offset) = model.data_location(info);
+                let in_place = i != Q_PROJ && i != K_PROJ && is_stored_as(&info.dtype, dtype);
+                if in_place && is_aligned(offset, dtype) {
+                    (shard, offset)
+                } else {
+                    (owned, 0)
+                }
+            })
+            .collect();
+        views[i] = TensorView {
+            layers,
+            size,
+            dtype,
+        };
+        sources[i] = infos;
+        rows[i] = row;
+    }
+
+    let mut floats = Vec::new();
+    let mut bytes = Vec::new();
+    let copied = fill_owned(&mut views, owned, path, |i, layer, view, out| {
+        let info = sources[i][layer];
+        let data = model.tensor_data(info);
+        // f16 and bf16 are copied as they are, everything else becomes fp32
+        let data = if !is_stored_as(&info.dtype, view.dtype) {
+            floats.clear();
+            info.dtype.dequantize(data, &mut floats);
+            bytes.clear();
+            bytes.extend(floats.iter().flat_map(|v| v.to_le_bytes()));
+            &bytes
+        } else {
+            data
+        };
+        if i == Q_PROJ || i == K_PROJ {
+            unpermute(data, out.len() / view.size * rows[i], head_size, out);
+        } else {
+            out.copy_from_slice(data);
+        }
+    })?;
+    let mut buffers = model.into_mmaps();
+    buffers.push(copied);
+    Ok(TransformerWeights::from_views(
+        buffers,
+        views,
+        shared_weights,
+    ))
+}
+
+/// Whether `dtype` is stored the way the kernels read `weight_type`, so that
+/// the data can be used as it is.
+fn is_stored_as(dtype: &Dtype, weight_type: WeightType) -> bool {
+    matches!(
+        (dtype, weight_type),
+        (Dtype::F32, WeightType::F32)
+            | (Dtype::F16, WeightType::F16)
+            | (Dtype::BF16, WeightType::BF16)
+    )
+}
+
+/// Writes the rows of `row_size` bytes of a query or key projection in
+/// llama2.c order: row `2 * i + j` of each head comes from row
+/// `j * head_size / 2 + i`.
+fn unpermute(rows: &[u8], row_size: usize, head_size: usize, out: &mut [u8]) {
+    let half = head_size / 2;
+    for (r, out) in out.chunks_exact_mut(row_size).enumerate() {
+        let (head, i, j) = (r / head_size, r % head_size / 2, r % 2);
+        out.copy_from_slice(&rows[(head * head_size + j * half + i) * row_size..][..row_size]);
+    }
+}
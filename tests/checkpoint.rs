@@ -0,0 +1,272 @@
+//! Runs the binary on tiny hand-built checkpoints in both the shared and the
+//! unshared classifier layouts, for every checkpoint version.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use common::{tmp, Config, Model};
+
+mod common;
+
+// GGUF stores Q8_0 in blocks of 32 values
+const CONFIG: Config = Config {
+    dim: 32,
+    hidden_dim: 32,
+    n_layers: 1,
+    n_heads: 2,
+    n_kv_heads: 2,
+    vocab_size: VOCAB.len(),
+    seq_len: 8,
+};
+const VOCAB: [&str; 7] = ["<unk>", "<s>", "</s>", " ", "a", "x", "y"];
+
+/// Storage of the checkpoints written by the tests.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// the legacy headerless fp32 layout
+    Legacy,
+    /// llama2.c's version 1 export, fp32
+    V1,
+    /// llama2.c's version 2 export, Q8_0 with this group size
+    Q8_0(usize),
+    /// a llama.cpp GGUF file with matrices of this ggml type and the vocabulary
+    Gguf(u32),
+}
+
+/// Writes a checkpoint whose attention and ffn weights are all zero, so the
+/// logits only depend on the token embedding and the classifier.
+///
+/// Every token embeds onto the first axis, `x` the most, so the shared
+/// classifier always predicts `x`. The unshared classifier only has a row for
+/// `y`, so it always predicts `y` instead.
+fn write_checkpoint(path: &Path, shared: bool, format: Format) {
+    let dim = CONFIG.dim;
+    let mut model = Model::blank(CONFIG, shared);
+    for (token, piece) in VOCAB.iter().enumerate() {
+        model.embedding[token * dim] = if *piece == "x" { 2.0 } else { 1.0 };
+    }
+    if let Some(wcls) = &mut model.wcls {
+        wcls[VOCAB.iter().position(|&t| t == "y").unwrap() * dim] = 1.0;
+    }
+    match format {
+        Format::Legacy => model.write_legacy(path),
+        Format::V1 => model.write_exported(path, None),
+        Format::Q8_0(group_size) => model.write_exported(path, Some(group_size)),
+        Format::Gguf(ggml_type) => model.write_gguf(path, ggml_type, &VOCAB),
+    }
+}
+
+/// Writes a llama2.c `tokenizer.bin` for `VOCAB`.
+fn write_tokenizer(path: &Path) {
+    let max_token_len = VOCAB.iter().map(|t| t.len()).max().unwrap() as u32;
+    let mut bytes = max_token_len.to_le_bytes().to_vec();
+    for token in VOCAB {
+        bytes.extend(0.0_f32.to_le_bytes());
+        bytes.extend((token.len() as u32).to_le_bytes());
+        bytes.extend(token.as_bytes());
+    }
+    fs::write(path, bytes).unwrap();
+}
+
+/// Runs the binary on `checkpoint` with the test tokenizer, greedily.
+///
+/// GGUF files bring their own vocabulary.
+fn run_checkpoint(checkpoint: &Path) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_llama2-rs"));
+    command.arg(checkpoint);
+    if checkpoint.extension().unwrap() != "gguf" {
+        let tokenizer = checkpoint.with_extension("tokenizer.bin");
+        write_tokenizer(&tokenizer);
+        command.arg("--tokenizer-path").arg(&tokenizer);
+    }
+    command
+        .args(["--temperature", "0", "--steps", "5"])
+        .output()
+        .unwrap()
+}
+
+fn run(shared: bool, format: Format) -> String {
+    let name = format!("{}_{format:?}", if shared { "shared" } else { "unshared" });
+    let extension = match format {
+        Format::Gguf(_) => "gguf",
+        _ => "bin",
+    };
+    let checkpoint = tmp(&format!("{name}.{extension}"));
+    write_checkpoint(&checkpoint, shared, format);
+
+    let output = run_checkpoint(&checkpoint);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn shared_classifier() {
+    assert_eq!(run(true, Format::Legacy).trim_end(), "xxxxx");
+}
+
+#[test]
+fn unshared_classifier() {
+    assert_eq!(run(false, Format::Legacy).trim_end(), "yyyyy");
+}
+
+#[test]
+fn v1_shared_classifier() {
+    assert_eq!(run(true, Format::V1).trim_end(), "xxxxx");
+}
+
+#[test]
+fn v1_unshared_classifier() {
+    assert_eq!(run(false, Format::V1).trim_end(), "yyyyy");
+}
+
+#[test]
+fn q8_0_shared_classifier() {
+    assert_eq!(run(true, Format::Q8_0(2)).trim_end(), "xxxxx");
+}
+
+#[test]
+fn q8_0_unshared_classifier() {
+    assert_eq!(run(false, Format::Q8_0(4)).trim_end(), "yyyyy");
+}
+
+#[test]
+fn gguf_f32() {
+    assert_eq!(run(true, Format::Gguf(0)).trim_end(), "xxxxx");
+    assert_eq!(run(false, Format::Gguf(0)).trim_end(), "yyyyy");
+}
+
+#[test]
+fn gguf_f16() {
+    assert_eq!(run(true, Format::Gguf(1)).trim_end(), "xxxxx");
+    assert_eq!(run(false, Format::Gguf(1)).trim_end(), "yyyyy");
+}
+
+#[test]
+fn gguf_q8_0() {
+    assert_eq!(run(true, Format::Gguf(8)).trim_end(), "xxxxx");
+    assert_eq!(run(false, Format::Gguf(8)).trim_end(), "yyyyy");
+}
+
+#[test]
+fn unknown_version() {
+    let checkpoint = tmp("version3.bin");
+    write_checkpoint(&checkpoint, true, Format::V1);
+    let mut bytes = fs::read(&checkpoint).unwrap();
+    bytes[4] = 3;
+    fs::write(&checkpoint, bytes).unwrap();
+
+    let output = run_checkpoint(&checkpoint);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unsupported checkpoint version 3"),
+        "{stderr}"
+    );
+}
+
+/// Writes a shared legacy checkpoint, applies `edit` to its bytes and returns
+/// the error the binary fails with.
+fn load_error(name: &str, edit: impl FnOnce(&mut Vec<u8>)) -> String {
+    let checkpoint = tmp(name);
+    write_checkpoint(&checkpoint, true, Format::Legacy);
+    let mut bytes = fs::read(&checkpoint).unwrap();
+    edit(&mut bytes);
+    fs::write(&checkpoint, bytes).unwrap();
+
+    let output = run_checkpoint(&checkpoint);
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// Sets field `index` of the legacy header to `value`.
+fn set_header(bytes: &mut [u8], index: usize, value: i32) {
+    bytes[index * 4..][..4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn truncated_header() {
+    let stderr = load_error("truncated_header.bin", |bytes| bytes.truncate(10));
+    assert!(stderr.contains("file is truncated"), "{stderr}");
+}
+
+#[test]
+fn truncated_tensor() {
+    // the last tensor of a shared legacy checkpoint is freq_cis
+    let stderr = load_error("truncated_tensor.bin", |bytes| {
+        bytes.truncate(bytes.len() - 4)
+    });
+    assert!(stderr.contains("file is truncated"), "{stderr}");
+    assert!(stderr.contains("tensor freq_cis needs"), "{stderr}");
+}
+
+#[test]
+fn heads_do_not_divide_dim() {
+    let stderr = load_error("heads_do_not_divide_dim.bin", |bytes| {
+        set_header(bytes, 3, 3)
+    });
+    assert!(
+        stderr.contains("dim 32 is not a multiple of num_heads 3"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn odd_head_size() {
+    // 32 heads of size 1, still a multiple of the 2 kv heads
+    let stderr = load_error("odd_head_size.bin", |bytes| set_header(bytes, 3, 32));
+    assert!(
+        stderr.contains("head size 1 must be even for RoPE"),
+        "{stderr}"
+    );
+}
+
+/// Writes a GGUF file with no tensor data and returns the error opening it.
+fn gguf_error(name: &str, tensor_count: u64, kv_count: u64, body: &[u8]) -> String {
+    let path = tmp(name);
+    let mut bytes = b"GGUF".to_vec();
+    bytes.extend(3_u32.to_le_bytes());
+    bytes.extend(tensor_count.to_le_bytes());
+    bytes.extend(kv_count.to_le_bytes());
+    bytes.extend(body);
+    fs::write(&path, bytes).unwrap();
+    llama2_rs::gguf::Gguf::open(&path)
+        .err()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn gguf_overflowing_dims() {
+    let mut info = 1_u64.to_le_bytes().to_vec();
+    info.push(b'w');
+    info.extend(2_u32.to_le_bytes());
+    info.extend(u64::MAX.to_le_bytes());
+    info.extend(2_u64.to_le_bytes());
+    // f32 at offset 0
+    info.extend(0_u32.to_le_bytes());
+    info.extend(0_u64.to_le_bytes());
+    let error = gguf_error("overflowing_dims.gguf", 1, 0, &info);
+    assert!(error.contains("tensor w has too many values"), "{error}");
+}
+
+#[test]
+fn gguf_nested_arrays() {
+    let mut kv = 1_u64.to_le_bytes().to_vec();
+    kv.push(b'a');
+    // an array of one array of one array..., ten deep, around an empty
+    // array of u32
+    kv.extend(9_u32.to_le_bytes());
+    for _ in 0..10 {
+        kv.extend(9_u32.to_le_bytes());
+        kv.extend(1_u64.to_le_bytes());
+    }
+    kv.extend(4_u32.to_le_bytes());
+    kv.extend(0_u64.to_le_bytes());
+    let error = gguf_error("nested_arrays.gguf", 0, 1, &kv);
+    assert!(error.contains("nests arrays more than 4 deep"), "{error}");
+}
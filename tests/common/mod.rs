@@ -0,0 +1,499 @@
+//! Models shared by the tests, written as llama2.c checkpoints of every
+//! version, as GGUF or as HF safetensors.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use llama2_rs::Transformer;
+use serde_json::json;
+
+pub const DIM: usize = 16;
+pub const HIDDEN_DIM: usize = 24;
+pub const N_LAYERS: usize = 2;
+pub const N_HEADS: usize = 4;
+pub const N_KV_HEADS: usize = 2;
+pub const VOCAB_SIZE: usize = 11;
+pub const SEQ_LEN: usize = 8;
+pub const HEAD_SIZE: usize = DIM / N_HEADS;
+pub const KV_DIM: usize = HEAD_SIZE * N_KV_HEADS;
+
+/// The dimensions of a test model.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub dim: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub vocab_size: usize,
+    pub seq_len: usize,
+}
+
+/// The dimensions of [`Model::random`].
+pub const CONFIG: Config = Config {
+    dim: DIM,
+    hidden_dim: HIDDEN_DIM,
+    n_layers: N_LAYERS,
+    n_heads: N_HEADS,
+    n_kv_heads: N_KV_HEADS,
+    vocab_size: VOCAB_SIZE,
+    seq_len: SEQ_LEN,
+};
+
+impl Config {
+    pub fn head_size(&self) -> usize {
+        self.dim / self.n_heads
+    }
+
+    pub fn kv_dim(&self) -> usize {
+        self.head_size() * self.n_kv_heads
+    }
+
+    /// The llama2.c header, with a negative vocabulary size for an unshared
+    /// classifier.
+    fn header(&self, shared: bool) -> [i32; 7] {
+        let vocab_size = self.vocab_size as i32;
+        [
+            self.dim as i32,
+            self.hidden_dim as i32,
+            self.n_layers as i32,
+            self.n_heads as i32,
+            self.n_kv_heads as i32,
+            if shared { vocab_size } else { -vocab_size },
+            self.seq_len as i32,
+        ]
+    }
+}
+
+/// A path called `name` in the directory for the files written by the tests.
+pub fn tmp(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name)
+}
+
+fn floats(values: &[f32]) -> impl Iterator<Item = u8> + '_ {
+    values.iter().flat_map(|v| v.to_le_bytes())
+}
+
+/// Deterministic pseudo-random floats in `[-1, 1)`.
+pub fn random(len: usize, seed: u64) -> Vec<f32> {
+    let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let r = (state.wrapping_mul(0x2545F4914F6CDD1D) >> 40) as f32 / (1u64 << 24) as f32;
+            r * 2.0 - 1.0
+        })
+        .collect()
+}
+
+/// The weights in llama2.c order and layout, one `Vec` per layer.
+pub struct Model {
+    pub config: Config,
+    pub embedding: Vec<f32>,
+    pub rms_att: Vec<Vec<f32>>,
+    pub wq: Vec<Vec<f32>>,
+    pub wk: Vec<Vec<f32>>,
+    pub wv: Vec<Vec<f32>>,
+    pub wo: Vec<Vec<f32>>,
+    pub rms_ffn: Vec<Vec<f32>>,
+    pub w1: Vec<Vec<f32>>,
+    pub w2: Vec<Vec<f32>>,
+    pub w3: Vec<Vec<f32>>,
+    pub rms_final: Vec<f32>,
+    pub wcls: Option<Vec<f32>>,
+}
+
+impl Model {
+    /// A random model of [`CONFIG`], with every weight representable in f16
+    /// so that half-precision files hold exactly the same model.
+    pub fn random(shared: bool) -> Self {
+        let mut seed = 0;
+        Self::with(CONFIG, shared, |len| {
+            seed += 1;
+            random(len, seed)
+                .into_iter()
+                .map(|v| half::f16::from_f32(v).to_f32())
+                .collect()
+        })
+    }
+
+    /// A model whose matrices are all zero and whose norms are all one, for
+    /// tests to set the few weights they need.
+    pub fn blank(config: Config, shared: bool) -> Self {
+        let mut model = Self::with(config, shared, |len| vec![0.0; len]);
+        for norm in model.rms_att.iter_mut().chain(&mut model.rms_ffn) {
+            norm.fill(1.0);
+        }
+        model.rms_final.fill(1.0);
+        model
+    }
+
+    /// A model whose tensors are made by `tensor(len)`, in llama2.c order.
+    fn with(config: Config, shared: bool, mut tensor: impl FnMut(usize) -> Vec<f32>) -> Self {
+        let Config {
+            dim,
+            hidden_dim,
+            vocab_size,
+            ..
+        } = config;
+        let kv_dim = config.kv_dim();
+        let mut layers = |len| {
+            (0..config.n_layers)
+                .map(|_| tensor(len))
+                .collect::<Vec<_>>()
+        };
+        Self {
+            config,
+            rms_att: layers(dim),
+            wq: layers(dim * dim),
+            wk: layers(kv_dim * dim),
+            wv: layers(kv_dim * dim),
+            wo: layers(dim * dim),
+            rms_ffn: layers(dim),
+            w1: layers(hidden_dim * dim),
+            w2: layers(dim * hidden_dim),
+            w3: layers(hidden_dim * dim),
+            embedding: tensor(vocab_size * dim),
+            rms_final: tensor(dim),
+            wcls: (!shared).then(|| tensor(vocab_size * dim)),
+        }
+    }
+
+    /// The per-layer weight matrices in llama2.c order.
+    fn matrices(&self) -> [&Vec<Vec<f32>>; 7] {
+        [
+            &self.wq, &self.wk, &self.wv, &self.wo, &self.w1, &self.w2, &self.w3,
+        ]
+    }
+
+    pub fn write_legacy(&self, path: &Path) {
+        let config = &self.config;
+        let header = config.header(self.wcls.is_none());
+        let mut bytes = header
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.extend(floats(&self.embedding));
+        for tensor in [
+            &self.rms_att,
+            &self.wq,
+            &self.wk,
+            &self.wv,
+            &self.wo,
+            &self.rms_ffn,
+            &self.w1,
+            &self.w2,
+            &self.w3,
+        ] {
+            tensor.iter().for_each(|layer| bytes.extend(floats(layer)));
+        }
+        bytes.extend(floats(&self.rms_final));
+        // freq_cis_real, freq_cis_imag
+        bytes.extend(floats(&vec![0.0; config.seq_len * config.head_size()]));
+        if let Some(wcls) = &self.wcls {
+            bytes.extend(floats(wcls));
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    /// Writes the model like llama2.c's `export.py`, version 1 in fp32, or
+    /// version 2 with the matrices in Q8_0 groups of `group_size`.
+    pub fn write_exported(&self, path: &Path, group_size: Option<usize>) {
+        let version = if group_size.is_some() { 2_i32 } else { 1 };
+        let mut bytes = 0x616b3432_u32.to_le_bytes().to_vec();
+        bytes.extend(version.to_le_bytes());
+        // the vocabulary size is positive, a flag tells whether the
+        // classifier is shared
+        let header = self.config.header(true);
+        bytes.extend(header.iter().flat_map(|v| v.to_le_bytes()));
+        bytes.push(self.wcls.is_none() as u8);
+        if let Some(group_size) = group_size {
+            bytes.extend((group_size as i32).to_le_bytes());
+        }
+        bytes.resize(256, 0);
+        for norm in self.rms_att.iter().chain(&self.rms_ffn) {
+            bytes.extend(floats(norm));
+        }
+        bytes.extend(floats(&self.rms_final));
+
+        let matrix = |v: &[f32]| {
+            let Some(group_size) = group_size else {
+                return floats(v).collect();
+            };
+            let mut q = Vec::new();
+            let mut s = Vec::new();
+            for group in v.chunks(group_size) {
+                let scale = group.iter().fold(0.0_f32, |m, v| m.max(v.abs())) / 127.0;
+                q.extend(group.iter().map(|v| {
+                    if scale == 0.0 {
+                        0
+                    } else {
+                        (v / scale).round() as i8 as u8
+                    }
+                }));
+                s.extend(scale.to_le_bytes());
+            }
+            [q, s].concat()
+        };
+        bytes.extend(matrix(&self.embedding));
+        for tensor in self.matrices() {
+            tensor.iter().for_each(|layer| bytes.extend(matrix(layer)));
+        }
+        if let Some(wcls) = &self.wcls {
+            bytes.extend(matrix(wcls));
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    /// Writes the model as a llama.cpp GGUF file with the matrices stored as
+    /// `ggml_type`, 0 for f32, 1 for f16 or 8 for Q8_0, and `vocab` as the
+    /// tokenizer.
+    pub fn write_gguf(&self, path: &Path, ggml_type: u32, vocab: &[&str]) {
+        let config = &self.config;
+        let string = |s: &str| [&(s.len() as u64).to_le_bytes()[..], s.as_bytes()].concat();
+        let mut metadata = Vec::new();
+        let mut kv = |key: &str, ty: u32, value: Vec<u8>| {
+            metadata.push([string(key), ty.to_le_bytes().to_vec(), value].concat())
+        };
+        kv("general.architecture", 8, string("llama"));
+        for (key, value) in [
+            ("llama.context_length", config.seq_len),
+            ("llama.embedding_length", config.dim),
+            ("llama.block_count", config.n_layers),
+            ("llama.feed_forward_length", config.hidden_dim),
+            ("llama.attention.head_count", config.n_heads),
+            ("llama.attention.head_count_kv", config.n_kv_heads),
+        ] {
+            kv(key, 4, (value as u32).to_le_bytes().to_vec());
+        }
+        kv("tokenizer.ggml.model", 8, string("llama"));
+        let mut tokens = 8_u32.to_le_bytes().to_vec();
+        tokens.extend((vocab.len() as u64).to_le_bytes());
+        for token in vocab {
+            tokens.extend(string(&token.replace(' ', "\u{2581}")));
+        }
+        kv("tokenizer.ggml.tokens", 9, tokens);
+
+        let (dim, hidden_dim, kv_dim) = (config.dim, config.hidden_dim, config.kv_dim());
+        // (name, dims innermost first, values, ggml type)
+        let mut tensors = vec![
+            (
+                "token_embd.weight".to_string(),
+                vec![dim, config.vocab_size],
+                &self.embedding,
+                ggml_type,
+            ),
+            ("output_norm.weight".into(), vec![dim], &self.rms_final, 0),
+        ];
+        for l in 0..config.n_layers {
+            for (name, dims, values, ty) in [
+                ("attn_norm", vec![dim], &self.rms_att[l], 0),
+                ("ffn_norm", vec![dim], &self.rms_ffn[l], 0),
+                ("attn_q", vec![dim, dim], &self.wq[l], ggml_type),
+                ("attn_k", vec![dim, kv_dim], &self.wk[l], ggml_type),
+                ("attn_v", vec![dim, kv_dim], &self.wv[l], ggml_type),
+                ("attn_output", vec![dim, dim], &self.wo[l], ggml_type),
+                ("ffn_gate", vec![dim, hidden_dim], &self.w1[l], ggml_type),
+                ("ffn_down", vec![hidden_dim, dim], &self.w2[l], ggml_type),
+                ("ffn_up", vec![dim, hidden_dim], &self.w3[l], ggml_type),
+            ] {
+                tensors.push((format!("blk.{l}.{name}.weight"), dims, values, ty));
+            }
+        }
+        if let Some(wcls) = &self.wcls {
+            tensors.push((
+                "output.weight".into(),
+                vec![dim, config.vocab_size],
+                wcls,
+                ggml_type,
+            ));
+        }
+
+        let tensor_count = tensors.len();
+        let mut infos = Vec::new();
+        let mut data = Vec::new();
+        for (name, dims, values, ty) in tensors {
+            data.resize(data.len().next_multiple_of(32), 0);
+            infos.extend(string(&name));
+            infos.extend((dims.len() as u32).to_le_bytes());
+            infos.extend(dims.iter().flat_map(|&d| (d as u64).to_le_bytes()));
+            infos.extend(ty.to_le_bytes());
+            infos.extend((data.len() as u64).to_le_bytes());
+            match ty {
+                0 => data.extend(floats(values)),
+                1 => data.extend(
+                    values
+                        .iter()
+                        .flat_map(|&v| half::f16::from_f32(v).to_le_bytes()),
+                ),
+                8 => {
+                    for block in values.chunks(32) {
+                        let max = block.iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+                        let d = half::f16::from_f32(max / 127.0);
+                        data.extend(d.to_le_bytes());
+                        data.extend(block.iter().map(|&v| {
+                            if max == 0.0 {
+                                0
+                            } else {
+                                (v / d.to_f32()).round() as i8 as u8
+                            }
+                        }));
+                    }
+                }
+                ty => unreachable!("ggml type {ty}"),
+            }
+        }
+
+        let mut bytes = b"GGUF".to_vec();
+        bytes.extend(3_u32.to_le_bytes());
+        bytes.extend((tensor_count as u64).to_le_bytes());
+        bytes.extend((metadata.len() as u64).to_le_bytes());
+        bytes.extend(metadata.concat());
+        bytes.extend(infos);
+        bytes.resize(bytes.len().next_multiple_of(32), 0);
+        bytes.extend(data);
+        fs::write(path, bytes).unwrap();
+    }
+
+    /// The tensors under their HF names, with q and k permuted like HF's
+    /// conversion script does.
+    fn hf_tensors(&self) -> Vec<(String, Vec<usize>, Vec<f32>)> {
+        let config = &self.config;
+        let (dim, hidden_dim, kv_dim) = (config.dim, config.hidden_dim, config.kv_dim());
+        let permute = |w| permute(w, config);
+        let mut tensors = vec![
+            (
+                "model.embed_tokens.weight".to_string(),
+                vec![config.vocab_size, dim],
+                self.embedding.clone(),
+            ),
+            (
+                "model.norm.weight".into(),
+                vec![dim],
+                self.rms_final.clone(),
+            ),
+        ];
+        if let Some(wcls) = &self.wcls {
+            tensors.push((
+                "lm_head.weight".into(),
+                vec![config.vocab_size, dim],
+                wcls.clone(),
+            ));
+        }
+        for l in 0..config.n_layers {
+            for (name, shape, values) in [
+                ("input_layernorm", vec![dim], self.rms_att[l].clone()),
+                ("self_attn.q_proj", vec![dim, dim], permute(&self.wq[l])),
+                ("self_attn.k_proj", vec![kv_dim, dim], permute(&self.wk[l])),
+                ("self_attn.v_proj", vec![kv_dim, dim], self.wv[l].clone()),
+                ("self_attn.o_proj", vec![dim, dim], self.wo[l].clone()),
+                (
+                    "post_attention_layernorm",
+                    vec![dim],
+                    self.rms_ffn[l].clone(),
+                ),
+                ("mlp.gate_proj", vec![hidden_dim, dim], self.w1[l].clone()),
+                ("mlp.down_proj", vec![dim, hidden_dim], self.w2[l].clone()),
+                ("mlp.up_proj", vec![hidden_dim, dim], self.w3[l].clone()),
+            ] {
+                tensors.push((format!("model.layers.{l}.{name}.weight"), shape, values));
+            }
+        }
+        tensors
+    }
+
+    /// Writes `config.json` and the tensors in `dtype` to `dir`, split into
+    /// `shards` files with an index if there is more than one.
+    pub fn write_hf(&self, dir: &Path, dtype: &str, shards: usize) {
+        fs::create_dir_all(dir).unwrap();
+        let config = &self.config;
+        let config = json!({
+            "architectures": ["LlamaForCausalLM"],
+            "model_type": "llama",
+            "hidden_size": config.dim,
+            "intermediate_size": config.hidden_dim,
+            "num_hidden_layers": config.n_layers,
+            "num_attention_heads": config.n_heads,
+            "num_key_value_heads": config.n_kv_heads,
+            "vocab_size": config.vocab_size,
+            "max_position_embeddings": config.seq_len,
+            "rms_norm_eps": 1e-5,
+            "tie_word_embeddings": self.wcls.is_none(),
+        });
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        let tensors = self.hf_tensors();
+        let per_shard = tensors.len().div_ceil(shards);
+        let mut weight_map = serde_json::Map::new();
+        for (i, tensors) in tensors.chunks(per_shard).enumerate() {
+            let name = if shards == 1 {
+                "model.safetensors".to_string()
+            } else {
+                format!("model-{:05}-of-{shards:05}.safetensors", i + 1)
+            };
+            let mut header = serde_json::Map::new();
+            header.insert("__metadata__".into(), json!({"format": "pt"}));
+            let mut data = Vec::new();
+            for (tensor, shape, values) in tensors {
+                let begin = data.len();
+                for &v in values {
+                    match dtype {
+                        "F32" => data.extend(v.to_le_bytes()),
+                        "F16" => data.extend(half::f16::from_f32(v).to_le_bytes()),
+                        "BF16" => data.extend(half::bf16::from_f32(v).to_le_bytes()),
+                        dtype => unreachable!("dtype {dtype}"),
+                    }
+                }
+                header.insert(
+                    tensor.clone(),
+                    json!({"dtype": dtype, "shape": shape, "data_offsets": [begin, data.len()]}),
+                );
+                weight_map.insert(tensor.clone(), json!(name));
+            }
+            let mut header = serde_json::Value::Object(header).to_string().into_bytes();
+            header.resize(header.len().next_multiple_of(8), b' ');
+            let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+            bytes.extend(header);
+            bytes.extend(data);
+            fs::write(dir.join(name), bytes).unwrap();
+        }
+        if shards > 1 {
+            let index = json!({"metadata": {}, "weight_map": weight_map});
+            fs::write(dir.join("model.safetensors.index.json"), index.to_string()).unwrap();
+        }
+    }
+}
+
+/// HF's permutation for rotate-half RoPE: value `2 * i + j` of each head
+/// moves to `j * head_size / 2 + i`.
+fn permute(w: &[f32], config: &Config) -> Vec<f32> {
+    let (dim, head_size) = (config.dim, config.head_size());
+    let mut out = vec![0.0; w.len()];
+    for (r, row) in w.chunks_exact(dim).enumerate() {
+        let (head, i, j) = (r / head_size, r % head_size / 2, r % 2);
+        let target = head * head_size + j * head_size / 2 + i;
+        out[target * dim..][..dim].copy_from_slice(row);
+    }
+    out
+}
+
+/// The logits of every position of a fixed prompt.
+pub fn forward_all(transformer: &mut Transformer) -> Vec<Vec<f32>> {
+    (0..SEQ_LEN)
+        .map(|pos| {
+            transformer
+                .forward((pos * 7 % VOCAB_SIZE) as u32, pos as u32)
+                .to_vec()
+        })
+        .collect()
+}
+
+/// The logits of every position of a fixed prompt, for the model at
+/// `checkpoint`.
+pub fn logits(checkpoint: &Path) -> Vec<Vec<f32>> {
+    forward_all(&mut Transformer::new(checkpoint).unwrap())
+}
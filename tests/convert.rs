@@ -0,0 +1,158 @@
+//! Exports a random model to every llama2.c checkpoint version and checks
+//! that the files load back to the same model.
+
+use std::fs;
+use std::process::Command;
+
+use common::{forward_all, logits, tmp, Config, Model, CONFIG, DIM, HEAD_SIZE, SEQ_LEN};
+use llama2_rs::transformer::{CheckpointVersion, WeightType};
+use llama2_rs::Transformer;
+
+mod common;
+
+#[test]
+fn legacy_round_trip() {
+    for shared in [true, false] {
+        let original = tmp(&format!("convert_legacy_{shared}.bin"));
+        Model::random(shared).write_legacy(&original);
+        let exported = original.with_extension("out.bin");
+        Transformer::new(&original)
+            .unwrap()
+            .export(&exported, CheckpointVersion::Legacy)
+            .unwrap();
+
+        // only freq_cis differs, the test model leaves it zeroed
+        let (original_bytes, exported_bytes) =
+            (fs::read(&original).unwrap(), fs::read(&exported).unwrap());
+        assert_eq!(original_bytes.len(), exported_bytes.len());
+        let freq_cis_len = SEQ_LEN * HEAD_SIZE * 4;
+        let freq_cis_end = if shared {
+            original_bytes.len()
+        } else {
+            original_bytes.len() - common::VOCAB_SIZE * DIM * 4
+        };
+        let freq_cis = freq_cis_end - freq_cis_len..freq_cis_end;
+        assert_eq!(
+            original_bytes[..freq_cis.start],
+            exported_bytes[..freq_cis.start]
+        );
+        assert_eq!(
+            original_bytes[freq_cis.end..],
+            exported_bytes[freq_cis.end..]
+        );
+        // position 0 has no rotation
+        let cos0 = f32::from_le_bytes(exported_bytes[freq_cis.start..][..4].try_into().unwrap());
+        assert_eq!(cos0, 1.0);
+
+        assert_eq!(logits(&exported), logits(&original));
+    }
+}
+
+#[test]
+fn v1_from_safetensors() {
+    let dir = tmp("convert_v1_safetensors");
+    let model = Model::random(false);
+    model.write_hf(&dir, "F16", 1);
+    let legacy = dir.with_extension("bin");
+    model.write_legacy(&legacy);
+
+    let exported = dir.with_extension("v1.bin");
+    Transformer::new(&dir)
+        .unwrap()
+        .export(&exported, CheckpointVersion::V1)
+        .unwrap();
+    let bytes = fs::read(&exported).unwrap();
+    assert_eq!(bytes[..4], 0x616b3432_u32.to_le_bytes());
+    assert_eq!(bytes[4..8], 1_i32.to_le_bytes());
+    assert_eq!(logits(&exported), logits(&legacy));
+}
+
+#[test]
+fn v2_matches_quantized_load() {
+    let legacy = tmp("convert_v2.bin");
+    Model::random(true).write_legacy(&legacy);
+    let matrix_type = WeightType::Q8_0 { group_size: 8 };
+
+    let exported = legacy.with_extension("v2.bin");
+    Transformer::new(&legacy)
+        .unwrap()
+        .export(&exported, CheckpointVersion::V2 { group_size: 8 })
+        .unwrap();
+    let mut v2 = Transformer::new(&exported).unwrap();
+    assert_eq!(v2.weights.matrix_type(), matrix_type);
+    // quantizing on load gives the same weights
+    let mut quantized = Transformer::with_weight_type(&legacy, matrix_type).unwrap();
+    assert_eq!(forward_all(&mut v2), forward_all(&mut quantized));
+
+    // requantizing with another group size goes through fp32
+    let requantized = legacy.with_extension("v2_4.bin");
+    v2.export(&requantized, CheckpointVersion::V2 { group_size: 4 })
+        .unwrap();
+    let v2 = Transformer::new(&requantized).unwrap();
+    assert_eq!(v2.weights.matrix_type(), WeightType::Q8_0 { group_size: 4 });
+}
+
+#[test]
+fn v2_group_size() {
+    let legacy = tmp("convert_v2_group_size.bin");
+    Model::random(true).write_legacy(&legacy);
+    let error = Transformer::new(&legacy)
+        .unwrap()
+        .export(
+            legacy.with_extension("v2.bin"),
+            CheckpointVersion::V2 { group_size: 5 },
+        )
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("group size 5 must divide"), "{error}");
+}
+
+/// Runs `convert` on a legacy checkpoint of `model` with `flags` and returns
+/// its error.
+fn convert_error(name: &str, model: &Model, flags: &[&str]) -> String {
+    let legacy = tmp(&format!("{name}.bin"));
+    model.write_legacy(&legacy);
+    let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .arg("convert")
+        .arg(&legacy)
+        .arg(legacy.with_extension("out.bin"))
+        .args(flags)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn group_size_needs_v2() {
+    let model = Model::random(true);
+    let error = convert_error("convert_group_size_v1", &model, &["--group-size", "8"]);
+    assert!(
+        error.contains("--group-size only applies to --version 2"),
+        "{error}"
+    );
+}
+
+#[test]
+fn group_size_must_divide() {
+    let model = Model::random(true);
+    let flags = ["--version", "2", "--group-size", "5"];
+    let error = convert_error("convert_group_size_5", &model, &flags);
+    assert!(
+        error.contains("group size 5 must divide dim 16 and hidden_dim 24"),
+        "{error}"
+    );
+
+    // an odd hidden_dim leaves no default group size but single values
+    let config = Config {
+        hidden_dim: 25,
+        ..CONFIG
+    };
+    let model = Model::blank(config, true);
+    let error = convert_error("convert_odd_hidden_dim", &model, &["--version", "2"]);
+    assert!(
+        error.contains("no group size from 64 down to 2 divides dim 16 and hidden_dim 25"),
+        "{error}"
+    );
+}
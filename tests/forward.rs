@@ -0,0 +1,119 @@
+//! Checks the forward pass on the random test model against a
+//! straightforward scalar implementation of Llama-2.
+
+use common::{forward_all, Model, DIM, HEAD_SIZE, N_HEADS, N_KV_HEADS, N_LAYERS};
+use common::{tmp, SEQ_LEN, VOCAB_SIZE};
+use llama2_rs::Transformer;
+
+mod common;
+
+/// `w` times `x`, with `w` stored row by row.
+fn matmul(w: &[f32], x: &[f32]) -> Vec<f32> {
+    w.chunks_exact(x.len()).map(|row| dot(row, x)).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn rms_norm(x: &[f32], weight: &[f32]) -> Vec<f32> {
+    let scale = 1.0 / (dot(x, x) / x.len() as f32 + 1e-5).sqrt();
+    x.iter().zip(weight).map(|(x, w)| w * x * scale).collect()
+}
+
+fn add(x: &mut [f32], y: &[f32]) {
+    x.iter_mut().zip(y).for_each(|(x, y)| *x += y);
+}
+
+/// Rotates each pair of values of each head by its RoPE angle at `pos`.
+fn rope(v: &mut [f32], pos: usize) {
+    for i in (0..v.len()).step_by(2) {
+        let freq = 1.0 / 10000f32.powf((i % HEAD_SIZE) as f32 / HEAD_SIZE as f32);
+        let (sin, cos) = (pos as f32 * freq).sin_cos();
+        let (v0, v1) = (v[i], v[i + 1]);
+        v[i] = v0 * cos - v1 * sin;
+        v[i + 1] = v0 * sin + v1 * cos;
+    }
+}
+
+/// The logits at every position of `tokens`.
+fn reference(model: &Model, tokens: &[usize]) -> Vec<Vec<f32>> {
+    let mut keys = vec![Vec::<Vec<f32>>::new(); N_LAYERS];
+    let mut values = vec![Vec::<Vec<f32>>::new(); N_LAYERS];
+    let mut logits = Vec::new();
+    for (pos, &token) in tokens.iter().enumerate() {
+        let mut x = model.embedding[token * DIM..][..DIM].to_vec();
+        for l in 0..N_LAYERS {
+            let xb = rms_norm(&x, &model.rms_att[l]);
+            let mut q = matmul(&model.wq[l], &xb);
+            let mut k = matmul(&model.wk[l], &xb);
+            rope(&mut q, pos);
+            rope(&mut k, pos);
+            keys[l].push(k);
+            values[l].push(matmul(&model.wv[l], &xb));
+
+            let mut attention = vec![0.0; DIM];
+            for h in 0..N_HEADS {
+                // heads share their key and value head in groups
+                let kv = h / (N_HEADS / N_KV_HEADS) * HEAD_SIZE;
+                let q = &q[h * HEAD_SIZE..][..HEAD_SIZE];
+                let scores = keys[l]
+                    .iter()
+                    .map(|k| dot(q, &k[kv..][..HEAD_SIZE]) / (HEAD_SIZE as f32).sqrt())
+                    .collect::<Vec<_>>();
+                let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let weights = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+                let sum = weights.iter().sum::<f32>();
+                for (weight, v) in weights.iter().zip(&values[l]) {
+                    for i in 0..HEAD_SIZE {
+                        attention[h * HEAD_SIZE + i] += weight / sum * v[kv + i];
+                    }
+                }
+            }
+            add(&mut x, &matmul(&model.wo[l], &attention));
+
+            let xb = rms_norm(&x, &model.rms_ffn[l]);
+            let gate = matmul(&model.w1[l], &xb);
+            let up = matmul(&model.w3[l], &xb);
+            let hidden = gate
+                .iter()
+                .zip(&up)
+                .map(|(g, u)| g / (1.0 + (-g).exp()) * u)
+                .collect::<Vec<_>>();
+            add(&mut x, &matmul(&model.w2[l], &hidden));
+        }
+        let x = rms_norm(&x, &model.rms_final);
+        logits.push(matmul(model.wcls.as_ref().unwrap_or(&model.embedding), &x));
+    }
+    logits
+}
+
+fn check(name: &str, shared: bool) {
+    let path = tmp(name);
+    let model = Model::random(shared);
+    model.write_legacy(&path);
+    let actual = forward_all(&mut Transformer::new(&path).unwrap());
+    // the same prompt as `forward_all`
+    let tokens = (0..SEQ_LEN)
+        .map(|pos| pos * 7 % VOCAB_SIZE)
+        .collect::<Vec<_>>();
+    let expected = reference(&model, &tokens);
+    for (pos, (actual, expected)) in actual.iter().zip(&expected).enumerate() {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() < 1e-4,
+                "position {pos}: {actual:?} != {expected:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn matches_reference_shared() {
+    check("forward_shared.bin", true);
+}
+
+#[test]
+fn matches_reference_unshared() {
+    check("forward_unshared.bin", false);
+}
@@ -0,0 +1,163 @@
+//! Generates greedily from the random test model with a tokenizer whose
+//! stop tokens can be chosen freely.
+
+use common::{tmp, Model, SEQ_LEN, VOCAB_SIZE};
+use llama2_rs::tokenizer::{utok, Tokenize};
+use llama2_rs::{generate, GeneratedToken, Generator, Sampler, Transformer};
+
+mod common;
+
+/// Token 1 starts every prompt but the empty one, which encodes to no token
+/// like with a tokenizer without BOS. The letters `a` to `h` are tokens 3 to
+/// 10. They decode to themselves, except `h`, the first byte of a three-byte
+/// character.
+struct TestTokenizer {
+    bos: Option<utok>,
+    eos: Option<utok>,
+}
+
+const PIECES: [&[u8]; VOCAB_SIZE] = [
+    b"?", b"", b"", b"a", b"b", b"c", b"d", b"e", b"f", b"g", b"\xe4",
+];
+
+impl Tokenize for TestTokenizer {
+    fn vocab_size(&self) -> usize {
+        VOCAB_SIZE
+    }
+
+    fn bos(&self) -> Option<utok> {
+        self.bos
+    }
+
+    fn eos(&self) -> Option<utok> {
+        self.eos
+    }
+
+    fn encode(&self, text: &str, _bos: bool, _eos: bool) -> Vec<utok> {
+        if text.is_empty() {
+            return Vec::new();
+        }
+        let mut tokens = vec![1];
+        tokens.extend(text.bytes().map(|c| (c - b'a') as utok + 3));
+        tokens
+    }
+
+    fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        self.encode(text, bos, eos)
+    }
+
+    fn decode(&self, _token: utok, next: utok) -> &[u8] {
+        PIECES[next as usize]
+    }
+
+    fn decode_first(&self, token: utok) -> &[u8] {
+        self.decode(token, token)
+    }
+
+    fn piece(&self, token: utok) -> &str {
+        std::str::from_utf8(PIECES[token as usize]).unwrap_or("")
+    }
+}
+
+fn model() -> Transformer {
+    let path = tmp("generator.bin");
+    Model::random(true).write_legacy(&path);
+    Transformer::new(path).unwrap()
+}
+
+/// The tokens generated greedily after `prompt`.
+fn run(tokenizer: &TestTokenizer, prompt: &str, steps: u32) -> Vec<GeneratedToken> {
+    let mut transformer = model();
+    let mut sampler = Sampler::new(VOCAB_SIZE as u32, 0.0, 0.9, 1);
+    Generator::new(&mut transformer, tokenizer, &mut sampler, prompt, steps)
+        .unwrap()
+        .collect()
+}
+
+const NO_STOP: TestTokenizer = TestTokenizer {
+    bos: None,
+    eos: None,
+};
+
+#[test]
+fn prompt_then_samples() {
+    let tokens = run(&NO_STOP, "cab", 6);
+    assert_eq!(tokens.len(), 6);
+    let ids = tokens.iter().map(|t| t.token).collect::<Vec<_>>();
+    assert_eq!(ids[..3], [5, 3, 4]);
+    let positions = tokens.iter().map(|t| t.pos).collect::<Vec<_>>();
+    assert_eq!(positions, [1, 2, 3, 4, 5, 6]);
+    assert_eq!(tokens[0].piece, "c");
+
+    // the log-probabilities are those of the logits at the previous position
+    let mut transformer = model();
+    let mut prev = 1;
+    for (pos, token) in tokens.iter().enumerate() {
+        let logits = transformer.forward(prev, pos as u32);
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f64 = logits.iter().map(|&l| ((l - max) as f64).exp()).sum();
+        let expected = (logits[token.token as usize] - max) as f64 - sum.ln();
+        assert!(
+            (token.logprob as f64 - expected).abs() < 1e-5,
+            "{} {expected}",
+            token.logprob
+        );
+        prev = token.token;
+    }
+}
+
+#[test]
+fn steps_are_clamped() {
+    // 0 and anything beyond the context length use the whole context
+    assert_eq!(run(&NO_STOP, "a", 0).len(), SEQ_LEN);
+    assert_eq!(run(&NO_STOP, "a", 100).len(), SEQ_LEN);
+    // a prompt longer than the context is cut off
+    assert_eq!(run(&NO_STOP, "abcdefgabcdefg", 0).len(), SEQ_LEN);
+}
+
+#[test]
+fn stops_on_bos_and_eos() {
+    let tokens = run(&NO_STOP, "a", 0);
+    let ids = tokens.iter().map(|t| t.token).collect::<Vec<_>>();
+    // stop on the first sampled token, after the prompt
+    let stop = ids[1];
+    let stopped = ids.iter().position(|&id| id == stop).unwrap();
+    for tokenizer in [
+        TestTokenizer {
+            bos: Some(stop),
+            eos: None,
+        },
+        TestTokenizer {
+            bos: None,
+            eos: Some(stop),
+        },
+    ] {
+        let tokens = run(&tokenizer, "a", 0);
+        assert_eq!(tokens.len(), stopped);
+    }
+}
+
+#[test]
+fn generate_flushes_incomplete_characters() {
+    let mut transformer = model();
+    let mut sampler = Sampler::new(VOCAB_SIZE as u32, 0.0, 0.9, 1);
+    let mut pieces = Vec::new();
+    // the prompt token 10 is the only one yielded before the steps run out
+    let pos = generate(&mut transformer, &NO_STOP, &mut sampler, "h", 1, |piece| {
+        pieces.push(piece.to_string())
+    })
+    .unwrap();
+    assert_eq!(pos, 1);
+    assert_eq!(pieces, ["", "\u{fffd}"]);
+}
+
+#[test]
+fn empty_prompt_without_bos() {
+    let mut transformer = model();
+    let mut sampler = Sampler::new(VOCAB_SIZE as u32, 0.0, 0.9, 1);
+    let error = Generator::new(&mut transformer, &NO_STOP, &mut sampler, "", 0)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("the prompt is empty"), "{error}");
+}
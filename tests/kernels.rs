@@ -0,0 +1,173 @@
+//! Checks every SIMD kernel supported by the CPU against the scalar reference.
+
+use half::{bf16, f16};
+use llama2_rs::kernels::{Isa, QuantizedVec};
+
+/// Deterministic pseudo-random floats in `[-scale, scale)`.
+fn random(len: usize, seed: u64, scale: f32) -> Vec<f32> {
+    let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let r = (state.wrapping_mul(0x2545F4914F6CDD1D) >> 40) as f32 / (1u64 << 24) as f32;
+            (r * 2.0 - 1.0) * scale
+        })
+        .collect()
+}
+
+fn assert_close(isa: Isa, kernel: &str, expected: &[f32], actual: &[f32]) {
+    assert_eq!(expected.len(), actual.len());
+    for (i, (&e, &a)) in expected.iter().zip(actual).enumerate() {
+        assert!(
+            (e - a).abs() <= 1e-5 + 1e-4 * e.abs(),
+            "{isa:?} {kernel}[{i}]: expected {e}, got {a}"
+        );
+    }
+}
+
+/// Sizes around the vector widths, to cover the remainder loops.
+const SIZES: [usize; 9] = [1, 3, 8, 15, 16, 17, 64, 100, 288];
+
+#[test]
+fn matmul() {
+    for isa in Isa::supported() {
+        for (seed, &n) in SIZES.iter().enumerate() {
+            let d = 37;
+            let x = random(n, seed as u64, 1.0);
+            let w = random(n * d, seed as u64 + 100, 1.0);
+            let mut expected = vec![0.0; d];
+            let mut actual = vec![0.0; d];
+            Isa::Scalar.matmul(&x, &w, &mut expected, n, d);
+            isa.matmul(&x, &w, &mut actual, n, d);
+            assert_close(isa, "matmul", &expected, &actual);
+        }
+    }
+}
+
+#[test]
+fn matmul_parallel() {
+    // large enough to be split over the worker pool
+    let (n, d) = (512, 300);
+    let x = random(n, 1, 1.0);
+    let w = random(n * d, 2, 1.0);
+    for isa in Isa::supported() {
+        let mut expected = vec![0.0; d];
+        let mut actual = vec![0.0; d];
+        Isa::Scalar.matmul(&x, &w, &mut expected, n, d);
+        isa.matmul(&x, &w, &mut actual, n, d);
+        assert_close(isa, "matmul", &expected, &actual);
+    }
+}
+
+#[test]
+fn matmul_q8() {
+    for isa in Isa::supported() {
+        for (seed, &group_size) in [1, 2, 4, 8, 16, 32, 64].iter().enumerate() {
+            for (n, d) in [(64, 37), (512, 300)] {
+                let x = random(n, seed as u64, 1.0);
+                let w = random(n * d, seed as u64 + 100, 1.0);
+                let (mut xq, mut wq) = (QuantizedVec::default(), QuantizedVec::default());
+                let (xq, wq) = (xq.quantize(&x, group_size), wq.quantize(&w, group_size));
+
+                // the quantized matmul is the fp32 one over the dequantized operands
+                let mut x = vec![0.0; n];
+                let mut w = vec![0.0; n * d];
+                xq.dequantize(&mut x);
+                wq.dequantize(&mut w);
+                let mut expected = vec![0.0; d];
+                let mut actual = vec![0.0; d];
+                Isa::Scalar.matmul(&x, &w, &mut expected, n, d);
+                isa.matmul_q8(xq, wq, &mut actual, n, d);
+                assert_close(isa, "matmul_q8", &expected, &actual);
+            }
+        }
+    }
+}
+
+#[test]
+fn matmul_half() {
+    for isa in Isa::supported() {
+        for (seed, &n) in SIZES.iter().enumerate() {
+            // with 300 rows the larger sizes are split over the worker pool
+            for d in [37, 300] {
+                let x = random(n, seed as u64, 1.0);
+                let w = random(n * d, seed as u64 + 100, 1.0);
+
+                // the half matmuls are the fp32 one over the upcast weights
+                let w16 = w.iter().map(|&v| f16::from_f32(v)).collect::<Vec<_>>();
+                let w = w16.iter().map(|v| v.to_f32()).collect::<Vec<_>>();
+                let mut expected = vec![0.0; d];
+                let mut actual = vec![0.0; d];
+                Isa::Scalar.matmul(&x, &w, &mut expected, n, d);
+                isa.matmul_f16(&x, &w16, &mut actual, n, d);
+                assert_close(isa, "matmul_f16", &expected, &actual);
+
+                let wb16 = w.iter().map(|&v| bf16::from_f32(v)).collect::<Vec<_>>();
+                let w = wb16.iter().map(|v| v.to_f32()).collect::<Vec<_>>();
+                Isa::Scalar.matmul(&x, &w, &mut expected, n, d);
+                isa.matmul_bf16(&x, &wb16, &mut actual, n, d);
+                assert_close(isa, "matmul_bf16", &expected, &actual);
+            }
+        }
+    }
+}
+
+#[test]
+fn quantize() {
+    let x = random(256, 7, 3.0);
+    for group_size in [1, 4, 32, 256] {
+        let mut xq = QuantizedVec::default();
+        let xq = xq.quantize(&x, group_size);
+        let mut y = vec![0.0; x.len()];
+        xq.dequantize(&mut y);
+        for (i, (&x, &y)) in x.iter().zip(&y).enumerate() {
+            // rounding is off by at most half a step of the group
+            let step = xq.s[i / group_size];
+            assert!((x - y).abs() <= step / 2.0 + 1e-6, "[{i}]: {x} became {y}");
+        }
+    }
+}
+
+#[test]
+fn rms_norm() {
+    for isa in Isa::supported() {
+        for (seed, &size) in SIZES.iter().enumerate() {
+            let x = random(size, seed as u64, 4.0);
+            let weight = random(size, seed as u64 + 100, 1.0);
+            let mut expected = vec![0.0; size];
+            let mut actual = vec![0.0; size];
+            Isa::Scalar.rms_norm(&x, &weight, &mut expected, size);
+            isa.rms_norm(&x, &weight, &mut actual, size);
+            assert_close(isa, "rms_norm", &expected, &actual);
+        }
+    }
+}
+
+#[test]
+fn softmax() {
+    for isa in Isa::supported() {
+        for (seed, &size) in SIZES.iter().enumerate() {
+            let mut expected = random(size, seed as u64, 30.0);
+            let mut actual = expected.clone();
+            Isa::Scalar.softmax(&mut expected, size);
+            isa.softmax(&mut actual, size);
+            assert_close(isa, "softmax", &expected, &actual);
+        }
+    }
+}
+
+#[test]
+fn swiglu() {
+    for isa in Isa::supported() {
+        for (seed, &size) in SIZES.iter().enumerate() {
+            let mut expected = random(size, seed as u64, 20.0);
+            let mut actual = expected.clone();
+            let y = random(size, seed as u64 + 100, 2.0);
+            Isa::Scalar.swiglu(&mut expected, &y, size);
+            isa.swiglu(&mut actual, &y, size);
+            assert_close(isa, "swiglu", &expected, &actual);
+        }
+    }
+}
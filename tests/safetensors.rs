@@ -0,0 +1,117 @@
+//! Loads a random model both as a legacy llama2.c checkpoint and as HF
+//! safetensors, and checks that both give the same logits.
+
+use std::fs;
+
+use common::{forward_all, logits, tmp, Model};
+use llama2_rs::transformer::WeightType;
+use llama2_rs::Transformer;
+
+mod common;
+
+fn check(name: &str, shared: bool, dtype: &str, shards: usize) {
+    let dir = tmp(&format!("safetensors_{name}"));
+    let model = Model::random(shared);
+    let legacy = dir.with_extension("bin");
+    model.write_legacy(&legacy);
+    model.write_hf(&dir, dtype, shards);
+
+    let expected = logits(&legacy);
+    assert_eq!(logits(&dir), expected);
+    // f16 matrices stay f16, converted inside the matmul
+    let matrix_type = Transformer::new(&dir).unwrap().weights.matrix_type();
+    assert_eq!(matrix_type, dtype.to_lowercase().parse().unwrap());
+    // the model file and the index can be passed instead of the directory
+    let file = if shards == 1 {
+        "model.safetensors"
+    } else {
+        "model.safetensors.index.json"
+    };
+    assert_eq!(logits(&dir.join(file)), expected);
+}
+
+#[test]
+fn f32_shared() {
+    check("f32_shared", true, "F32", 1);
+}
+
+#[test]
+fn f32_unshared() {
+    check("f32_unshared", false, "F32", 1);
+}
+
+#[test]
+fn f16_sharded() {
+    check("f16_sharded", false, "F16", 3);
+}
+
+#[test]
+fn bf16() {
+    // bf16 keeps fewer mantissa bits than f16, compare against the same
+    // weights upcast to fp32 on load
+    let dir = tmp("safetensors_bf16");
+    Model::random(true).write_hf(&dir, "BF16", 1);
+    let mut bf16 = Transformer::new(&dir).unwrap();
+    assert_eq!(bf16.weights.matrix_type(), WeightType::BF16);
+    let mut f32 = Transformer::with_weight_type(&dir, WeightType::F32).unwrap();
+    assert_eq!(f32.weights.matrix_type(), WeightType::F32);
+    assert_eq!(forward_all(&mut bf16), forward_all(&mut f32));
+}
+
+#[test]
+fn missing_config() {
+    let dir = tmp("safetensors_missing_config");
+    Model::random(true).write_hf(&dir, "F32", 1);
+    fs::remove_file(dir.join("config.json")).unwrap();
+    let error = Transformer::new(&dir).err().unwrap().to_string();
+    assert!(error.contains("config.json"), "{error}");
+}
+
+#[test]
+fn overflowing_shape() {
+    let path = tmp("safetensors_overflow.safetensors");
+    let header = format!(
+        r#"{{"w": {{"dtype": "F32", "shape": [{}, 2], "data_offsets": [0, 0]}}}}"#,
+        usize::MAX
+    );
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend(header.as_bytes());
+    fs::write(&path, bytes).unwrap();
+    let error = llama2_rs::safetensors::SafeTensors::open(&path)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("tensor w has too many values"), "{error}");
+}
+
+#[test]
+fn tensor_in_two_shards() {
+    let dir = tmp("safetensors_two_shards");
+    fs::create_dir_all(&dir).unwrap();
+    // both shards hold w, the index lists it in the first one
+    for (shard, names) in [("a", &["w"][..]), ("b", &["v", "w"])] {
+        let header = names
+            .iter()
+            .map(|name| {
+                format!(r#""{name}": {{"dtype": "F32", "shape": [1], "data_offsets": [0, 4]}}"#)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let header = format!("{{{header}}}");
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header.as_bytes());
+        bytes.extend(1f32.to_le_bytes());
+        fs::write(dir.join(format!("{shard}.safetensors")), bytes).unwrap();
+    }
+    let index = dir.join("model.safetensors.index.json");
+    let weight_map = r#"{"weight_map": {"w": "a.safetensors", "v": "b.safetensors"}}"#;
+    fs::write(&index, weight_map).unwrap();
+    let error = llama2_rs::safetensors::SafeTensors::open(&index)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        error.contains("tensor w is also in another shard"),
+        "{error}"
+    );
+}
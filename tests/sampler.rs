@@ -0,0 +1,57 @@
+//! Samples from hand-picked logits, covering greedy, multinomial and top-p
+//! sampling and the distributions top-p used to choke on.
+
+use llama2_rs::Sampler;
+
+/// Logits whose softmax is `probabilities`.
+fn logits(probabilities: &[f32]) -> Vec<f32> {
+    probabilities.iter().map(|p| p.ln()).collect()
+}
+
+/// Samples `logits` with every seed in `1..=draws`, counting each token.
+fn histogram(probabilities: &[f32], temperature: f32, topp: f32, draws: u64) -> Vec<u32> {
+    let mut counts = vec![0; probabilities.len()];
+    for seed in 1..=draws {
+        let mut sampler = Sampler::new(probabilities.len() as u32, temperature, topp, seed);
+        counts[sampler.sample(&mut logits(probabilities)) as usize] += 1;
+    }
+    counts
+}
+
+#[test]
+fn greedy() {
+    let mut sampler = Sampler::new(4, 0.0, 0.9, 1);
+    assert_eq!(sampler.sample(&mut [0.5, -1.0, 3.0, 2.9]), 2);
+    // only the first vocab_size logits are considered
+    let mut sampler = Sampler::new(2, 0.0, 0.9, 1);
+    assert_eq!(sampler.sample(&mut [0.5, 1.0, 3.0]), 1);
+}
+
+#[test]
+fn multinomial() {
+    let counts = histogram(&[0.5, 0.3, 0.2], 1.0, 1.0, 1000);
+    assert!(counts.iter().all(|&count| count > 100), "{counts:?}");
+    assert!(counts[0] > counts[2], "{counts:?}");
+}
+
+#[test]
+fn topp_truncates() {
+    // 0.7 + 0.2 exceeds 0.8, the last token is never sampled
+    let counts = histogram(&[0.7, 0.2, 0.1], 1.0, 0.8, 1000);
+    assert!(counts[0] > 0 && counts[1] > 0, "{counts:?}");
+    assert_eq!(counts[2], 0, "{counts:?}");
+    // 0.7 alone exceeds 0.5
+    assert_eq!(histogram(&[0.7, 0.2, 0.1], 1.0, 0.5, 100), [100, 0, 0]);
+}
+
+#[test]
+fn topp_without_candidates() {
+    // no probability reaches the cutoff (1 - 0.4) / (2 - 1), the most likely
+    // token is taken
+    assert_eq!(histogram(&[0.55, 0.45], 1.0, 0.4, 100), [100, 0]);
+    // a single token makes the cutoff infinite
+    assert_eq!(histogram(&[1.0], 1.0, 0.9, 10), [10]);
+    // NaN logits compare false with the cutoff
+    let mut sampler = Sampler::new(3, 1.0, 0.9, 1);
+    assert!(sampler.sample(&mut [f32::NAN; 3]) < 3);
+}
@@ -0,0 +1,749 @@
+//! Loads a small SentencePiece vocabulary with byte fallback, written as a
+//! `tokenizer.model` protobuf, and checks it against llama2.c's layout, then
+//! a byte-level BPE vocabulary written as a HuggingFace `tokenizer.json`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use common::tmp;
+use llama2_rs::sentencepiece::{ModelType, PieceType, SentencePieceModel};
+use llama2_rs::tokenizer::{BpeTokenizer, StreamDecoder, Tokenize};
+use llama2_rs::Tokenizer;
+use serde_json::json;
+
+mod common;
+
+/// `(piece, score)` of the normal pieces, after `<unk>`, `<s>`, `</s>` and
+/// the 256 byte pieces.
+const PIECES: [(&str, f32); 16] = [
+    ("▁", -1.0),
+    ("a", -2.0),
+    ("b", -3.0),
+    ("c", -4.0),
+    ("e", -5.0),
+    ("h", -6.0),
+    ("l", -7.0),
+    ("o", -8.0),
+    ("▁a", -9.0),
+    ("ab", -10.0),
+    ("▁ab", -11.0),
+    ("he", -12.0),
+    ("ll", -13.0),
+    ("llo", -14.0),
+    ("hello", -15.0),
+    ("▁hello", -16.0),
+];
+
+/// The vocabulary as `(piece, score, SentencePiece type)`.
+fn vocab() -> Vec<(String, f32, u64)> {
+    let mut vocab = vec![
+        ("<unk>".to_string(), 0.0, 2),
+        ("<s>".into(), 0.0, 3),
+        ("</s>".into(), 0.0, 3),
+    ];
+    vocab.extend((0..=255).map(|byte| (format!("<0x{byte:02X}>"), 0.0, 6)));
+    vocab.extend(
+        PIECES
+            .iter()
+            .map(|&(piece, score)| (piece.into(), score, 1)),
+    );
+    vocab
+}
+
+/// Appends the protobuf varint encoding of `value` to `out`.
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends a length-delimited field to `out`.
+fn bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend(bytes);
+}
+
+/// Writes the vocabulary as a BPE `ModelProto`, with a normalizer spec
+/// the reader has to skip.
+fn write_model(path: &Path) {
+    let mut model = Vec::new();
+    for (piece, score, ty) in vocab() {
+        let mut message = Vec::new();
+        bytes_field(&mut message, 1, piece.as_bytes());
+        varint(&mut message, 2 << 3 | 5);
+        message.extend(score.to_le_bytes());
+        // NORMAL is the default and is usually left out
+        if ty != 1 {
+            varint(&mut message, 3 << 3);
+            varint(&mut message, ty);
+        }
+        bytes_field(&mut model, 1, &message);
+    }
+    let mut trainer_spec = Vec::new();
+    bytes_field(&mut trainer_spec, 1, b"corpus.txt");
+    for (field, value) in [
+        (3, 2),
+        (35, 1),
+        (40, 0),
+        (41, 1),
+        (42, 2),
+        (43, -1_i64 as u64),
+    ] {
+        varint(&mut trainer_spec, field << 3);
+        varint(&mut trainer_spec, value);
+    }
+    bytes_field(&mut model, 2, &trainer_spec);
+    let mut normalizer_spec = Vec::new();
+    bytes_field(&mut normalizer_spec, 1, b"identity");
+    bytes_field(&mut model, 3, &normalizer_spec);
+    fs::write(path, model).unwrap();
+}
+
+/// The `tokenizer.bin` llama2.c's `tokenizer.py` writes for the vocabulary.
+fn expected_tokenizer_bin() -> Vec<u8> {
+    let pieces = vocab()
+        .into_iter()
+        .map(|(piece, score, _)| {
+            let piece = match piece.as_str() {
+                "<s>" => "\n<s>\n".to_string(),
+                "</s>" => "\n</s>\n".to_string(),
+                piece => piece.replace('▁', " "),
+            };
+            (piece, score)
+        })
+        .collect::<Vec<_>>();
+    let max_token_len = pieces.iter().map(|(piece, _)| piece.len()).max().unwrap();
+    let mut bytes = (max_token_len as u32).to_le_bytes().to_vec();
+    for (piece, score) in pieces {
+        bytes.extend(score.to_le_bytes());
+        bytes.extend((piece.len() as u32).to_le_bytes());
+        bytes.extend(piece.as_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn sentencepiece_model() {
+    let path = tmp("sentencepiece.model");
+    write_model(&path);
+    let model = SentencePieceModel::open(&path).unwrap();
+    assert_eq!(model.pieces.len(), 3 + 256 + PIECES.len());
+    assert_eq!(model.model_type, ModelType::Bpe);
+    assert_eq!(
+        (model.unk_id, model.bos_id, model.eos_id, model.pad_id),
+        (Some(0), Some(1), Some(2), None)
+    );
+    let types = |ty| model.pieces.iter().filter(|p| p.ty == ty).count();
+    assert_eq!(types(PieceType::Control), 2);
+    assert_eq!(types(PieceType::Byte), 256);
+    let last = model.pieces.last().unwrap();
+    assert_eq!((last.piece.as_str(), last.score), ("▁hello", -16.0));
+}
+
+#[test]
+fn sentencepiece_encode() {
+    let path = tmp("sentencepiece_encode.model");
+    write_model(&path);
+    let bin = path.with_extension("bin");
+    fs::write(&bin, expected_tokenizer_bin()).unwrap();
+    let from_model = Tokenizer::from_sentencepiece(&path).unwrap();
+    let from_bin = Tokenizer::new(&bin, from_model.vocab_size()).unwrap();
+
+    let id = |piece: &str| 3 + 256 + PIECES.iter().position(|p| p.0 == piece).unwrap() as u32;
+    for text in ["hello ab", "a bc", "hé"] {
+        assert_eq!(
+            from_model.encode(text, true, false),
+            from_bin.encode(text, true, false)
+        );
+    }
+    assert_eq!(
+        from_model.encode("hello ab", true, true),
+        [1, id("▁hello"), id("▁ab"), 2]
+    );
+    // é is not in the vocabulary, its two UTF-8 bytes fall back to byte pieces
+    assert_eq!(
+        from_model.encode("hé", false, false),
+        [id("▁"), id("h"), 3 + 0xc3, 3 + 0xa9]
+    );
+}
+
+#[test]
+fn special_tokens() {
+    let path = tmp("sentencepiece_special.model");
+    write_model(&path);
+    let bin = path.with_extension("bin");
+    fs::write(&bin, expected_tokenizer_bin()).unwrap();
+    let id = |piece: &str| 3 + 256 + PIECES.iter().position(|p| p.0 == piece).unwrap() as u32;
+    let expected = [
+        ("<unk>".to_string(), 0),
+        ("</s>".into(), 2),
+        ("<s>".into(), 1),
+    ];
+    for tokenizer in [
+        Tokenizer::from_sentencepiece(&path).unwrap(),
+        Tokenizer::new(&bin, 3 + 256 + PIECES.len()).unwrap(),
+    ] {
+        assert_eq!(tokenizer.special_tokens(), expected);
+        // only the text before the first special token gets the leading space
+        assert_eq!(
+            tokenizer.encode_with_special("a<s>hello</s> ab", false, true),
+            [id("▁a"), 1, id("hello"), 2, id("▁ab"), 2]
+        );
+        // without the special mode the markup is plain text
+        let tokens = tokenizer.encode("<s>hello", false, false);
+        assert!(!tokens.contains(&1), "{tokens:?}");
+    }
+
+    let mut tokenizer = Tokenizer::new(&bin, 3 + 256 + PIECES.len()).unwrap();
+    tokenizer.set_bos(Some(id("▁")));
+    tokenizer.set_eos(None);
+    tokenizer.add_special_token("[INST]", id("ab"));
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (Some(id("▁")), None));
+    assert_eq!(
+        tokenizer.encode("hello", true, true),
+        [id("▁"), id("▁hello")]
+    );
+    assert_eq!(
+        tokenizer.encode_with_special("[INST]a", false, false),
+        [id("ab"), id("a")]
+    );
+    assert_eq!(tokenizer.decode(id("▁"), id("▁hello")), b"hello");
+    assert_eq!(tokenizer.decode(1, id("▁hello")), b" hello");
+}
+
+#[test]
+fn sentencepiece_decode() {
+    let path = tmp("sentencepiece_decode.model");
+    write_model(&path);
+    let mut tokenizer = Tokenizer::from_sentencepiece(&path).unwrap();
+    for text in ["hello ab", "hé", "字 a", ""] {
+        for (bos, eos) in [(false, false), (true, true)] {
+            let tokens = tokenizer.encode(text, bos, eos);
+            assert_eq!(tokenizer.decode_all(&tokens), text);
+        }
+    }
+    // the raw bytes of a byte piece, not a str
+    assert_eq!(tokenizer.decode(1, 3 + 0xc3), [0xc3]);
+
+    // 字 is three byte pieces, only the last one completes it
+    let mut decoder = StreamDecoder::new(&tokenizer);
+    let pieces = tokenizer
+        .encode("a字", true, false)
+        .into_iter()
+        .map(|token| decoder.push(token))
+        .collect::<Vec<_>>();
+    assert_eq!(pieces, ["", "a", "", "", "字"]);
+    // the space is only stripped at the start and after BOS
+    let mut decoder = StreamDecoder::after(&tokenizer, 3 + 0xc3);
+    assert_eq!(decoder.push(3 + 256), " ");
+
+    // bytes that cannot be part of a character are replaced, an unfinished
+    // character is replaced when the decoder finishes
+    let mut decoder = StreamDecoder::new(&tokenizer);
+    assert_eq!(decoder.push(3 + 0xff), "\u{fffd}");
+    assert_eq!(decoder.push(3 + 0xe5), "");
+    assert_eq!(decoder.push(3 + 256 + 1), "\u{fffd}a");
+    assert_eq!(decoder.push(3 + 0xe5), "");
+    assert_eq!(decoder.finish(), "\u{fffd}");
+
+    // the space is stripped at the start even without a BOS token
+    let tokens = tokenizer.encode("hello ab", false, false);
+    tokenizer.set_bos(None);
+    assert_eq!(tokenizer.decode_all(&tokens), "hello ab");
+}
+
+#[test]
+fn tokenizer_export() {
+    let path = tmp("sentencepiece_export.model");
+    write_model(&path);
+    let bin = path.with_extension("bin");
+    let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .args(["tokenizer", "export"])
+        .arg(&path)
+        .arg(&bin)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&bin).unwrap(), expected_tokenizer_bin());
+}
+
+#[test]
+fn tokenize_command() {
+    let path = tmp("sentencepiece_tokenize.model");
+    write_model(&path);
+    let bin = path.with_extension("bin");
+    fs::write(&bin, expected_tokenizer_bin()).unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.success(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+    let id = |piece: &str| 3 + 256 + PIECES.iter().position(|p| p.0 == piece).unwrap() as u32;
+
+    let (success, stdout) = run(&["tokenize", path.to_str().unwrap(), "--bos", "--json", "hé"]);
+    assert!(success);
+    let output: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["max_token_len"], "\n</s>\n".len());
+    let tokens = output["tokens"].as_array().unwrap();
+    let ids = tokens.iter().map(|t| t["id"].as_u64().unwrap() as u32);
+    assert_eq!(
+        ids.collect::<Vec<_>>(),
+        [1, id("▁"), id("h"), 3 + 0xc3, 3 + 0xa9]
+    );
+    assert_eq!(tokens[2]["piece"], "h");
+    assert_eq!(tokens[2]["score"], -6.0);
+    assert_eq!(tokens[2]["byte"], serde_json::Value::Null);
+    assert_eq!(tokens[3]["byte"], 0xc3);
+
+    // the tokenizer.bin gives the same tokens, the vocabulary size is read
+    // from the file
+    let (success, stdout) = run(&["tokenize", bin.to_str().unwrap(), "hé"]);
+    assert!(success);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].trim_end().ends_with("\"h\""), "{stdout}");
+    // every line pads the piece column to the longest piece, "<0xC3>"
+    let width = lines[0].len();
+    assert_eq!(lines[1].len(), width, "{stdout}");
+    assert_eq!(&lines[2][width..], " byte fallback 0xC3", "{stdout}");
+
+    // stdin is read without its last newline
+    let mut child = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .args(["tokenize", bin.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"h\xc3\xa9\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), stdout);
+
+    let ids = [1, id("▁"), id("h"), 3 + 0xc3, 3 + 0xa9].map(|id| id.to_string());
+    let mut args = vec!["detokenize", bin.to_str().unwrap()];
+    args.extend(ids.iter().map(String::as_str));
+    assert_eq!(run(&args), (true, "hé\n".to_string()));
+    args.push("1000");
+    assert!(!run(&args).0);
+}
+
+#[test]
+fn vocabulary_larger_than_model() {
+    let path = tmp("larger_vocabulary.model");
+    write_model(&path);
+    // the test model has only 11 tokens
+    let checkpoint = tmp("larger_vocabulary.bin");
+    common::Model::random(true).write_legacy(&checkpoint);
+    let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .arg(&checkpoint)
+        .arg("--tokenizer-path")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("the vocabulary has 275 tokens, more than the 11 of the model"),
+        "{stderr}"
+    );
+
+    // a model with a larger vocabulary can sample tokens the tokenizer lacks
+    let tokenizer = Tokenizer::from_sentencepiece(&path).unwrap();
+    assert_eq!(tokenizer.decode(1, 1000), b"");
+
+    // the same goes for tokenizer.json
+    let dir = tmp("larger_vocabulary_bpe");
+    write_bpe(&dir, json!({"type": "ByteLevel"}), &GPT2_MERGES);
+    let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .arg(&checkpoint)
+        .arg("--tokenizer-path")
+        .arg(dir.join("tokenizer.json"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("more than the 11 of the model"), "{stderr}");
+    let tokenizer = BpeTokenizer::new(dir.join("tokenizer.json")).unwrap();
+    assert_eq!(Tokenize::decode(&tokenizer, 0, 1000), b"");
+}
+
+#[test]
+fn truncated_model() {
+    let path = tmp("sentencepiece_truncated.model");
+    write_model(&path);
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+    let error = SentencePieceModel::open(&path).err().unwrap().to_string();
+    assert!(error.contains("truncated"), "{error}");
+}
+
+#[test]
+fn vocabulary_without_byte_pieces() {
+    // no byte pieces at all, 'z' and 'é' become <unk>
+    let vocab = ["<unk>", "\n<s>\n", "\n</s>\n", " ", "a", " a"];
+    let path = tmp("no_byte_pieces.bin");
+    let mut bytes = 5u32.to_le_bytes().to_vec();
+    for piece in vocab {
+        bytes.extend(0f32.to_le_bytes());
+        bytes.extend((piece.len() as u32).to_le_bytes());
+        bytes.extend(piece.as_bytes());
+    }
+    fs::write(&path, bytes).unwrap();
+    let tokenizer = Tokenizer::open(&path).unwrap();
+    assert_eq!(tokenizer.encode("az", true, false), [1, 5, 0]);
+    assert_eq!(tokenizer.encode("é", false, false), [3, 0, 0]);
+    let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+        .args(["tokenize", path.to_str().unwrap(), "az"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // byte pieces are found wherever they are in the vocabulary
+    let path = tmp("some_byte_pieces.bin");
+    let mut bytes = 6u32.to_le_bytes().to_vec();
+    for piece in ["<unk>", "\n<s>\n", "\n</s>\n", "a", "<0x7A>"] {
+        bytes.extend(0f32.to_le_bytes());
+        bytes.extend((piece.len() as u32).to_le_bytes());
+        bytes.extend(piece.as_bytes());
+    }
+    fs::write(&path, bytes).unwrap();
+    let tokenizer = Tokenizer::open(&path).unwrap();
+    assert_eq!(tokenizer.encode("az", false, false), [3, 4]);
+}
+
+/// The merge loop `Tokenizer::encode` used to run: merges the best scoring
+/// pair of neighbours, the leftmost one on ties, until none is left.
+fn reference_encode(vocab: &[(String, f32)], text: &str, bos: bool, eos: bool) -> Vec<u32> {
+    let ids = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, (piece, _))| (piece.as_str(), id as u32))
+        .collect::<HashMap<_, _>>();
+    let mut tokens = Vec::new();
+    if bos {
+        tokens.push(1);
+    }
+    if !text.is_empty() {
+        tokens.push(ids[" "]);
+    }
+    for c in text.chars() {
+        match ids.get(c.to_string().as_str()) {
+            Some(&id) => tokens.push(id),
+            None => tokens.extend(c.to_string().bytes().map(|b| b as u32 + 3)),
+        }
+    }
+    loop {
+        let mut best = (f32::NEG_INFINITY, None);
+        for (i, pair) in tokens.windows(2).enumerate() {
+            let pair = format!("{}{}", vocab[pair[0] as usize].0, vocab[pair[1] as usize].0);
+            if let Some(&id) = ids.get(pair.as_str()) {
+                if vocab[id as usize].1 > best.0 {
+                    best = (vocab[id as usize].1, Some((i, id)));
+                }
+            }
+        }
+        let Some((i, id)) = best.1 else { break };
+        tokens[i] = id;
+        tokens.remove(i + 1);
+    }
+    if eos {
+        tokens.push(2);
+    }
+    tokens
+}
+
+#[test]
+fn encode_matches_reference() {
+    // few distinct scores so that ties are common, with -0.0 tying 0.0 and
+    // -inf pieces never merged
+    const SCORES: [f32; 6] = [0.0, -0.0, -1.0, -2.0, 1.5, f32::NEG_INFINITY];
+    const ALPHABET: [char; 8] = [' ', 'a', 'b', 'c', 'd', 'é', '字', '\n'];
+    let pick = |r: f32, len: usize| ((r + 1.0) / 2.0 * len as f32) as usize % len;
+
+    let mut vocab = vec![
+        ("<unk>".to_string(), 0.0),
+        ("\n<s>\n".into(), 0.0),
+        ("\n</s>\n".into(), 0.0),
+    ];
+    vocab.extend((0..=255u8).map(|byte| (format!("<0x{byte:02X}>"), 0.0)));
+    // single characters except 'd', which falls back to bytes
+    vocab.extend(
+        ALPHABET
+            .iter()
+            .filter(|&&c| c != 'd')
+            .map(|c| (c.to_string(), -3.0)),
+    );
+    let random = common::random(4096, 22);
+    let mut random = random.into_iter();
+    let mut seen = vocab
+        .iter()
+        .map(|(piece, _)| piece.clone())
+        .collect::<HashSet<_>>();
+    while vocab.len() < 3 + 256 + 600 {
+        let len = 2 + pick(random.next().unwrap(), 5);
+        let piece = (0..len)
+            .map(|_| ALPHABET[pick(random.next().unwrap(), ALPHABET.len())])
+            .collect::<String>();
+        let score = SCORES[pick(random.next().unwrap(), SCORES.len())];
+        if seen.insert(piece.clone()) {
+            vocab.push((piece, score));
+        }
+    }
+
+    let path = tmp("encode_reference.bin");
+    let max_token_len = vocab.iter().map(|(piece, _)| piece.len()).max().unwrap();
+    let mut bytes = (max_token_len as u32).to_le_bytes().to_vec();
+    for (piece, score) in &vocab {
+        bytes.extend(score.to_le_bytes());
+        bytes.extend((piece.len() as u32).to_le_bytes());
+        bytes.extend(piece.as_bytes());
+    }
+    fs::write(&path, bytes).unwrap();
+    let tokenizer = Tokenizer::new(&path, vocab.len()).unwrap();
+
+    let mut corpus = vec![
+        String::new(),
+        "a".into(),
+        "abc abc abc".into(),
+        "dddd".into(),
+        "字字 é\nxyz".into(),
+    ];
+    let random = common::random(20_000, 23);
+    let mut random = random.into_iter();
+    for _ in 0..200 {
+        let len = pick(random.next().unwrap(), 80);
+        corpus.push(
+            (0..len)
+                .map(|_| ALPHABET[pick(random.next().unwrap(), ALPHABET.len())])
+                .collect(),
+        );
+    }
+    for text in &corpus {
+        for (bos, eos) in [(false, false), (true, false), (false, true), (true, true)] {
+            assert_eq!(
+                tokenizer.encode(text, bos, eos),
+                reference_encode(&vocab, text, bos, eos),
+                "{text:?}"
+            );
+        }
+    }
+}
+
+/// GPT-2's `bytes_to_unicode`, the byte-level alphabet of `tokenizer.json`.
+fn byte_char(byte: u8) -> char {
+    let visible = |b: u8| matches!(b, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff);
+    if visible(byte) {
+        byte as char
+    } else {
+        let n = (0..byte).filter(|&b| !visible(b)).count() as u32;
+        char::from_u32(0x100 + n).unwrap()
+    }
+}
+
+/// Writes a byte-level BPE `tokenizer.json` to `dir`: the 256 bytes are
+/// tokens 0 to 255, followed by the results of `merges` in order and then
+/// by the added tokens.
+fn write_bpe(dir: &Path, pre_tokenizer: serde_json::Value, merges: &[&str]) {
+    fs::create_dir_all(dir).unwrap();
+    let mut vocab = serde_json::Map::new();
+    for byte in 0..=255 {
+        vocab.insert(byte_char(byte).to_string(), byte.into());
+    }
+    for merge in merges {
+        vocab.insert(merge.replace(' ', ""), vocab.len().into());
+    }
+    // old files write merges as "a b", new ones as ["a", "b"]
+    let (joined, pairs) = merges.split_at(merges.len() / 2);
+    let merges = joined
+        .iter()
+        .map(|merge| json!(merge))
+        .chain(
+            pairs
+                .iter()
+                .map(|merge| json!(merge.split(' ').collect::<Vec<_>>())),
+        )
+        .collect::<Vec<_>>();
+    let n = vocab.len();
+    let json = json!({
+        "version": "1.0",
+        "added_tokens": [
+            {"id": n, "content": "<|begin_of_text|>", "special": true},
+            {"id": n + 1, "content": "<|end_of_text|>", "special": true},
+            {"id": n + 2, "content": "<sep>", "special": false},
+        ],
+        "normalizer": null,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [
+                {"SpecialToken": {"id": "<|begin_of_text|>", "type_id": 0}},
+                {"Sequence": {"id": "A", "type_id": 0}},
+            ],
+        },
+        "decoder": {"type": "ByteLevel"},
+        "model": {"type": "BPE", "vocab": vocab, "merges": merges},
+    });
+    fs::write(dir.join("tokenizer.json"), json.to_string()).unwrap();
+}
+
+const GPT2_MERGES: [&str; 9] = [
+    "h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "l d", "Ġwor ld",
+];
+
+#[test]
+fn bpe_encode() {
+    let dir = tmp("bpe_gpt2");
+    write_bpe(&dir, json!({"type": "ByteLevel"}), &GPT2_MERGES);
+    let tokenizer = BpeTokenizer::new(dir.join("tokenizer.json")).unwrap();
+    assert_eq!(tokenizer.vocab_size(), 256 + 9 + 3);
+    // no tokenizer_config.json, BOS comes from the template
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (Some(265), None));
+
+    // "hello" and " world" are pre-tokenized apart and merge into one token each
+    assert_eq!(
+        tokenizer.encode("hello world", true, false),
+        [265, 259, 264]
+    );
+    // merges by rank: "o r" comes before any merge of "w"
+    assert_eq!(tokenizer.encode("wor", false, false), [b'w' as u32, 261]);
+    // é is two bytes without a merge
+    assert_eq!(
+        tokenizer.encode("héllo", false, false),
+        [b'h' as u32, 0xc3, 0xa9, 257, b'o' as u32]
+    );
+    // non-special added tokens are matched in the text, special ones are not
+    assert_eq!(
+        tokenizer.encode("hello<sep>world", false, false),
+        [259, 267, b'w' as u32, 261, 263]
+    );
+    assert_eq!(
+        tokenizer.encode("<|end_of_text|>", false, false),
+        "<|end_of_text|>".bytes().map(u32::from).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        tokenizer.encode_with_special("hello<|end_of_text|>", false, false),
+        [259, 266]
+    );
+
+    assert_eq!(Tokenize::decode(&tokenizer, 259, 264), b" world");
+    assert_eq!(Tokenize::decode(&tokenizer, 0, 265), b"<|begin_of_text|>");
+    // the bytes of é come back together
+    let tokens = tokenizer.encode("héllo world", false, false);
+    assert_eq!(tokenizer.decode_all(&tokens), "héllo world");
+}
+
+#[test]
+fn bpe_tokenize_command() {
+    let dir = tmp("bpe_tokenize");
+    write_bpe(&dir, json!({"type": "ByteLevel"}), &GPT2_MERGES);
+    let path = dir.join("tokenizer.json");
+    let run = |command: &str, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_llama2-rs"))
+            .arg(command)
+            .arg(&path)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let output: serde_json::Value =
+        serde_json::from_str(&run("tokenize", &["--json", "hello world"])).unwrap();
+    assert_eq!(output["vocab_size"], 256 + 9 + 3);
+    assert_eq!(output["max_token_len"], "<|begin_of_text|>".len());
+    let tokens = output["tokens"].as_array().unwrap();
+    let ids = tokens.iter().map(|t| t["id"].as_u64().unwrap());
+    assert_eq!(ids.collect::<Vec<_>>(), [259, 264]);
+    // pieces are shown as in the vocabulary, BPE has no scores
+    assert_eq!(tokens[1]["piece"], "Ġworld");
+    assert_eq!(tokens[1]["score"], serde_json::Value::Null);
+
+    let stdout = run("tokenize", &["hello world"]);
+    assert_eq!(stdout.lines().count(), 2);
+    assert!(stdout.contains("\"Ġworld\""), "{stdout}");
+    assert_eq!(run("detokenize", &["259", "264"]), "hello world\n");
+}
+
+#[test]
+fn bpe_tokenizer_config() {
+    let dir = tmp("bpe_config");
+    write_bpe(&dir, json!({"type": "ByteLevel"}), &GPT2_MERGES);
+    let config = json!({
+        "bos_token": "<|begin_of_text|>",
+        "eos_token": {"content": "<|end_of_text|>", "special": true},
+    });
+    fs::write(dir.join("tokenizer_config.json"), config.to_string()).unwrap();
+    let tokenizer = BpeTokenizer::new(dir.join("tokenizer.json")).unwrap();
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (Some(265), Some(266)));
+    assert_eq!(tokenizer.encode("hello", true, true), [265, 259, 266]);
+}
+
+#[test]
+fn bpe_split_pre_tokenizer() {
+    // "3 4" ranks first, so only splitting digits in groups of three keeps
+    // "123" whole
+    let merges = ["3 4", "1 2", "12 3", "4 5"];
+    let dir = tmp("bpe_split");
+    let pre_tokenizer = json!({
+        "type": "Sequence",
+        "pretokenizers": [
+            {
+                "type": "Split",
+                "pattern": {"Regex": "\\p{N}{1,3}"},
+                "behavior": "Isolated",
+                "invert": false,
+            },
+            {"type": "ByteLevel", "add_prefix_space": false, "use_regex": false},
+        ],
+    });
+    write_bpe(&dir, pre_tokenizer, &merges);
+    let tokenizer = BpeTokenizer::new(dir.join("tokenizer.json")).unwrap();
+    assert_eq!(tokenizer.encode("12345", false, false), [258, 259]);
+
+    write_bpe(
+        &dir,
+        json!({"type": "ByteLevel", "use_regex": false}),
+        &merges,
+    );
+    let tokenizer = BpeTokenizer::new(dir.join("tokenizer.json")).unwrap();
+    assert_eq!(
+        tokenizer.encode("12345", false, false),
+        [257, 256, b'5' as u32]
+    );
+}
+
+#[test]
+fn bpe_unsupported_pre_tokenizer() {
+    let dir = tmp("bpe_metaspace");
+    write_bpe(&dir, json!({"type": "Metaspace", "replacement": "▁"}), &[]);
+    let error = BpeTokenizer::new(dir.join("tokenizer.json"))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("Metaspace"), "{error}");
+}
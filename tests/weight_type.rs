@@ -0,0 +1,65 @@
+//! Checks the forward pass over half-precision and quantized weights against
+//! the fp32 reference.
+
+use common::{forward_all, tmp, Model};
+use llama2_rs::transformer::WeightType;
+use llama2_rs::Transformer;
+
+mod common;
+
+/// The logits of the random model converted to `weight_type` on load, along
+/// with the fp32 reference.
+fn run(name: &str, weight_type: WeightType) -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+    let checkpoint = tmp(&format!("{name}.bin"));
+    Model::random(false).write_legacy(&checkpoint);
+    let mut reference = Transformer::new(&checkpoint).unwrap();
+    assert_eq!(reference.weights.matrix_type(), WeightType::F32);
+    let mut transformer = Transformer::with_weight_type(&checkpoint, weight_type).unwrap();
+    assert_eq!(transformer.weights.matrix_type(), weight_type);
+    (forward_all(&mut transformer), forward_all(&mut reference))
+}
+
+/// Asserts that the logits are within `tolerance` of the largest logit.
+fn assert_close(actual: &[Vec<f32>], expected: &[Vec<f32>], tolerance: f32) {
+    for (pos, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+        let scale = expected.iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!(
+                (a - e).abs() <= tolerance * scale,
+                "pos {pos}, logit {i}: expected {e}, got {a}"
+            );
+        }
+    }
+}
+
+#[test]
+fn f16_matches_fp32() {
+    // the weights of the model are exact in f16, and the f16 matmul sums in
+    // the same order as the fp32 one
+    let (actual, expected) = run("weight_type_f16", WeightType::F16);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn bf16_close_to_fp32() {
+    let (actual, expected) = run("weight_type_bf16", WeightType::BF16);
+    assert_close(&actual, &expected, 0.05);
+}
+
+#[test]
+fn q8_0_close_to_fp32() {
+    let (actual, expected) = run("weight_type_q8_0", WeightType::Q8_0 { group_size: 8 });
+    assert_close(&actual, &expected, 0.05);
+}
+
+#[test]
+fn q8_0_group_size() {
+    let checkpoint = tmp("weight_type_group.bin");
+    Model::random(true).write_legacy(&checkpoint);
+    let weight_type = WeightType::Q8_0 { group_size: 5 };
+    let error = Transformer::with_weight_type(&checkpoint, weight_type)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("group size 5 must divide"), "{error}");
+}